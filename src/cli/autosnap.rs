@@ -0,0 +1,262 @@
+use crate::{
+    commit::StratumRef,
+    interrupt,
+    store::{Store, autosnap::Retention},
+};
+use rustix::fs::inotify;
+use std::collections::HashMap;
+use std::mem::MaybeUninit;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(clap::Args, Debug)]
+pub struct AutosnapArgs {
+    /// Worktrees to snapshot, e.g. `myapp+main`, or every mounted worktree if none
+    /// are given
+    #[clap(value_parser)]
+    worktrees: Vec<StratumRef>,
+
+    /// Snapshot changed worktrees every this many minutes
+    #[clap(long, default_value_t = 15)]
+    interval: u64,
+
+    /// Also snapshot a worktree once its upperdir has had no changes for this many
+    /// seconds
+    #[clap(long)]
+    quiet: Option<u64>,
+
+    /// Keep the newest snapshot of each of the last N hours
+    #[clap(long, default_value_t = 0)]
+    keep_hourly: usize,
+
+    /// Keep the newest snapshot of each of the last N days
+    #[clap(long, default_value_t = 0)]
+    keep_daily: usize,
+
+    /// Keep the newest snapshot of each of the last N weeks
+    #[clap(long, default_value_t = 0)]
+    keep_weekly: usize,
+
+    /// Snapshot once and exit
+    #[clap(long, conflicts_with = "quiet")]
+    once: bool,
+}
+
+impl AutosnapArgs {
+    pub fn execute(self, store: &Store) -> Result<(), String> {
+        let worktrees = self
+            .worktrees
+            .iter()
+            .map(|sref| match sref {
+                StratumRef::Worktree { label, worktree } => Ok((label.clone(), worktree.clone())),
+                _ => Err(format!(
+                    "Invalid worktree {}. Use 'label+worktree_name'.",
+                    sref
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let retention = Retention {
+            hourly: self.keep_hourly,
+            daily: self.keep_daily,
+            weekly: self.keep_weekly,
+        };
+
+        if self.once {
+            for (label, worktree) in targets(store, &worktrees)? {
+                snapshot(store, &label, &worktree, &retention);
+            }
+            return Ok(());
+        }
+
+        interrupt::handle_interrupts();
+        let interval = Duration::from_secs(self.interval.max(1) * 60);
+        let quiet = self.quiet.map(Duration::from_secs);
+        let mut watcher = quiet.map(|_| UpperdirWatcher::new()).transpose()?;
+        let mut next_round = Instant::now();
+
+        while !interrupt::interrupted() {
+            if Instant::now() >= next_round {
+                let targets = targets(store, &worktrees)?;
+                if let Some(watcher) = &mut watcher {
+                    watcher.sync(store, &targets);
+                }
+                for (label, worktree) in &targets {
+                    snapshot(store, label, worktree, &retention);
+                }
+                next_round = Instant::now() + interval;
+            }
+
+            if let (Some(watcher), Some(quiet)) = (&mut watcher, quiet) {
+                watcher.read_events();
+                for (label, worktree) in watcher.take_quiet(quiet) {
+                    snapshot(store, &label, &worktree, &retention);
+                }
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        Ok(())
+    }
+}
+
+/// The worktrees to snapshot this round
+fn targets(store: &Store, worktrees: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+    if worktrees.is_empty() {
+        store.mounted_worktrees()
+    } else {
+        Ok(worktrees.to_vec())
+    }
+}
+
+/// Snapshot a worktree and prune its label's old snapshots, logging failures rather
+/// than stopping
+fn snapshot(store: &Store, label: &str, worktree: &str, retention: &Retention) {
+    let _lock = match store.lock_shared() {
+        Ok(lock) => lock,
+        Err(e) => {
+            tracing::warn!("Failed to snapshot {}+{}: {}", label, worktree, e);
+            return;
+        }
+    };
+    match store.autosnap_worktree(label, worktree) {
+        Ok(Some(tag)) => println!("{}:{}  (snapshot of {}+{})", label, tag, label, worktree),
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to snapshot {}+{}: {}", label, worktree, e),
+    }
+    match store.prune_autosnaps(label, retention) {
+        Ok(pruned) => {
+            for tag in pruned {
+                tracing::info!("Pruned snapshot {}:{}", label, tag);
+            }
+        }
+        Err(e) => tracing::warn!("Failed to prune snapshots of {}: {}", label, e),
+    }
+}
+
+/// Watches worktree upperdirs for changes, to find out when they've gone quiet
+///
+/// inotify doesn't watch directories recursively, so every directory in an upperdir
+/// is watched on its own. Upperdirs only hold what a worktree changed, so that's far
+/// fewer than in the whole tree.
+struct UpperdirWatcher {
+    inotify: OwnedFd,
+    /// Worktree and directory of each watch descriptor
+    watches: HashMap<i32, ((String, String), PathBuf)>,
+    /// When each worktree's upperdir last changed, if it did since its last snapshot
+    changed: HashMap<(String, String), Instant>,
+}
+
+impl UpperdirWatcher {
+    const EVENTS: inotify::WatchFlags = inotify::WatchFlags::CREATE
+        .union(inotify::WatchFlags::DELETE)
+        .union(inotify::WatchFlags::MODIFY)
+        .union(inotify::WatchFlags::ATTRIB)
+        .union(inotify::WatchFlags::MOVED_FROM)
+        .union(inotify::WatchFlags::MOVED_TO);
+
+    fn new() -> Result<Self, String> {
+        let inotify = inotify::init(inotify::CreateFlags::CLOEXEC | inotify::CreateFlags::NONBLOCK)
+            .map_err(|e| format!("Failed to initialize inotify: {}", e))?;
+        Ok(Self {
+            inotify,
+            watches: HashMap::new(),
+            changed: HashMap::new(),
+        })
+    }
+
+    /// Watch the upperdirs of `targets`, and stop watching those of other worktrees
+    fn sync(&mut self, store: &Store, targets: &[(String, String)]) {
+        let stale = self
+            .watches
+            .iter()
+            .filter(|(_, (target, _))| !targets.contains(target))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+        for wd in stale {
+            let _ = inotify::remove_watch(&self.inotify, wd);
+            self.watches.remove(&wd);
+        }
+
+        for target in targets {
+            if self.watches.values().any(|(t, _)| t == target) {
+                continue;
+            }
+            let upperdir = PathBuf::from(store.worktree_upperdir(&target.0, &target.1));
+            self.watch_tree(target, &upperdir);
+        }
+    }
+
+    /// Watch `dir` and every directory beneath it
+    fn watch_tree(&mut self, target: &(String, String), dir: &Path) {
+        match inotify::add_watch(&self.inotify, dir, Self::EVENTS) {
+            Ok(wd) => {
+                self.watches.insert(wd, (target.clone(), dir.to_path_buf()));
+            }
+            Err(e) => {
+                tracing::warn!("Failed to watch {}: {}", dir.display(), e);
+                return;
+            }
+        }
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                self.watch_tree(target, &entry.path());
+            }
+        }
+    }
+
+    /// Record the changes made since the last call
+    fn read_events(&mut self) {
+        let mut buf = [MaybeUninit::uninit(); 4096];
+        let mut new_dirs = Vec::new();
+        let mut reader = inotify::Reader::new(&self.inotify, &mut buf);
+        loop {
+            let event = match reader.next() {
+                Ok(event) => event,
+                Err(rustix::io::Errno::WOULDBLOCK) => break,
+                Err(e) => {
+                    tracing::warn!("Failed to read inotify events: {}", e);
+                    break;
+                }
+            };
+            if event.events().contains(inotify::ReadFlags::IGNORED) {
+                self.watches.remove(&event.wd());
+                continue;
+            }
+            let Some((target, dir)) = self.watches.get(&event.wd()) else {
+                continue;
+            };
+            self.changed.insert(target.clone(), Instant::now());
+
+            // New directories need watches of their own
+            let flags = event.events();
+            if flags.contains(inotify::ReadFlags::ISDIR)
+                && flags.intersects(inotify::ReadFlags::CREATE | inotify::ReadFlags::MOVED_TO)
+                && let Some(name) = event.file_name()
+            {
+                let name = std::ffi::OsStr::from_bytes(name.to_bytes());
+                new_dirs.push((target.clone(), dir.join(name)));
+            }
+        }
+        for (target, dir) in new_dirs {
+            self.watch_tree(&target, &dir);
+        }
+    }
+
+    /// Take the worktrees that changed, but not in the last `quiet`
+    fn take_quiet(&mut self, quiet: Duration) -> Vec<(String, String)> {
+        let quiet_targets = self
+            .changed
+            .iter()
+            .filter(|(_, changed)| changed.elapsed() >= quiet)
+            .map(|(target, _)| target.clone())
+            .collect::<Vec<_>>();
+        for target in &quiet_targets {
+            self.changed.remove(target);
+        }
+        quiet_targets
+    }
+}
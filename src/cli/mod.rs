@@ -1,4 +1,6 @@
+mod autosnap;
 mod patchset;
+mod watchdog;
 mod worktree;
 use crate::commit::StratumRef;
 use crate::util::{self};
@@ -18,7 +20,7 @@ pub enum Commands {
     /// Import a directory as a new stratum commit
     #[clap(name = "import", aliases = &["i"])]
     Import {
-        /// Directory to import from
+        /// Directory to import from, or a patch archive made by `worktree export-patch`
         #[clap(value_parser)]
         directory: PathBuf,
 
@@ -54,6 +56,81 @@ pub enum Commands {
         new_tag: String,
     },
 
+    /// Record how a commit relates to other patches when used in a patchset
+    ///
+    /// Patches are referred to by their ref as written in the patchset, or by name.
+    /// Replaces any relations recorded before.
+    #[clap(name = "annotate")]
+    Annotate {
+        /// The commit to annotate
+        #[clap(value_parser)]
+        stratum_ref: StratumRef,
+
+        /// Patches that must be present, and are applied first
+        #[clap(long, value_delimiter = ',')]
+        requires: Vec<String>,
+
+        /// Patches that can't be applied together with this one
+        #[clap(long, value_delimiter = ',')]
+        conflicts: Vec<String>,
+
+        /// Patches applied before this one, if present
+        #[clap(long, value_delimiter = ',')]
+        after: Vec<String>,
+
+        /// Patches applied after this one, if present
+        #[clap(long, value_delimiter = ',')]
+        before: Vec<String>,
+    },
+
+    /// Commit a worktree's changes on top of its base
+    ///
+    /// Paths matching the worktree's ignore rules are left out of the commit.
+    #[clap(name = "commit")]
+    Commit {
+        /// The worktree to commit, e.g. `myapp+main`
+        #[clap(value_parser)]
+        worktree: StratumRef,
+
+        /// Tag for the new commit, in the worktree's label
+        #[clap(value_parser)]
+        tag: String,
+
+        /// Only commit this path of the worktree and everything beneath it
+        ///
+        /// Can be given multiple times.
+        #[clap(long = "path")]
+        paths: Vec<String>,
+
+        /// Remove the committed changes from the worktree and rebase it onto the new
+        /// commit, leaving the rest of its changes pending
+        #[clap(long)]
+        rebase: bool,
+    },
+
+    /// Merge two commits or worktrees into a new commit
+    ///
+    /// Changes since the common ancestor are combined. Paths both sides changed
+    /// differently make the merge fail, unless a strategy resolves them.
+    #[clap(name = "merge")]
+    Merge {
+        /// The first side ("ours")
+        #[clap(value_parser)]
+        ours: StratumRef,
+
+        /// The second side ("theirs")
+        #[clap(value_parser)]
+        theirs: StratumRef,
+
+        /// Label and tag for the merged commit, e.g. `myapp:merged`
+        #[clap(long)]
+        into: String,
+
+        /// How to resolve paths both sides changed differently
+        #[clap(long, value_enum)]
+        strategy: Option<crate::store::merge::MergePolicy>,
+    },
+
     /// Remove a tag from a stratum
     #[clap(name = "untag", aliases = &["ut"])]
     Untag {
@@ -72,6 +149,36 @@ pub enum Commands {
         /// The path to mount the stratum at (optional, will auto-generate if not provided)
         #[clap(value_parser)]
         mountpoint: Option<PathBuf>,
+
+        /// Stack these commits as lower layers instead of using the stratum's own base,
+        /// bottom-most first (e.g. `--layers base,modA,modB`)
+        ///
+        /// If the stratum reference is a worktree, its upperdir is stacked on top.
+        #[clap(long, value_delimiter = ',')]
+        layers: Vec<StratumRef>,
+
+        /// Keep changes on a throwaway tmpfs, discarded on unmount
+        ///
+        /// Also makes tags and commits writable for the lifetime of the mount.
+        #[clap(long, conflicts_with = "read_only")]
+        tmpfs: bool,
+
+        /// Skip syncing changes to disk (overlay `volatile`), for fast caches
+        ///
+        /// The worktree may be left inconsistent if the system crashes while mounted.
+        #[clap(long)]
+        volatile: bool,
+
+        /// Mount a worktree's current state without write access
+        #[clap(long)]
+        read_only: bool,
+
+        /// Idmap the mount, in the form `<from>:<to>:<count>`
+        ///
+        /// Files owned by IDs `from..from+count` on disk show up as owned by
+        /// `to..to+count`. Applies to both UIDs and GIDs.
+        #[clap(long)]
+        uidmap: Option<crate::mount::idmap::IdMap>,
     },
 
     #[clap(name = "unmount", aliases = &["umount", "um", "u", "umnt"])]
@@ -82,6 +189,20 @@ pub enum Commands {
         mountpoint: PathBuf,
     },
 
+    /// Periodically snapshot worktrees that changed, pruning old snapshots
+    ///
+    /// Snapshots are commits of the worktree's changes, tagged as
+    /// `<label>:auto-<timestamp>`. The worktrees themselves are left as they are.
+    #[clap(name = "autosnap")]
+    Autosnap(autosnap::AutosnapArgs),
+
+    /// Watch mounted worktrees' sizes, warning as they near their limit
+    ///
+    /// Worktrees set to `--read-only` with `worktree limit` are remounted read-only
+    /// once they go over it.
+    #[clap(name = "watchdog")]
+    Watchdog(watchdog::WatchdogArgs),
+
     /// Manage worktrees
     #[clap(subcommand, name = "worktree", alias = "wt")]
     Worktree(worktree::WorktreeCommand),
@@ -89,6 +210,14 @@ pub enum Commands {
     /// Patchset management commands
     #[clap(subcommand, name = "patchset", alias = "ps")]
     Patchset(patchset::PatchsetCommand),
+
+    /// Show or change store-wide settings
+    #[clap(name = "config")]
+    Config {
+        /// fs-verity policy: enable it on imports and enforce it on mounts
+        #[clap(long, value_enum)]
+        verity: Option<crate::store::config::VerityPolicy>,
+    },
 }
 
 #[cfg(debug_assertions)]
@@ -98,8 +227,17 @@ const BASE_PATH: &str = "/var/lib/stratum";
 
 impl Cli {
     pub fn run(self) -> Result<(), String> {
-        let store = crate::store::Store::new(BASE_PATH.to_string());
+        let mut store = crate::store::Store::new(BASE_PATH.to_string());
         tracing::trace!("Running command: {:?}", self.command);
+        // Keeps `patchset clean` from removing this command's temporary directories
+        // and commits. Clean takes the lock itself, the autosnap daemon takes it per
+        // snapshot, and the watchdog only remounts.
+        let _store_lock = match &self.command {
+            Commands::Patchset(patchset::PatchsetCommand::Clean)
+            | Commands::Autosnap(_)
+            | Commands::Watchdog(_) => None,
+            _ => Some(store.lock_shared()?),
+        };
         match self.command {
             Commands::Import {
                 directory,
@@ -107,19 +245,34 @@ impl Cli {
                 bare, // todo: handle bare import
                 patch,
             } => {
-                if !bare {
-                    unimplemented!(
-                        "Export file import is not yet implemented, please use --bare option for now."
-                    );
-                }
-
                 // Use symlink_metadata to avoid following symlinks when checking if it's a directory
                 let metadata = std::fs::symlink_metadata(&directory).map_err(|e| {
                     format!("Failed to read metadata for {}: {}", directory.display(), e)
                 })?;
-                if !metadata.is_dir() {
+
+                // Patch archives are extracted, turning their `.wh.` files into whiteouts
+                let extracted = if metadata.is_file() {
+                    if patch.is_none() {
+                        return Err(format!(
+                            "{} is a patch archive, import it with --patch",
+                            directory.display()
+                        ));
+                    }
+                    Some(store.extract_patch_archive(&directory)?)
+                } else if !metadata.is_dir() {
                     return Err(format!("{} is not a directory", directory.display()));
+                } else {
+                    None
+                };
+                if !bare && extracted.is_none() {
+                    unimplemented!(
+                        "Export file import is not yet implemented, please use --bare option for now."
+                    );
                 }
+                let directory = extracted
+                    .as_ref()
+                    .map(|dir| dir.path().to_path_buf())
+                    .unwrap_or(directory);
 
                 tracing::info!(
                     "Importing directory: {} with label: {}",
@@ -133,10 +286,13 @@ impl Cli {
                 // Import the directory and get the commit ID
                 let parent_commit = if let Some(patch_ref) = patch {
                     let commit_id = patch_ref.resolve_commit_id(&store)?;
+                    // The patch isn't stored on its own, `patchset extract` recovers it
+                    // from the new commit if it's ever needed
                     store.union_patch_commit(
                         &stratum_label,
                         &directory.to_string_lossy(),
                         &commit_id,
+                        None,
                         false,
                     )?
                 } else {
@@ -182,6 +338,69 @@ impl Cli {
                 println!("Tagged commit {} with '{}'", commit_id, new_tag);
                 Ok(())
             }
+            Commands::Annotate {
+                stratum_ref,
+                requires,
+                conflicts,
+                after,
+                before,
+            } => {
+                let commit_id = stratum_ref.resolve_commit_id(&store)?;
+                let relations = crate::commit::PatchRelations {
+                    requires,
+                    conflicts,
+                    after,
+                    before,
+                };
+                store.set_patch_relations(&commit_id, relations.clone())?;
+                print!(
+                    "{}",
+                    toml::to_string(&relations)
+                        .map_err(|e| format!("Failed to serialize relations: {}", e))?
+                );
+                Ok(())
+            }
+            Commands::Commit {
+                worktree,
+                tag,
+                paths,
+                rebase,
+            } => {
+                let StratumRef::Worktree { label, worktree } = worktree else {
+                    return Err(
+                        "Invalid worktree name format. Use 'label+worktree_name'.".to_string()
+                    );
+                };
+
+                let options = crate::store::worktree_commit::CommitOptions { paths, rebase };
+                let commit_id = store.commit_worktree(&label, &worktree, &options)?;
+                store
+                    .tag_commit(&label, &commit_id, &tag)
+                    .map_err(|e| format!("Failed to tag commit '{}': {}", commit_id, e))?;
+                println!("{}  (tagged as {}:{})", commit_id, label, tag);
+                Ok(())
+            }
+            Commands::Merge {
+                ours,
+                theirs,
+                into,
+                strategy,
+            } => {
+                let (label, tag) = util::parse_label(&into)
+                    .map_err(|e| format!("Failed to parse label '{}': {}", into, e))?;
+                let tag = tag.unwrap_or_else(|| "latest".to_string());
+
+                let result = store.merge(&ours, &theirs, &label, strategy)?;
+                store
+                    .tag_commit(&label, &result.commit, &tag)
+                    .map_err(|e| format!("Failed to tag commit '{}': {}", result.commit, e))?;
+
+                for conflict in &result.conflicts {
+                    println!("{}", conflict);
+                }
+                println!("{}  (tagged as {}:{})", result.commit, label, tag);
+                Ok(())
+            }
             Commands::Untag { tag } => {
                 let (label, tag_name) = util::parse_label(&tag)
                     .map_err(|e| format!("Failed to parse tag '{}': {}", tag, e))?;
@@ -199,6 +418,11 @@ impl Cli {
             Commands::Mount {
                 stratum_ref,
                 mountpoint,
+                layers,
+                tmpfs,
+                volatile,
+                read_only,
+                uidmap,
             } => {
                 // Generate mountpoint if not provided
                 let mount_path = if let Some(mp) = mountpoint {
@@ -224,6 +448,17 @@ impl Cli {
                     mount_path
                 );
 
+                let options = crate::mount::MountOptions {
+                    tmpfs,
+                    volatile,
+                    read_only,
+                    idmap: uidmap,
+                };
+
+                if !layers.is_empty() {
+                    return store.mount_layers(&layers, &stratum_ref, &mount_path, &options);
+                }
+
                 // Extract worktree from stratum_ref using pattern matching
                 let worktree = match &stratum_ref {
                     crate::commit::StratumRef::Worktree { label: _, worktree } => {
@@ -234,7 +469,7 @@ impl Cli {
                     }
                 };
 
-                store.mount_ref(&stratum_ref, &mount_path, worktree)
+                store.mount_ref(&stratum_ref, &mount_path, worktree, &options)
             }
             Commands::Unmount { mountpoint } => {
                 tracing::info!("Unmounting stratum from {}", mountpoint.display());
@@ -256,6 +491,8 @@ impl Cli {
                     .execute(&store)
                     .map_err(|e| format!("Worktree command failed: {}", e))
             }
+            Commands::Autosnap(args) => args.execute(&store),
+            Commands::Watchdog(args) => args.execute(&store),
             Commands::Patchset(command) => {
                 // Delegate to the patchset command handler
                 command
@@ -274,6 +511,20 @@ impl Cli {
                 println!("Removed stratum reference: {}", stratum_ref);
                 Ok(())
             }
+            Commands::Config { verity } => {
+                let mut config = store.config().clone();
+                if let Some(verity) = verity {
+                    tracing::info!("Setting verity policy to {:?}", verity);
+                    config.verity = verity;
+                    store.set_config(config.clone())?;
+                }
+                print!(
+                    "{}",
+                    toml::to_string(&config)
+                        .map_err(|e| format!("Failed to serialize config: {}", e))?
+                );
+                Ok(())
+            }
         }
     }
 }
@@ -1,7 +1,25 @@
-use crate::{commit::StratumRef, patchset::Patchset, store::Store};
+use crate::{
+    commit::StratumRef,
+    mount::MountOptions,
+    patchset::{
+        Patchset,
+        build::{self, BuildEvent, BuildPhase},
+        cache::PatchsetCache,
+        include::PatchsetFile,
+        lock::PatchsetLock,
+    },
+    store::Store,
+    util::format_size,
+};
 use clap::Parser;
 use std::path::{Path, PathBuf};
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanFormat {
+    Text,
+    Json,
+}
+
 #[derive(Parser, Debug)]
 pub enum PatchsetCommand {
     #[clap(name = "build", aliases = &["b"])]
@@ -13,17 +31,256 @@ pub enum PatchsetCommand {
         /// Stratum tag to link the patchset as
         #[clap(value_parser)]
         tag: String,
+
+        /// Require the lockfile to pin every ref to the commit it currently resolves to
+        ///
+        /// Without this, pinned refs are built from their pinned commits even if they
+        /// have moved since (use `update` to move them), and refs missing from the
+        /// lockfile are pinned and added to it. With it, the build fails instead if the
+        /// lockfile is missing, lacks a ref or a ref has moved, and the lockfile is left
+        /// alone.
+        #[clap(long)]
+        locked: bool,
+
+        /// Only print what the build would do, without writing anything
+        #[clap(long)]
+        dry_run: bool,
+
+        /// Print the build plan in this format instead of building (implies --dry-run)
+        #[clap(long, value_enum)]
+        plan: Option<PlanFormat>,
+    },
+
+    /// Report which patches override or delete each other's files
+    #[clap(name = "check", aliases = &["c"])]
+    Check {
+        /// Path to the patchset definition file
+        #[clap(value_parser)]
+        patchset_file: PathBuf,
+    },
+
+    /// Mount a patchset as a stack of its patches, without building a commit
+    #[clap(name = "mount", aliases = &["m"])]
+    Mount {
+        /// Path to the patchset definition file
+        #[clap(value_parser)]
+        patchset_file: PathBuf,
+
+        /// The path to mount the patchset at
+        #[clap(value_parser)]
+        mountpoint: PathBuf,
+
+        /// Stack this worktree's upperdir on top to keep changes (e.g. `myapp+saves`)
+        #[clap(long, value_parser)]
+        worktree: Option<StratumRef>,
+
+        /// Keep changes on a throwaway tmpfs, discarded on unmount
+        #[clap(long, conflicts_with = "read_only")]
+        tmpfs: bool,
+
+        /// Skip syncing changes to disk (overlay `volatile`), for fast caches
+        ///
+        /// The worktree may be left inconsistent if the system crashes while mounted.
+        #[clap(long)]
+        volatile: bool,
+
+        /// Mount the worktree's current state without write access
+        #[clap(long)]
+        read_only: bool,
+
+        /// Idmap the mount, in the form `<from>:<to>:<count>`
+        ///
+        /// Files owned by IDs `from..from+count` on disk show up as owned by
+        /// `to..to+count`. Applies to both UIDs and GIDs.
+        #[clap(long)]
+        uidmap: Option<crate::mount::idmap::IdMap>,
+    },
+
+    /// Print a patchset equivalent to a commit's layer history
+    #[clap(name = "extract", aliases = &["x"])]
+    Extract {
+        /// The commit to extract the history of
+        #[clap(value_parser)]
+        stratum_ref: StratumRef,
+    },
+
+    /// Print a patchset definition
+    #[clap(name = "show", aliases = &["s"])]
+    Show {
+        /// Path to the patchset definition file
+        #[clap(value_parser)]
+        patchset_file: PathBuf,
+
+        /// Expand included patchsets into a single flat list of patches
+        #[clap(long)]
+        expanded: bool,
+    },
+
+    /// Re-resolve refs in the patchset's lockfile
+    #[clap(name = "update", aliases = &["u"])]
+    Update {
+        /// Path to the patchset definition file
+        #[clap(value_parser)]
+        patchset_file: PathBuf,
+
+        /// Refs to update, as written in the patchset (all of them if omitted)
+        #[clap(value_parser)]
+        refs: Vec<String>,
     },
+
+    /// Remove cached intermediate builds that haven't been used recently
+    #[clap(name = "gc")]
+    Gc {
+        /// Evict cache entries unused for this many days
+        #[clap(long, default_value_t = 30)]
+        max_age: u32,
+    },
+
+    /// Remove leftovers of builds that crashed or were killed
+    ///
+    /// Refuses to run while another stratum command is using the store.
+    #[clap(name = "clean")]
+    Clean,
 }
 
 impl PatchsetCommand {
+    /// Print what building a patchset would do, using the lockfile the same way a
+    /// build would but never saving it
+    fn plan(
+        patchset: Patchset,
+        store: &Store,
+        tag: &str,
+        existing: Option<PatchsetLock>,
+        locked: bool,
+        format: PlanFormat,
+    ) -> Result<(), String> {
+        let mut lock_problems = Vec::new();
+        let lock = match (existing, locked) {
+            (None, true) => {
+                lock_problems.push("No lockfile, build without --locked to create it".to_string());
+                None
+            }
+            (Some(lock), true) => {
+                lock_problems.extend(lock.drifted(&patchset, store).unwrap_or_else(|e| vec![e]));
+                Some(lock)
+            }
+            (existing, false) => {
+                match PatchsetLock::resolve(&patchset, store, existing.as_ref(), |_| false) {
+                    Ok(lock) => Some(lock),
+                    Err(e) => {
+                        lock_problems.push(e);
+                        existing
+                    }
+                }
+            }
+        };
+        let patchset = match &lock {
+            Some(lock) => patchset.with_lock(lock),
+            None => patchset,
+        };
+
+        let mut plan = patchset.plan(store, tag)?;
+        plan.problems.extend(lock_problems);
+        match format {
+            PlanFormat::Text => print!("{plan}"),
+            PlanFormat::Json => println!(
+                "{}",
+                serde_json::to_string_pretty(&plan)
+                    .map_err(|e| format!("Failed to serialize build plan: {}", e))?
+            ),
+        }
+
+        if plan.is_ok() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Build would fail: {} problems",
+                plan.problems.len()
+            ))
+        }
+    }
+
+    /// Print build progress to stderr, keeping stdout for the result
+    fn report_progress(event: BuildEvent) {
+        match event {
+            BuildEvent::Started { total, cached } if cached > 0 => {
+                eprintln!(
+                    "Reusing {} of {} patches from the build cache",
+                    cached, total
+                )
+            }
+            BuildEvent::Started { .. } => {}
+            BuildEvent::Patch {
+                index,
+                total,
+                name,
+                phase,
+            } => {
+                let phase = match phase {
+                    BuildPhase::Staging => "staging".to_string(),
+                    BuildPhase::Staged { bytes } => format!("staged {}", format_size(bytes)),
+                    BuildPhase::Committing => "committing".to_string(),
+                    BuildPhase::Done => "done".to_string(),
+                };
+                eprintln!("[{}/{}] {}: {}", index, total, name, phase);
+            }
+            BuildEvent::Finished { commit } => eprintln!("Built {}", commit),
+        }
+    }
+
     /// Execute the patchset command
     pub fn execute(self, store: &Store) -> Result<(), String> {
         match self {
-            PatchsetCommand::Build { patchset_file, tag } => {
+            PatchsetCommand::Build {
+                patchset_file,
+                tag,
+                locked,
+                dry_run,
+                plan,
+            } => {
                 let patchset = Patchset::load_patchset_from_file(&patchset_file)?;
+                let lock_path = PatchsetLock::path_for(&patchset_file);
+                let existing = PatchsetLock::load(&lock_path)?;
+                if dry_run || plan.is_some() {
+                    return Self::plan(
+                        patchset,
+                        store,
+                        &tag,
+                        existing,
+                        locked,
+                        plan.unwrap_or(PlanFormat::Text),
+                    );
+                }
+                let lock = if locked {
+                    let lock = existing.ok_or_else(|| {
+                        format!(
+                            "No lockfile at {}, build without --locked to create it",
+                            lock_path.display()
+                        )
+                    })?;
+                    let drifted = lock.drifted(&patchset, store)?;
+                    if !drifted.is_empty() {
+                        return Err(format!(
+                            "Lockfile {} is out of date:\n  {}",
+                            lock_path.display(),
+                            drifted.join("\n  ")
+                        ));
+                    }
+                    lock
+                } else {
+                    PatchsetLock::resolve(&patchset, store, existing.as_ref(), |_| false)?
+                };
+                let patchset = patchset.with_lock(&lock);
+
+                println!("Load order:");
+                for (i, entry) in patchset.ordered_entries(store)?.iter().enumerate() {
+                    println!("  {}. {}", i + 1, entry.display_name());
+                }
+
+                let conflicts = patchset.check(store)?;
+                build::handle_interrupts();
                 let artifact = patchset
-                    .generate_commit(store, &tag)
+                    .generate_commit_with_progress(store, &tag, &Self::report_progress)
                     .map_err(|e| format!("Failed to generate commit from patchset: {}", e))?;
                 tracing::info!(
                     "Patchset '{}' built successfully with tag '{}'",
@@ -34,7 +291,122 @@ impl PatchsetCommand {
                 let commit_id = artifact.resolve_commit_id(store)?;
                 tracing::info!("Patchset commit ID: {}", commit_id);
 
+                if !locked {
+                    lock.save(&lock_path)?;
+                }
+
                 println!("{commit_id} ({tag})");
+                if !conflicts.is_empty() {
+                    println!();
+                    print!("conflicts: {conflicts}");
+                }
+                Ok(())
+            }
+            PatchsetCommand::Check { patchset_file } => {
+                let mut patchset = Patchset::load_patchset_from_file(&patchset_file)?;
+                if let Some(lock) = PatchsetLock::load(&PatchsetLock::path_for(&patchset_file))? {
+                    patchset = patchset.with_lock(&lock);
+                }
+                let report = patchset.check(store)?;
+                print!("{report}");
+                Ok(())
+            }
+            PatchsetCommand::Mount {
+                patchset_file,
+                mountpoint,
+                worktree,
+                tmpfs,
+                volatile,
+                read_only,
+                uidmap,
+            } => {
+                let mut patchset = Patchset::load_patchset_from_file(&patchset_file)?;
+                if let Some(lock) = PatchsetLock::load(&PatchsetLock::path_for(&patchset_file))? {
+                    patchset = patchset.with_lock(&lock);
+                }
+                patchset.mount(
+                    store,
+                    &mountpoint.to_string_lossy(),
+                    worktree.as_ref(),
+                    &MountOptions {
+                        tmpfs,
+                        volatile,
+                        read_only,
+                        idmap: uidmap,
+                    },
+                )?;
+                println!(
+                    "Mounted {} at {}",
+                    patchset_file.display(),
+                    mountpoint.display()
+                );
+                Ok(())
+            }
+            PatchsetCommand::Extract { stratum_ref } => {
+                let patchset = Patchset::from_history(store, &stratum_ref)?;
+                print!(
+                    "{}",
+                    toml::to_string(&patchset)
+                        .map_err(|e| format!("Failed to serialize patchset: {}", e))?
+                );
+                Ok(())
+            }
+            PatchsetCommand::Show {
+                patchset_file,
+                expanded,
+            } => {
+                let content = if expanded {
+                    toml::to_string(&Patchset::load_patchset_from_file(&patchset_file)?)
+                } else {
+                    toml::to_string(&PatchsetFile::load(&patchset_file)?)
+                }
+                .map_err(|e| format!("Failed to serialize patchset: {}", e))?;
+                print!("{content}");
+                Ok(())
+            }
+            PatchsetCommand::Update {
+                patchset_file,
+                refs,
+            } => {
+                let patchset = Patchset::load_patchset_from_file(&patchset_file)?;
+                let known_refs = patchset.refs();
+                if let Some(unknown) = refs.iter().find(|r| !known_refs.contains(r)) {
+                    return Err(format!(
+                        "'{}' is not a ref in {}",
+                        unknown,
+                        patchset_file.display()
+                    ));
+                }
+
+                let lock_path = PatchsetLock::path_for(&patchset_file);
+                let existing = PatchsetLock::load(&lock_path)?;
+                let lock = PatchsetLock::resolve(&patchset, store, existing.as_ref(), |r| {
+                    refs.is_empty() || refs.iter().any(|wanted| wanted == r)
+                })?;
+
+                for entry in &lock.entries {
+                    let old = existing.as_ref().and_then(|l| l.get(&entry.reference));
+                    match old {
+                        Some(old) if old.commit == entry.commit => {}
+                        Some(old) => println!(
+                            "Updated {}: {} -> {}",
+                            entry.reference, old.commit, entry.commit
+                        ),
+                        None => println!("Locked {}: {}", entry.reference, entry.commit),
+                    }
+                }
+                lock.save(&lock_path)?;
+                Ok(())
+            }
+            PatchsetCommand::Gc { max_age } => {
+                let evicted =
+                    PatchsetCache::new(store).gc(chrono::Duration::days(i64::from(max_age)))?;
+                println!("Evicted {} cached builds", evicted);
+                Ok(())
+            }
+            PatchsetCommand::Clean => {
+                let report = crate::patchset::clean::clean(store)?;
+                print!("{report}");
                 Ok(())
             }
         }
@@ -0,0 +1,137 @@
+use crate::{
+    commit::StratumRef,
+    interrupt,
+    store::{Store, worktree_limit::LimitStatus},
+    util::format_size,
+};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+#[derive(clap::Args, Debug)]
+pub struct WatchdogArgs {
+    /// Worktrees to watch, e.g. `myapp+main`, or every worktree mounted writable
+    /// (on a tmpfs or not) if none are given
+    #[clap(value_parser)]
+    worktrees: Vec<StratumRef>,
+
+    /// Check the worktrees every this many seconds
+    #[clap(long, default_value_t = 30)]
+    interval: u64,
+
+    /// Warn once a worktree's upperdir reaches this percentage of its limit
+    #[clap(long, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    warn_at: u8,
+
+    /// Check once and exit
+    #[clap(long)]
+    once: bool,
+}
+
+impl WatchdogArgs {
+    pub fn execute(self, store: &Store) -> Result<(), String> {
+        let worktrees = self
+            .worktrees
+            .iter()
+            .map(|sref| match sref {
+                StratumRef::Worktree { label, worktree } => Ok((label.clone(), worktree.clone())),
+                _ => Err(format!(
+                    "Invalid worktree {}. Use 'label+worktree_name'.",
+                    sref
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Last status of each worktree, so warnings are only given when it changes
+        let mut statuses = HashMap::new();
+        if self.once {
+            for target in targets(store, &worktrees)? {
+                check(store, &target, self.warn_at, &mut statuses);
+            }
+            return Ok(());
+        }
+
+        interrupt::handle_interrupts();
+        let interval = Duration::from_secs(self.interval.max(1));
+        let mut next_round = Instant::now();
+        while !interrupt::interrupted() {
+            if Instant::now() >= next_round {
+                let targets = targets(store, &worktrees)?;
+                statuses.retain(|target, _| targets.contains(target));
+                for target in &targets {
+                    check(store, target, self.warn_at, &mut statuses);
+                }
+                next_round = Instant::now() + interval;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        Ok(())
+    }
+}
+
+/// The worktrees to check this round
+fn targets(store: &Store, worktrees: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+    if worktrees.is_empty() {
+        store.watched_worktrees()
+    } else {
+        Ok(worktrees.to_vec())
+    }
+}
+
+/// Check a worktree against its limit, reporting failures rather than stopping
+fn check(
+    store: &Store,
+    target: &(String, String),
+    warn_at: u8,
+    statuses: &mut HashMap<(String, String), LimitStatus>,
+) {
+    let (label, worktree) = target;
+    let usage = match store.worktree_usage(label, worktree) {
+        Ok(usage) => usage,
+        Err(e) => {
+            eprintln!("Failed to check {}+{}: {}", label, worktree, e);
+            return;
+        }
+    };
+    let status = usage.status(warn_at);
+    let previous = statuses.insert(target.clone(), status);
+    let Some(max_size) = usage.max_size else {
+        return;
+    };
+
+    if previous != Some(status) {
+        match status {
+            LimitStatus::Near => eprintln!(
+                "Worktree {}+{} is nearing its limit: {} of {}",
+                label,
+                worktree,
+                format_size(usage.used),
+                format_size(max_size)
+            ),
+            LimitStatus::Over => eprintln!(
+                "Worktree {}+{} is over its limit: {} of {}",
+                label,
+                worktree,
+                format_size(usage.used),
+                format_size(max_size)
+            ),
+            LimitStatus::Unlimited | LimitStatus::Within => {}
+        }
+    }
+
+    // Retried every round, since it fails while files are open for writing
+    if status == LimitStatus::Over && usage.read_only_over_limit {
+        match store.make_worktree_read_only(label, worktree) {
+            Ok(remounted) => {
+                for mount_point in remounted {
+                    println!(
+                        "{}+{}  (over its limit, remounted read-only at {})",
+                        label,
+                        worktree,
+                        mount_point.display()
+                    );
+                }
+            }
+            Err(e) => eprintln!("Failed to make {}+{} read-only: {}", label, worktree, e),
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use crate::commit::StratumRef;
 use crate::store::Store;
+use crate::store::rebase::{RebaseOptions, Resolution};
+use crate::util::{self, format_size};
 use clap::Parser;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 pub enum WorktreeCommand {
@@ -37,12 +40,189 @@ pub enum WorktreeCommand {
         name: StratumRef,
     },
 
+    /// Clone a worktree, including its uncommitted changes
+    ///
+    /// Files are reflinked where the filesystem supports it.
+    Fork {
+        /// Worktree to clone, in the format `label+worktree_name`
+        source: StratumRef,
+        /// The new worktree, in the format `label+worktree_name`
+        target: StratumRef,
+    },
+
+    /// Rename a worktree, possibly moving it to another label
+    #[clap(name = "rename", aliases = &["mv", "move"])]
+    Rename {
+        /// Worktree to rename, in the format `label+worktree_name`
+        worktree: StratumRef,
+        /// The new name, in the format `label+worktree_name`
+        new_name: StratumRef,
+        /// If the worktree is mounted, unmount it and mount it again under the new
+        /// name, instead of failing
+        #[clap(long)]
+        remount: bool,
+    },
+
+    /// Export a worktree's changes as a patch archive, e.g. `profile.tar.zst`
+    ///
+    /// Whiteouts and opaque directories are stored as `.wh.` files, so the archive
+    /// can be imported elsewhere with `import --patch`.
+    #[clap(name = "export-patch")]
+    ExportPatch {
+        /// Worktree to export, in the format `label+worktree_name`
+        worktree: StratumRef,
+        /// Archive to write, compressed according to its extension
+        output: PathBuf,
+    },
+
+    /// Show a worktree's disk usage and what its changes are
+    ///
+    /// Counts the files added, modified (copied up) and deleted in the upperdir, and
+    /// how much of it duplicates objects already in the store.
+    #[clap(name = "du")]
+    Du {
+        /// Worktree to inspect, in the format `label+worktree_name`
+        worktree: StratumRef,
+        /// How many of the largest files to list
+        #[clap(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Show or set the most a worktree's upperdir may hold
+    ///
+    /// Tmpfs-backed mounts get a tmpfs of that size, and disk-backed upperdirs a
+    /// project quota where the filesystem supports it. `stratum watchdog` warns as
+    /// worktrees near their limit.
+    Limit {
+        /// Worktree to limit, in the format `label+worktree_name`
+        worktree: StratumRef,
+        /// Maximum size, e.g. `10G`. Shows the current usage and limit if not given
+        #[clap(value_parser = util::parse_size)]
+        size: Option<u64>,
+        /// Have the watchdog remount the worktree read-only once it's over the limit
+        #[clap(long, requires = "size")]
+        read_only: bool,
+        /// Remove the limit
+        #[clap(long, conflicts_with = "size")]
+        clear: bool,
+    },
+
     /// Rebase a worktree to a new base commit
     Rebase {
         worktree: StratumRef,
         new_commit: StratumRef,
+        /// Swap the mount in place instead of unmounting and remounting it
+        ///
+        /// Requires Linux 6.5 or later. Fails if any process has files open in
+        /// directories whose contents change between the old and new base.
+        #[clap(long)]
+        live: bool,
+        /// Resolve conflicts by keeping the worktree's version
+        #[clap(long, conflicts_with_all = ["take_base", "abort"])]
+        keep_upper: bool,
+        /// Resolve conflicts by removing the worktree's version, so the new base's
+        /// shows through
+        #[clap(long, conflicts_with_all = ["keep_upper", "abort"])]
+        take_base: bool,
+        /// Abort if any conflict isn't resolved by the resolution file (the default)
+        #[clap(long)]
+        abort: bool,
+        /// TOML file resolving conflicts path by path, taking precedence over the
+        /// options above
+        #[clap(long, value_parser)]
+        resolutions: Option<PathBuf>,
     },
+
+    /// Shelve a worktree's changes and restore them later
+    #[clap(subcommand)]
+    Stash(StashCommand),
+}
+
+#[derive(Parser, Debug)]
+pub enum StashCommand {
+    /// Save the upperdir's changes to the stash and clear the upperdir
+    Push {
+        /// Worktree to stash, in the format `label+worktree_name`
+        worktree: StratumRef,
+        /// Note on what the changes are
+        #[clap(long, short)]
+        message: Option<String>,
+    },
+    /// Reapply a stash entry on top of the upperdir and remove it from the stash
+    ///
+    /// Fails if the entry would overwrite changes made since it was stashed.
+    Pop {
+        worktree: StratumRef,
+        /// Entry to pop, counting from the most recent one
+        #[clap(default_value_t = 0)]
+        index: usize,
+    },
+    /// List stash entries, most recent first
+    #[clap(name = "list", aliases = &["ls", "l"])]
+    List { worktree: StratumRef },
+    /// Discard a stash entry
+    #[clap(name = "drop", aliases = &["rm"])]
+    Drop {
+        worktree: StratumRef,
+        #[clap(default_value_t = 0)]
+        index: usize,
+    },
+}
+
+/// Split a `label+worktree_name` reference
+fn worktree_parts(sref: StratumRef) -> Result<(String, String), String> {
+    match sref {
+        StratumRef::Worktree { label, worktree } => Ok((label, worktree)),
+        _ => Err("Invalid worktree name format. Use 'label+worktree_name'.".to_string()),
+    }
+}
+
+/// Enforce a worktree's limit with a project quota, telling the user if the
+/// filesystem can't and it's left to the watchdog
+fn apply_quota(store: &Store, label: &str, worktree_name: &str, max_size: Option<u64>) {
+    if let Err(e) = store.apply_worktree_quota(label, worktree_name, max_size) {
+        eprintln!(
+            "{}, the limit of {}+{} is only enforced by `stratum watchdog`",
+            e, label, worktree_name
+        );
+    }
+}
+
+impl StashCommand {
+    pub fn execute(self, store: &Store) -> Result<(), String> {
+        match self {
+            StashCommand::Push { worktree, message } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                let commit_id = store.stash_worktree(&label, &worktree_name, message)?;
+                println!("{commit_id}");
+                Ok(())
+            }
+            StashCommand::Pop { worktree, index } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                store.pop_worktree_stash(&label, &worktree_name, index)
+            }
+            StashCommand::List { worktree } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                let worktree = store.load_worktree(&label, &worktree_name)?;
+                for (i, entry) in worktree.worktree.stash.iter().enumerate() {
+                    println!(
+                        "{}: {} {} {}",
+                        i,
+                        entry.created.format("%Y-%m-%d %H:%M:%S"),
+                        entry.commit,
+                        entry.message.as_deref().unwrap_or("")
+                    );
+                }
+                Ok(())
+            }
+            StashCommand::Drop { worktree, index } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                store.drop_worktree_stash(&label, &worktree_name, index)
+            }
+        }
+    }
 }
+
 impl WorktreeCommand {
     /// Execute the worktree command
     pub fn execute(self, store: &Store) -> Result<(), String> {
@@ -105,9 +285,104 @@ impl WorktreeCommand {
                 };
                 store.remove_worktree(&label, &worktree_name)
             }
+            WorktreeCommand::Fork { source, target } => {
+                let (label, worktree_name) = worktree_parts(source)?;
+                let (new_label, new_worktree_name) = worktree_parts(target)?;
+                store.fork_worktree(&label, &worktree_name, &new_label, &new_worktree_name)?;
+                let max_size = store
+                    .load_worktree(&new_label, &new_worktree_name)?
+                    .worktree
+                    .max_size;
+                if max_size.is_some() {
+                    apply_quota(store, &new_label, &new_worktree_name, max_size);
+                }
+                Ok(())
+            }
+            WorktreeCommand::Rename {
+                worktree,
+                new_name,
+                remount,
+            } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                let (new_label, new_worktree_name) = worktree_parts(new_name)?;
+                store.rename_worktree(
+                    &label,
+                    &worktree_name,
+                    &new_label,
+                    &new_worktree_name,
+                    remount,
+                )
+            }
+            WorktreeCommand::ExportPatch { worktree, output } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                store.export_worktree_patch(&label, &worktree_name, &output)?;
+                println!("{}", output.display());
+                Ok(())
+            }
+            WorktreeCommand::Du { worktree, top } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                let stats = store.worktree_stats(&label, &worktree_name, top)?;
+                println!(
+                    "Size:       {} ({} on disk)",
+                    format_size(stats.total_bytes),
+                    format_size(stats.disk_bytes)
+                );
+                println!("Added:      {} files", stats.added);
+                println!("Modified:   {} files", stats.modified);
+                println!("Deleted:    {} files", stats.deleted);
+                if stats.opaque_dirs > 0 {
+                    println!("Opaque:     {} directories", stats.opaque_dirs);
+                }
+                println!("Ignored:    {}", format_size(stats.ignored_bytes));
+                println!(
+                    "Duplicate:  {} already in the object store",
+                    format_size(stats.duplicate_bytes)
+                );
+                if !stats.largest.is_empty() {
+                    println!();
+                    println!("Largest files:");
+                    for (path, size) in &stats.largest {
+                        println!("{:>10}  {}", format_size(*size), path.display());
+                    }
+                }
+                Ok(())
+            }
+            WorktreeCommand::Limit {
+                worktree,
+                size,
+                read_only,
+                clear,
+            } => {
+                let (label, worktree_name) = worktree_parts(worktree)?;
+                if size.is_some() || clear {
+                    store.set_worktree_max_size(&label, &worktree_name, size, read_only)?;
+                    apply_quota(store, &label, &worktree_name, size);
+                    return Ok(());
+                }
+                let usage = store.worktree_usage(&label, &worktree_name)?;
+                match usage.max_size {
+                    Some(max_size) => println!(
+                        "{} of {}{}",
+                        format_size(usage.used),
+                        format_size(max_size),
+                        if usage.read_only_over_limit {
+                            " (read-only when over)"
+                        } else {
+                            ""
+                        }
+                    ),
+                    None => println!("{} (no limit)", format_size(usage.used)),
+                }
+                Ok(())
+            }
             WorktreeCommand::Rebase {
                 worktree,
                 new_commit,
+                live,
+                keep_upper,
+                take_base,
+                abort: _,
+                resolutions,
             } => {
                 let (label, worktree_name) = match worktree {
                     StratumRef::Worktree { label, worktree } => (label, worktree),
@@ -118,8 +393,27 @@ impl WorktreeCommand {
                     }
                 };
 
-                store.rebase_worktree(&label, &worktree_name, &new_commit)
+                let options = RebaseOptions {
+                    policy: if keep_upper {
+                        Some(Resolution::KeepUpper)
+                    } else if take_base {
+                        Some(Resolution::TakeBase)
+                    } else {
+                        None
+                    },
+                    resolutions: match resolutions {
+                        Some(path) => RebaseOptions::load_resolutions(&path)?,
+                        None => Default::default(),
+                    },
+                };
+
+                if live {
+                    store.rebase_worktree_live(&label, &worktree_name, &new_commit, &options)
+                } else {
+                    store.rebase_worktree(&label, &worktree_name, &new_commit, &options)
+                }
             }
+            WorktreeCommand::Stash(command) => command.execute(store),
         }
     }
 }
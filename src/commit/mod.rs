@@ -47,6 +47,34 @@ pub struct WorktreeInfo {
     pub last_committed: Option<chrono::DateTime<chrono::Utc>>,
     /// Optional description of what this worktree is for
     pub description: Option<String>,
+    /// Upperdir changes shelved with `worktree stash push`, most recent first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stash: Vec<StashEntry>,
+    /// Ignore patterns for paths to leave out of commits, on top of the label's
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    /// Most the upperdir may hold, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    /// Make mounts of the worktree read-only once the upperdir grows past `max_size`,
+    /// rather than only warning about it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only_over_limit: bool,
+    /// Project ID of the upperdir's quota, allocated the first time it's limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<u32>,
+}
+
+/// Upperdir changes of a worktree, shelved to be restored later
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct StashEntry {
+    /// Commit holding the upperdir as it was, including whiteouts and opaque directories
+    pub commit: String,
+    /// When the changes were stashed
+    pub created: chrono::DateTime<chrono::Utc>,
+    /// Optional note on what the changes are
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 
 /// Main commit structure that maps directly to TOML sections
@@ -63,6 +91,9 @@ pub struct Commit {
     pub files: FileStats,
     /// [merkle] section - merkle tree information
     pub merkle: MerkleInfo,
+    /// [patch] section - how this commit relates to other patches in a patchset
+    #[serde(default, skip_serializing_if = "PatchRelations::is_empty")]
+    pub patch: PatchRelations,
 }
 
 impl Commit {
@@ -82,6 +113,9 @@ impl Commit {
                 metadata_hash: hex::encode(metadata_hash),
                 timestamp: chrono::Utc::now(),
                 parent_commit: parent_id,
+                verity_digest: None,
+                applied_patch: None,
+                merged_parents: Vec::new(),
             },
             files: FileStats {
                 count: file_count,
@@ -91,6 +125,7 @@ impl Commit {
                 leaf_count,
                 tree_depth,
             },
+            patch: PatchRelations::default(),
         }
     }
 
@@ -137,6 +172,72 @@ pub struct CommitInfo {
     pub timestamp: chrono::DateTime<chrono::Utc>,
     /// Optional parent commit ID for history tracking
     pub parent_commit: Option<String>,
+    /// Kernel fs-verity digest of the commit's composefs image
+    ///
+    /// Only set if fs-verity was enabled on the image and all of its objects,
+    /// in which case mounts enforce it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verity_digest: Option<String>,
+    /// The patch commit applied on top of `parent_commit` to create this commit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub applied_patch: Option<String>,
+    /// Further parents of a merge commit, besides `parent_commit`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged_parents: Vec<String>,
+}
+
+impl CommitInfo {
+    /// Every parent of the commit, the first one being `parent_commit`
+    pub fn parents(&self) -> impl Iterator<Item = &str> {
+        self.parent_commit
+            .iter()
+            .chain(self.merged_parents.iter())
+            .map(String::as_str)
+    }
+}
+
+/// [patch] section - load order constraints for using a commit as a patch
+///
+/// Other patches are referred to by their ref as written in the patchset, or by
+/// their name. See [`crate::patchset::order`] for how these are resolved.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchRelations {
+    /// Patches that must be present, and are applied before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requires: Vec<String>,
+    /// Patches that can't be applied together with this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    /// Patches applied before this one, if present
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+    /// Patches applied after this one, if present
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub before: Vec<String>,
+}
+
+impl PatchRelations {
+    pub fn is_empty(&self) -> bool {
+        self.requires.is_empty()
+            && self.conflicts.is_empty()
+            && self.after.is_empty()
+            && self.before.is_empty()
+    }
+
+    /// Add every relation from `other` that isn't already present
+    pub fn merge(&mut self, other: &PatchRelations) {
+        fn extend(into: &mut Vec<String>, from: &[String]) {
+            for item in from {
+                if !into.contains(item) {
+                    into.push(item.clone());
+                }
+            }
+        }
+        extend(&mut self.requires, &other.requires);
+        extend(&mut self.conflicts, &other.conflicts);
+        extend(&mut self.after, &other.after);
+        extend(&mut self.before, &other.before);
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -167,6 +268,11 @@ impl Worktree {
                 last_modified: now,
                 last_committed: None,
                 description,
+                stash: Vec::new(),
+                ignore: Vec::new(),
+                max_size: None,
+                read_only_over_limit: false,
+                project_id: None,
             },
         }
     }
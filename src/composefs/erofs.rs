@@ -6,9 +6,38 @@
 //! and we want to be able to read and write them
 use composefs::erofs::reader::{DirectoryEntry, InodeHeader, InodeOps, InodeType};
 use rustix::path::Arg;
+use std::collections::{BTreeMap, HashMap};
 use tracing::trace;
 use zerocopy::FromBytes;
 
+/// `EROFS_FT_CHRDEV`, the directory entry file type of character devices
+const EROFS_FT_CHRDEV: u8 = 3;
+
+/// What an entry in a composefs image means to OverlayFS, when the image is a layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayEntryKind {
+    /// A directory, merged with same-named directories in lower layers
+    Directory,
+    /// An opaque directory, hiding everything beneath it in lower layers
+    OpaqueDirectory,
+    /// A whiteout, deleting the path from lower layers
+    Whiteout,
+    /// Anything else (regular files, symlinks, devices...), replacing the path in lower layers
+    File,
+}
+
+/// What identifies an entry's contents, to compare images without mounting them
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EntryFingerprint {
+    kind: OverlayEntryKind,
+    file_type: u8,
+    size: u64,
+    /// The object holding the file's data, for files stored in the object store
+    redirect: Option<String>,
+    /// The file's data, for files small enough to be stored in the image
+    inline: Vec<u8>,
+}
+
 pub struct ErofsImage<'i> {
     pub i: composefs::erofs::reader::Image<'i>,
 }
@@ -186,6 +215,132 @@ impl<'i> ErofsImage<'i> {
         Ok(nid)
     }
 
+    /// List every path in the image, relative to the root, along with what it
+    /// means to OverlayFS when the image is used as a layer.
+    pub fn overlay_entries(&self) -> Vec<(String, OverlayEntryKind)> {
+        let mut entries = Vec::new();
+        self.collect_overlay_entries(&self.i.root(), "", &mut entries);
+        entries
+    }
+
+    /// List the paths whose entries differ between this image and `other`
+    ///
+    /// Paths only in one of the images count as changed, as do entries whose type,
+    /// size or contents differ. Contents are compared by the object a file is stored
+    /// in, or its inline data. Directories are only compared by their overlay kind.
+    pub fn changed_paths(&self, other: &ErofsImage) -> Vec<String> {
+        let ours = self.entry_fingerprints();
+        let theirs = other.entry_fingerprints();
+        let mut changed = ours
+            .iter()
+            .filter(|(path, fingerprint)| theirs.get(*path) != Some(*fingerprint))
+            .map(|(path, _)| path.clone())
+            .chain(theirs.keys().filter(|p| !ours.contains_key(*p)).cloned())
+            .collect::<Vec<_>>();
+        changed.sort();
+        changed
+    }
+
+    fn entry_fingerprints(&self) -> BTreeMap<String, EntryFingerprint> {
+        let kinds = self
+            .overlay_entries()
+            .into_iter()
+            .collect::<HashMap<_, _>>();
+        let mut fingerprints = BTreeMap::new();
+        self.collect_fingerprints(&self.i.root(), "", &kinds, &mut fingerprints);
+        fingerprints
+    }
+
+    fn collect_fingerprints<'a>(
+        &'a self,
+        dir: &InodeType<'a>,
+        prefix: &str,
+        kinds: &HashMap<String, OverlayEntryKind>,
+        out: &mut BTreeMap<String, EntryFingerprint>,
+    ) {
+        for entry in self.list_files(dir) {
+            if entry.name == b"." || entry.name == b".." {
+                continue;
+            }
+            let path = format!("{}{}", prefix, String::from_utf8_lossy(entry.name));
+            let nid = entry.header.inode_offset.get();
+            let inode = self.i.inode(nid);
+            let kind = kinds.get(&path).copied().unwrap_or(OverlayEntryKind::File);
+
+            if inode.mode().is_dir() {
+                out.insert(
+                    path.clone(),
+                    EntryFingerprint {
+                        kind,
+                        file_type: entry.header.file_type,
+                        size: 0,
+                        redirect: None,
+                        inline: Vec::new(),
+                    },
+                );
+                self.collect_fingerprints(&inode, &format!("{}/", path), kinds, out);
+            } else {
+                out.insert(
+                    path,
+                    EntryFingerprint {
+                        kind,
+                        file_type: entry.header.file_type,
+                        size: inode.size(),
+                        redirect: self.get_overlay_redirect(nid).ok().flatten(),
+                        inline: inode_inline(&inode).to_vec(),
+                    },
+                );
+            }
+        }
+    }
+
+    fn collect_overlay_entries<'a>(
+        &'a self,
+        dir: &InodeType<'a>,
+        prefix: &str,
+        out: &mut Vec<(String, OverlayEntryKind)>,
+    ) {
+        for entry in self.list_files(dir) {
+            if entry.name == b"." || entry.name == b".." {
+                continue;
+            }
+            let path = format!("{}{}", prefix, String::from_utf8_lossy(entry.name));
+            let nid = entry.header.inode_offset.get();
+            let inode = self.i.inode(nid);
+            // Overlay xattrs from the source tree are escaped by mkcomposefs, so that
+            // they apply to whoever stacks the image rather than to the image itself
+            let xattrs = self.get_xattrs(nid).unwrap_or_default();
+            let has_xattr = |name: &str, value: Option<&[u8]>| {
+                [format!("overlay.{name}"), format!("overlay.overlay.{name}")]
+                    .iter()
+                    .any(|key| {
+                        xattrs
+                            .get(key)
+                            .is_some_and(|v| value.is_none_or(|value| v == value))
+                    })
+            };
+
+            if inode.mode().is_dir() {
+                let kind = if has_xattr("opaque", Some(b"y")) {
+                    OverlayEntryKind::OpaqueDirectory
+                } else {
+                    OverlayEntryKind::Directory
+                };
+                out.push((path.clone(), kind));
+                self.collect_overlay_entries(&inode, &format!("{}/", path), out);
+            } else {
+                let whiteout = (entry.header.file_type == EROFS_FT_CHRDEV && inode.u() == 0)
+                    || has_xattr("whiteout", None);
+                let kind = if whiteout {
+                    OverlayEntryKind::Whiteout
+                } else {
+                    OverlayEntryKind::File
+                };
+                out.push((path, kind));
+            }
+        }
+    }
+
     /// Check if a DirectoryEntry is an OverlayFS whiteout or an opaque directory.
     ///
     /// If it is, return the given argument.
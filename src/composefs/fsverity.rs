@@ -8,3 +8,219 @@ pub struct VerityLayer {
     pub context: Vec<u8>,
     remaining: usize,
 }
+
+/// `FS_IOC_ENABLE_VERITY`, `_IOW('f', 133, struct fsverity_enable_arg)`
+const FS_IOC_ENABLE_VERITY: libc::c_ulong = 0x4080_6685;
+/// `FS_IOC_MEASURE_VERITY`, `_IOWR('f', 134, struct fsverity_digest)`
+const FS_IOC_MEASURE_VERITY: libc::c_ulong = 0xc004_6686;
+const FS_VERITY_HASH_ALG_SHA256: u32 = 1;
+const SHA256_DIGEST_SIZE: usize = 32;
+
+/// Mirror of the kernel's `struct fsverity_enable_arg`
+#[repr(C)]
+struct FsVerityEnableArg {
+    version: u32,
+    hash_algorithm: u32,
+    block_size: u32,
+    salt_size: u32,
+    salt_ptr: u64,
+    sig_size: u32,
+    reserved1: u32,
+    sig_ptr: u64,
+    reserved2: [u64; 11],
+}
+
+/// Mirror of the kernel's `struct fsverity_digest`, with room for a SHA-256 digest
+#[repr(C)]
+struct FsVerityDigest {
+    digest_algorithm: u16,
+    digest_size: u16,
+    digest: [u8; SHA256_DIGEST_SIZE],
+}
+
+/// Enable kernel fs-verity (SHA-256, 4K blocks) on a file, as composefs expects
+///
+/// Succeeds if verity is already enabled. Fails with `EOPNOTSUPP`/`ENOTTY` if the
+/// backing filesystem doesn't support fs-verity.
+pub fn enable_verity(path: &std::path::Path) -> std::io::Result<()> {
+    // fs-verity can't be enabled while the file is open for writing anywhere,
+    // including by us.
+    let file = std::fs::File::open(path)?;
+    let arg = FsVerityEnableArg {
+        version: 1,
+        hash_algorithm: FS_VERITY_HASH_ALG_SHA256,
+        block_size: 4096,
+        salt_size: 0,
+        salt_ptr: 0,
+        sig_size: 0,
+        reserved1: 0,
+        sig_ptr: 0,
+        reserved2: [0; 11],
+    };
+    // SAFETY: `arg` is a valid fsverity_enable_arg that outlives the call
+    let ret = unsafe {
+        libc::ioctl(
+            std::os::fd::AsRawFd::as_raw_fd(&file),
+            FS_IOC_ENABLE_VERITY as _,
+            &arg as *const FsVerityEnableArg,
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EEXIST) {
+            return Ok(());
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Read the kernel fs-verity digest of an open file
+///
+/// Fails with `ENODATA` if fs-verity isn't enabled on the file.
+pub fn measure_verity(fd: impl std::os::fd::AsFd) -> std::io::Result<[u8; SHA256_DIGEST_SIZE]> {
+    let mut digest = FsVerityDigest {
+        digest_algorithm: 0,
+        digest_size: SHA256_DIGEST_SIZE as u16,
+        digest: [0; SHA256_DIGEST_SIZE],
+    };
+    // SAFETY: `digest` is a valid fsverity_digest with `digest_size` bytes of room
+    let ret = unsafe {
+        libc::ioctl(
+            std::os::fd::AsRawFd::as_raw_fd(&fd.as_fd()),
+            FS_IOC_MEASURE_VERITY as _,
+            &mut digest as *mut FsVerityDigest,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if u32::from(digest.digest_algorithm) != FS_VERITY_HASH_ALG_SHA256 {
+        return Err(std::io::Error::other(format!(
+            "unexpected fs-verity digest algorithm {}",
+            digest.digest_algorithm
+        )));
+    }
+    Ok(digest.digest)
+}
+
+/// Block size of the Merkle tree, as used by [`enable_verity`]
+const VERITY_BLOCK_SIZE: usize = 4096;
+
+/// Compute the fs-verity digest (SHA-256, 4K blocks, no salt) of a file's contents in
+/// userspace
+///
+/// This is what [`measure_verity`] returns once verity is enabled, so it can be used
+/// for files on filesystems without fs-verity support, or that aren't in the store.
+pub fn compute_verity_digest(
+    mut reader: impl std::io::Read,
+) -> std::io::Result<[u8; SHA256_DIGEST_SIZE]> {
+    fn hash_block(data: &[u8]) -> [u8; SHA256_DIGEST_SIZE] {
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(data);
+        hasher.update(&[0u8; VERITY_BLOCK_SIZE][..VERITY_BLOCK_SIZE - data.len()]);
+        hasher.finalize().into()
+    }
+
+    /// Add a hash to a level of the tree, hashing the level's block into the next
+    /// level once it's full
+    fn push_hash(levels: &mut Vec<Vec<u8>>, mut level: usize, mut hash: [u8; SHA256_DIGEST_SIZE]) {
+        loop {
+            if levels.len() == level {
+                levels.push(Vec::with_capacity(VERITY_BLOCK_SIZE));
+            }
+            levels[level].extend_from_slice(&hash);
+            if levels[level].len() < VERITY_BLOCK_SIZE {
+                return;
+            }
+            hash = hash_block(&levels[level]);
+            levels[level].clear();
+            level += 1;
+        }
+    }
+
+    // Only the partially filled block of each level is kept around
+    let mut levels: Vec<Vec<u8>> = Vec::new();
+    let mut size = 0u64;
+    let mut block = vec![0u8; VERITY_BLOCK_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < VERITY_BLOCK_SIZE {
+            match reader.read(&mut block[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        size += filled as u64;
+        push_hash(&mut levels, 0, hash_block(&block[..filled]));
+        if filled < VERITY_BLOCK_SIZE {
+            break;
+        }
+    }
+
+    // Flush the partial blocks upwards until a single hash is left at the top
+    let mut root = [0u8; SHA256_DIGEST_SIZE];
+    let mut level = 0;
+    while level < levels.len() {
+        if levels[level].len() == SHA256_DIGEST_SIZE
+            && levels[level + 1..].iter().all(|l| l.is_empty())
+        {
+            root.copy_from_slice(&levels[level]);
+            break;
+        }
+        if !levels[level].is_empty() {
+            let hash = hash_block(&levels[level]);
+            levels[level].clear();
+            push_hash(&mut levels, level + 1, hash);
+        }
+        level += 1;
+    }
+
+    // struct fsverity_descriptor
+    let mut descriptor = [0u8; 256];
+    descriptor[0] = 1; // version
+    descriptor[1] = FS_VERITY_HASH_ALG_SHA256 as u8;
+    descriptor[2] = VERITY_BLOCK_SIZE.trailing_zeros() as u8; // log_blocksize
+    descriptor[8..16].copy_from_slice(&size.to_le_bytes());
+    descriptor[16..16 + SHA256_DIGEST_SIZE].copy_from_slice(&root);
+    Ok(sha2::Sha256::digest(descriptor).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_verity_digest() {
+        let digest = |data: &[u8]| hex::encode(compute_verity_digest(data).unwrap());
+        assert_eq!(
+            digest(b""),
+            "3d248ca542a24fc62d1c43b916eae5016878e2533c88238480b26128a1f1af95"
+        );
+        assert_eq!(
+            digest(b"hello\n"),
+            "9c76eecc7b76fcb46199cb27b90cf59a660e10575bb0412128905129d5b1c2aa"
+        );
+        assert_eq!(
+            digest(&[b'a'; 5000]),
+            "918347c69490f04c08ed15c9711f5da336fac318892ef517e47f6c5c3f1c5811"
+        );
+        // A full level of the tree, and one more block than that
+        let data = (0..129 * 4096 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            digest(&data[..128 * 4096]),
+            "d82861203d50ae9b60948504a704f35f5118bd229aeb1a22d6dae47b1767c4c4"
+        );
+        assert_eq!(
+            digest(&data),
+            "34b166bae3def142d696164d60139da4c7698cabdc0c950f1d8e82fd701ad2c3"
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Ignore rules for worktree commits
+//!
+//! Worktrees collect things that should never be committed, like shader caches,
+//! logs and crash dumps. Patterns are gathered from the label's `meta.toml`, the
+//! worktree's `meta.toml` and a `.stratumignore` file at the root of the worktree,
+//! in that order. They follow `.gitignore` rules:
+//!
+//! - blank lines and lines starting with `#` are skipped
+//! - `!` negates a pattern, re-including what an earlier pattern ignored
+//! - a trailing `/` only matches directories
+//! - patterns with a `/` at the start or in the middle match from the worktree root,
+//!   others match a file or directory name at any depth
+//! - `*` and `?` match within a path component, `**` across components
+//!
+//! As with git, nothing beneath an ignored directory can be re-included. Ignored
+//! paths stay in the upperdir, they're only left out of commits.
+
+use std::path::Path;
+
+use crate::util::glob_match;
+
+/// Name of the ignore file at the root of a worktree
+pub const IGNORE_FILE: &str = ".stratumignore";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    pattern: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Parse rules from the contents of an ignore file
+    pub fn parse(content: &str) -> Self {
+        let mut rules = Self::default();
+        rules.extend(content.lines());
+        rules
+    }
+
+    /// Add patterns, which take precedence over the ones added before
+    pub fn extend<'a>(&mut self, patterns: impl IntoIterator<Item = &'a str>) {
+        for line in patterns {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let dir_only = pattern.ends_with('/');
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            self.rules.push(Rule {
+                anchored: pattern.contains('/'),
+                pattern: pattern.trim_start_matches('/').to_string(),
+                negated,
+                dir_only,
+            });
+        }
+    }
+
+    /// Whether `path`, relative to the worktree root, is ignored
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        // Anything beneath an ignored directory is ignored
+        let mut ancestor = Path::new("").to_path_buf();
+        let components = path.components().collect::<Vec<_>>();
+        for component in components.iter().take(components.len().saturating_sub(1)) {
+            ancestor.push(component);
+            if self.matches(&ancestor, true) {
+                return true;
+            }
+        }
+        self.matches(path, is_dir)
+    }
+
+    /// Whether the last rule matching `path` ignores it
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let path_str = path.to_string_lossy();
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                (is_dir || !rule.dir_only)
+                    && if rule.anchored {
+                        glob_match(&rule.pattern, &path_str)
+                    } else {
+                        glob_match(&rule.pattern, &name)
+                    }
+            })
+            .is_some_and(|rule| !rule.negated)
+    }
+
+    /// Whether anything beneath `dir` isn't ignored
+    ///
+    /// Directories only count if they're empty, since they're otherwise just there
+    /// to hold their contents.
+    pub fn has_unignored(&self, dir: &Path) -> std::io::Result<bool> {
+        fn walk(rules: &IgnoreRules, root: &Path, dir: &Path) -> std::io::Result<bool> {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                let is_dir = entry.file_type()?.is_dir();
+                if rules.is_ignored(relative, is_dir) {
+                    continue;
+                }
+                if !is_dir
+                    || std::fs::read_dir(&path)?.next().is_none()
+                    || walk(rules, root, &path)?
+                {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        walk(self, dir, dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_rules() {
+        let rules = IgnoreRules::parse(
+            "# caches\n\
+             *.log\n\
+             !keep.log\n\
+             shadercache/\n\
+             /crash\n\
+             Data/**/*.tmp\n",
+        );
+        let ignored = |path: &str, is_dir: bool| rules.is_ignored(Path::new(path), is_dir);
+
+        assert!(ignored("game.log", false));
+        assert!(ignored("logs/deep/game.log", false));
+        assert!(!ignored("keep.log", false));
+        assert!(ignored("shadercache", true));
+        assert!(!ignored("shadercache", false));
+        assert!(ignored("drive_c/shadercache/a.bin", false));
+        assert!(ignored("crash", true));
+        assert!(!ignored("saves/crash", false));
+        assert!(ignored("Data/textures/sky.tmp", false));
+        assert!(!ignored("textures/sky.tmp", false));
+        assert!(!ignored("saves/slot1.sav", false));
+    }
+}
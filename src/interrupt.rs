@@ -0,0 +1,34 @@
+//! Ctrl-C handling for long-running commands
+//!
+//! Patchset builds and background loops like `autosnap` shouldn't be killed halfway
+//! through a step. Once [`handle_interrupts`] is installed, Ctrl-C only sets a flag,
+//! which they check between steps with [`interrupted`]. Pressing Ctrl-C again kills
+//! the process right away.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    // Let a second Ctrl-C kill the process
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_DFL);
+    }
+}
+
+/// Catch Ctrl-C instead of letting it kill the process
+pub fn handle_interrupts() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Whether Ctrl-C was pressed since [`handle_interrupts`] was installed
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -2,6 +2,8 @@ use clap::Parser;
 mod cli;
 mod commit;
 mod composefs;
+mod ignore;
+mod interrupt;
 mod mount;
 mod object;
 mod patchset;
@@ -24,7 +26,8 @@ fn main() {
         .add_directive("sled=off".parse().unwrap());
 
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        // Logs go to stderr, so command output can be redirected
+        .with(fmt::layer().with_writer(std::io::stderr))
         .with(filter)
         .init();
 
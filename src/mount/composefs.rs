@@ -287,23 +287,33 @@ pub fn overlayfs_set_fd(
 }
 
 /// Sets the "lowerdir+" and "datadir+" mount options of an overlayfs mount.
+///
+/// Lower layers are given topmost first, following the kernel's lowerdir order.
 pub fn overlayfs_set_lower_and_data_fds(
     fs_fd: impl AsFd,
-    lower_fd: impl AsFd,
+    lower_fds: &[BorrowedFd<'_>],
     data_fd: Option<impl AsFd>,
 ) -> rustix::io::Result<()> {
-    overlayfs_set_fd(fs_fd.as_fd(), "lowerdir+", lower_fd.as_fd())?;
+    for lower_fd in lower_fds {
+        overlayfs_set_fd(fs_fd.as_fd(), "lowerdir+", lower_fd)?;
+    }
     if let Some(data_fd) = data_fd {
         overlayfs_set_fd(fs_fd.as_fd(), "datadir+", data_fd.as_fd())?;
     }
     Ok(())
 }
 
+/// The most layers the kernel stacks in a single overlay mount (`OVL_MAX_STACK`),
+/// counting lowerdirs and data-only layers
+pub const MAX_LOWER_LAYERS: usize = 500;
+
 /// Configuration for composefs mounting with optional upperdir support
 #[derive(Debug)]
 pub struct ComposeFsConfig {
     /// The composefs image file descriptor
     pub image_fd: OwnedFd,
+    /// Additional composefs images stacked beneath `image_fd`, topmost first
+    pub lower_images: Vec<OwnedFd>,
     /// The name/source identifier for the mount
     pub name: String,
     /// Optional custom source name for the mount (shows in /proc/mounts)
@@ -314,12 +324,24 @@ pub struct ComposeFsConfig {
     pub upperdir: Option<PathBuf>,
     /// Optional workdir for overlay (required if upperdir is provided)
     pub workdir: Option<PathBuf>,
-    /// Whether to enable verity checking
+    /// Whether to enable verity checking (`verity=require`)
+    ///
+    /// All objects must have fs-verity enabled with the digests recorded in the image,
+    /// otherwise reading them fails.
     pub verity_required: bool,
     /// Whether to enable metacopy
     pub metacopy: bool,
     /// Whether to enable redirect_dir
     pub redirect_dir: bool,
+    /// Whether to use overlay's `volatile` option, skipping all syncs to the upperdir
+    pub volatile: bool,
+    /// Whether to mount without write access
+    ///
+    /// The upperdir, if any, is stacked as the topmost lowerdir instead, so no
+    /// workdir is needed.
+    pub read_only: bool,
+    /// Optional ID mapping for an idmapped mount
+    pub idmap: Option<super::idmap::IdMap>,
 }
 
 impl ComposeFsConfig {
@@ -327,14 +349,18 @@ impl ComposeFsConfig {
     pub fn read_only(image_fd: OwnedFd, name: String) -> Self {
         Self {
             image_fd,
+            lower_images: Vec::new(),
             name,
             source_name: None,
             basedir: None,
             upperdir: None,
             workdir: None,
-            verity_required: true,
+            verity_required: false,
             metacopy: true,
             redirect_dir: true,
+            volatile: false,
+            read_only: false,
+            idmap: None,
         }
     }
 
@@ -347,14 +373,18 @@ impl ComposeFsConfig {
     ) -> Self {
         Self {
             image_fd,
+            lower_images: Vec::new(),
             name,
             source_name: None,
             basedir: None,
             upperdir: Some(upperdir),
             workdir,
-            verity_required: true,
+            verity_required: false,
             metacopy: true,
             redirect_dir: true,
+            volatile: false,
+            read_only: false,
+            idmap: None,
         }
     }
 
@@ -364,6 +394,12 @@ impl ComposeFsConfig {
         self
     }
 
+    /// Stack additional composefs images beneath the main image, topmost first
+    pub fn with_lower_images(mut self, lower_images: Vec<OwnedFd>) -> Self {
+        self.lower_images = lower_images;
+        self
+    }
+
     /// Set custom source name for the mount
     pub fn with_source_name(mut self, source_name: String) -> Self {
         self.source_name = Some(source_name);
@@ -387,6 +423,24 @@ impl ComposeFsConfig {
         self.redirect_dir = enabled;
         self
     }
+
+    /// Set volatile option
+    pub fn with_volatile(mut self, enabled: bool) -> Self {
+        self.volatile = enabled;
+        self
+    }
+
+    /// Set whether the mount is read-only
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Set the ID mapping for an idmapped mount
+    pub fn with_idmap(mut self, idmap: Option<super::idmap::IdMap>) -> Self {
+        self.idmap = idmap;
+        self
+    }
 }
 
 /// Mounts an EROFS filesystem image.
@@ -395,7 +449,8 @@ pub fn erofs_fsmount(
     _config: &ComposeFsConfig, // Keep config for future EROFS options
 ) -> Result<OwnedFd> {
     let erofs = rustix::mount::fsopen("erofs", FsOpenFlags::empty())?; // Changed FsMountFlags to FsOpenFlags
-    // TODO: Handle config.verity, config.metacopy, config.redirect_dir if applicable to EROFS
+    // verity, metacopy and redirect_dir are overlay options, see composefs_fsmount. The
+    // image itself is checked by its fs-verity digest before it gets here.
     fsconfig_set_string(erofs.as_fd(), "source", proc_self_fd(image_fd))?;
     fsconfig_create(erofs.as_fd())?;
     let mnt_fd = fsmount(
@@ -422,17 +477,44 @@ pub fn composefs_fsmount(
         workdir_path
     );
 
-    // Mount EROFS base layer
-    let erofs_mnt_raw = erofs_fsmount(config.image_fd.as_fd(), config)?;
-    tracing::debug!("EROFS layer mounted, raw fd: {}", erofs_mnt_raw.as_raw_fd());
+    // Mount EROFS layers, topmost first
+    let mut erofs_mnts = Vec::with_capacity(1 + config.lower_images.len());
+    for image_fd in std::iter::once(&config.image_fd).chain(config.lower_images.iter()) {
+        let erofs_mnt_raw = erofs_fsmount(image_fd.as_fd(), config)?;
+        tracing::debug!("EROFS layer mounted, raw fd: {}", erofs_mnt_raw.as_raw_fd());
 
-    // Prepare the EROFS mount (e.g., move to temp dir for older kernels)
-    let erofs_mnt = prepare_mount(erofs_mnt_raw)?;
-    let erofs_mnt_fd = erofs_mnt.as_fd(); // erofs_mnt now holds the (potentially TmpMount) prepared mount
-    tracing::debug!(
-        "EROFS mount prepared, effective fd: {}",
-        erofs_mnt_fd.as_raw_fd()
-    );
+        // Prepare the EROFS mount (e.g., move to temp dir for older kernels)
+        let erofs_mnt = prepare_mount(erofs_mnt_raw)?;
+        tracing::debug!(
+            "EROFS mount prepared, effective fd: {}",
+            erofs_mnt.as_fd().as_raw_fd()
+        );
+        erofs_mnts.push(erofs_mnt);
+    }
+    // erofs_mnts now holds the (potentially TmpMount) prepared mounts
+    let mut erofs_mnt_fds = erofs_mnts.iter().map(|m| m.as_fd()).collect::<Vec<_>>();
+
+    // For read-only mounts, stack the upperdir as the topmost lowerdir instead
+    let upper_as_lower = match upperdir_path.or(config.upperdir.as_deref()) {
+        Some(upath) if config.read_only => {
+            tracing::debug!("Read-only mount, using upperdir {:?} as a lowerdir", upath);
+            Some(open(
+                upath,
+                RustixFsOFlags::PATH | RustixFsOFlags::DIRECTORY | RustixFsOFlags::CLOEXEC,
+                RustixFsMode::empty(),
+            )?)
+        }
+        _ => None,
+    };
+    if let Some(fd) = upper_as_lower.as_ref() {
+        erofs_mnt_fds.insert(0, fd.as_fd());
+    }
+    let (upperdir_path, workdir_path) = if config.read_only {
+        (None, None)
+    } else {
+        (upperdir_path, workdir_path)
+    };
+    let has_upperdir = upperdir_path.is_some() || (!config.read_only && config.upperdir.is_some());
 
     tracing::trace!("Opening overlayfs for composefs mount");
     // Open OverlayFS
@@ -479,7 +561,7 @@ pub fn composefs_fsmount(
         basedir_fd_ref.map(|fd| fd.as_raw_fd())
     );
 
-    overlayfs_set_lower_and_data_fds(overlayfs.as_fd(), erofs_mnt_fd, basedir_fd_ref)?;
+    overlayfs_set_lower_and_data_fds(overlayfs.as_fd(), &erofs_mnt_fds, basedir_fd_ref)?;
 
     // Set metacopy option if enabled in config
     if config.metacopy {
@@ -493,6 +575,12 @@ pub fn composefs_fsmount(
         fsconfig_set_string(overlayfs.as_fd(), "redirect_dir", "on")?;
     }
 
+    // Require fs-verity on all data-only objects if enabled in config
+    if config.verity_required {
+        tracing::debug!("Setting verity=require for overlayfs");
+        fsconfig_set_string(overlayfs.as_fd(), "verity", "require")?;
+    }
+
     tracing::debug!("Lower and data fds set for overlayfs");
     // Set upperdir if provided - use string paths instead of FDs for persistence
     if let Some(upath) = upperdir_path {
@@ -507,7 +595,7 @@ pub fn composefs_fsmount(
                 )
             })?,
         )?;
-    } else if let Some(upperdir_path) = config.upperdir.as_ref() {
+    } else if let Some(upperdir_path) = config.upperdir.as_ref().filter(|_| !config.read_only) {
         tracing::debug!("Setting upperdir from config to: {:?}", upperdir_path);
         fsconfig_set_string(
             overlayfs.as_fd(),
@@ -533,7 +621,7 @@ pub fn composefs_fsmount(
                 )
             })?,
         )?;
-    } else if let Some(workdir_path) = config.workdir.as_ref() {
+    } else if let Some(workdir_path) = config.workdir.as_ref().filter(|_| !config.read_only) {
         tracing::debug!("Setting workdir from config to: {:?}", workdir_path);
         fsconfig_set_string(
             overlayfs.as_fd(),
@@ -545,7 +633,7 @@ pub fn composefs_fsmount(
                 )
             })?,
         )?;
-    } else if upperdir_path.is_some() || config.upperdir.is_some() {
+    } else if has_upperdir {
         tracing::debug!(
             "No workdir provided, but upperdir is specified. Auto-creating a temporary workdir."
         );
@@ -572,6 +660,11 @@ pub fn composefs_fsmount(
         tracing::debug!("No workdir or upperdir specified, skipping workdir setup.");
     }
 
+    if config.volatile && has_upperdir {
+        tracing::debug!("Setting volatile for overlayfs");
+        rustix::mount::fsconfig_set_flag(overlayfs.as_fd(), "volatile")?;
+    }
+
     tracing::debug!("Finalizing overlayfs configuration with fsconfig_create.");
     fsconfig_create(overlayfs.as_fd()).inspect_err(|e| {
         tracing::error!(
@@ -589,7 +682,18 @@ pub fn composefs_fsmount(
         FsMountFlags::FSMOUNT_CLOEXEC // No target, so CLOEXEC on the returned fd
     };
 
-    let final_mnt_fd = fsmount(overlayfs.as_fd(), mount_flags, MountAttrFlags::empty())?;
+    let mount_attrs = if config.read_only {
+        MountAttrFlags::MOUNT_ATTR_RDONLY
+    } else {
+        MountAttrFlags::empty()
+    };
+    let final_mnt_fd = fsmount(overlayfs.as_fd(), mount_flags, mount_attrs)?;
+
+    if let Some(idmap) = config.idmap {
+        tracing::debug!("Applying ID mapping {} to overlay mount", idmap);
+        let userns = idmap.userns_fd()?;
+        super::idmap::set_mount_idmap(final_mnt_fd.as_fd(), userns.as_fd())?;
+    }
 
     if let Some(tp) = target_path {
         let canon_target_path = canonicalize(tp).unwrap_or_else(|_| tp.to_path_buf());
@@ -606,19 +710,21 @@ pub fn composefs_fsmount(
             MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
         )?;
 
-        // We're not really supposed to drop erofs_mnt here,
-        // since we need to keep the EROFS layer alive for the overlay.
+        // We're not really supposed to drop erofs_mnts here,
+        // since we need to keep the EROFS layers alive for the overlay.
         // However, we're dropping it anyway so it gets cleaned up, it should work fine though
 
         // If mount fails, consider uncommenting this
-        // tracing::debug!("Forgetting erofs_mnt to keep EROFS layer alive for overlay");
-        // std::mem::forget(erofs_mnt);
+        // tracing::debug!("Forgetting erofs_mnts to keep EROFS layers alive for overlay");
+        // std::mem::forget(erofs_mnts);
 
         Ok(FsHandle::Path(canon_target_path))
     } else {
-        // Note: Keeping the erofs_mnt alive to ensure proper cleanup.
-        // Returning FsHandle::Fd while holding onto erofs_mnt to avoid issues.
-        tracing::debug!("Returning FsHandle::Fd while keeping erofs_mnt alive for proper cleanup.");
+        // Note: Keeping the erofs_mnts alive to ensure proper cleanup.
+        // Returning FsHandle::Fd while holding onto erofs_mnts to avoid issues.
+        tracing::debug!(
+            "Returning FsHandle::Fd while keeping erofs_mnts alive for proper cleanup."
+        );
         Ok(FsHandle::Fd(final_mnt_fd))
     }
 }
@@ -652,6 +758,51 @@ pub fn mount_composefs_persistent_at(config: &ComposeFsConfig, mountpoint: &Path
     Ok(())
 }
 
+/// Atomically replace the composefs mount at `mountpoint` with a new one
+///
+/// The new mount is built detached with `fsmount`, then attached *beneath* the
+/// existing mount with `MOVE_MOUNT_BENEATH` (Linux 6.5+), and finally the old mount
+/// is lazily detached, revealing the new one. Processes that still hold references
+/// into the old mount (open files, cwd) keep using it until they let go.
+pub fn swap_composefs_at(config: &ComposeFsConfig, mountpoint: &Path) -> Result<()> {
+    let new_mount = composefs_fsmount(
+        config,
+        None,
+        config.basedir.as_deref(),
+        config.upperdir.as_deref(),
+        config.workdir.as_deref(),
+    )?;
+
+    let canon_mountpoint = canonicalize(mountpoint)?;
+    tracing::debug!(
+        "Attaching new composefs mount beneath {}",
+        canon_mountpoint.display()
+    );
+    move_mount(
+        new_mount.as_fd(),
+        "",
+        CWD,
+        &canon_mountpoint,
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH | MoveMountFlags::MOVE_MOUNT_BENEATH,
+    )
+    .inspect_err(|e| {
+        tracing::error!(
+            "Failed to attach new mount beneath {} (requires Linux 6.5+): {}",
+            canon_mountpoint.display(),
+            e
+        );
+    })?;
+
+    tracing::debug!(
+        "Lazily detaching old composefs mount at {}",
+        canon_mountpoint.display()
+    );
+    unmount(&canon_mountpoint, UnmountFlags::DETACH)?;
+
+    tracing::info!("Swapped composefs mount at {}", canon_mountpoint.display());
+    Ok(())
+}
+
 /// Unmount a composefs mount at the specified path
 /// This can be used to clean up persistent mounts
 pub fn unmount_composefs_at(mountpoint: &Path) -> Result<()> {
@@ -701,8 +852,16 @@ impl ComposeFsMount {
         // We need to ensure that if basedir is a path, it's handled correctly by composefs_fsmount.
         // composefs_fsmount now handles opening basedir path to an fd.
 
+        let lower_images = self
+            .config
+            .lower_images
+            .iter()
+            .map(rustix::io::dup)
+            .collect::<rustix::io::Result<Vec<_>>>()?;
+
         let mount_config = ComposeFsConfig {
             image_fd: duplicated_fd, // This is now the owned FD for the mount operation
+            lower_images,
             name: self.config.name.clone(),
             source_name: self.config.source_name.clone(),
             basedir: self.config.basedir.clone(), // Pass the path, composefs_fsmount will open it
@@ -711,6 +870,9 @@ impl ComposeFsMount {
             verity_required: self.config.verity_required,
             metacopy: self.config.metacopy,
             redirect_dir: self.config.redirect_dir,
+            volatile: self.config.volatile,
+            read_only: self.config.read_only,
+            idmap: self.config.idmap,
         };
 
         let fs_handle = mount_composefs_at(&mount_config, &self.mountpoint)?;
@@ -763,7 +925,9 @@ mod tests {
 
         let config = ComposeFsConfig::read_only(image_fd, "test".to_string());
         assert!(config.upperdir.is_none());
-        assert!(config.verity_required);
+        // verity is opt-in, since it only works once objects have fs-verity enabled
+        assert!(!config.verity_required);
+        assert!(config.with_verity(true).verity_required);
     }
 
     #[test]
@@ -779,6 +943,6 @@ mod tests {
             None,
         );
         assert!(config.upperdir.is_some());
-        assert!(config.verity_required);
+        assert!(!config.verity_required);
     }
 }
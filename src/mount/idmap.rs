@@ -0,0 +1,174 @@
+//! ID-mapped mount support
+//!
+//! An idmapped mount shows files owned by one range of IDs on disk as owned by
+//! another range, without touching the files themselves. This lets a store owned
+//! by one user be mounted for another. The kernel takes the mapping from a user
+//! namespace, so we create a throwaway one with the requested mapping.
+
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Error, Result},
+    os::{
+        fd::{AsRawFd, BorrowedFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+/// A contiguous ID mapping, applied to both UIDs and GIDs
+///
+/// Written as `<from>:<to>:<count>`: IDs `from..from+count` on disk show up as
+/// `to..to+count` through the mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct IdMap {
+    pub from: u32,
+    pub to: u32,
+    pub count: u32,
+}
+
+impl FromStr for IdMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts = s
+            .split(':')
+            .map(|p| {
+                p.parse::<u32>()
+                    .map_err(|e| format!("Invalid ID mapping '{}': {}", s, e))
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        match parts[..] {
+            [from, to, count] if count > 0 => Ok(IdMap { from, to, count }),
+            [_, _, _] => Err(format!("Invalid ID mapping '{}': count must be > 0", s)),
+            _ => Err(format!(
+                "Invalid ID mapping '{}': expected <from>:<to>:<count>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for IdMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.from, self.to, self.count)
+    }
+}
+
+impl IdMap {
+    /// The line to write to the namespace's `uid_map` and `gid_map`
+    ///
+    /// Lines are `<inside> <outside> <count>`. The on-disk ID is the outside one,
+    /// and the ID shown through the mount the inside one, the same order
+    /// util-linux's `X-mount.idmap` uses.
+    fn map_line(&self) -> String {
+        format!("{} {} {}\n", self.to, self.from, self.count)
+    }
+
+    /// Create a user namespace with this mapping and return an fd to it
+    ///
+    /// The namespace is kept alive by the returned fd; the helper process that
+    /// created it is killed right away.
+    pub fn userns_fd(&self) -> Result<OwnedFd> {
+        let mut cmd = Command::new("sleep");
+        cmd.arg("infinity")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        // SAFETY: unshare(2) is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                if libc::unshare(libc::CLONE_NEWUSER) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().inspect_err(|e| {
+            tracing::error!("Failed to spawn user namespace helper: {}", e);
+        })?;
+
+        let result = (|| {
+            let proc_dir = format!("/proc/{}", child.id());
+            let mapping = self.map_line();
+            std::fs::write(format!("{}/uid_map", proc_dir), &mapping)?;
+            std::fs::write(format!("{}/gid_map", proc_dir), &mapping)?;
+            Ok(std::fs::File::open(format!("{}/ns/user", proc_dir))?.into())
+        })();
+
+        let _ = child.kill();
+        let _ = child.wait();
+        result
+    }
+}
+
+/// Mirror of the kernel's `struct mount_attr`
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+/// Apply an ID mapping to a detached mount (e.g. one returned by `fsmount`)
+pub fn set_mount_idmap(mnt_fd: BorrowedFd<'_>, userns_fd: BorrowedFd<'_>) -> Result<()> {
+    let attr = MountAttr {
+        attr_set: rustix::mount::MountAttrFlags::MOUNT_ATTR_IDMAP.bits() as u64,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: userns_fd.as_raw_fd() as u64,
+    };
+    // SAFETY: `attr` is a valid mount_attr and outlives the call; rustix doesn't
+    // wrap mount_setattr(2) yet.
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            mnt_fd.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &attr as *const MountAttr,
+            std::mem::size_of::<MountAttr>(),
+        )
+    };
+    if ret != 0 {
+        let err = Error::last_os_error();
+        return Err(Error::other(format!(
+            "mount_setattr(MOUNT_ATTR_IDMAP) failed: {}",
+            err
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idmap_parse() {
+        let map: IdMap = "1000:2000:65536".parse().unwrap();
+        assert_eq!(
+            map,
+            IdMap {
+                from: 1000,
+                to: 2000,
+                count: 65536
+            }
+        );
+        assert_eq!(map.to_string(), "1000:2000:65536");
+
+        assert!("1000:2000".parse::<IdMap>().is_err());
+        assert!("1000:2000:0".parse::<IdMap>().is_err());
+        assert!("a:b:c".parse::<IdMap>().is_err());
+    }
+
+    #[test]
+    fn test_idmap_map_line() {
+        let map: IdMap = "1000:2000:65536".parse().unwrap();
+        assert_eq!(map.map_line(), "2000 1000 65536\n");
+    }
+}
@@ -1,5 +1,8 @@
 //! Mount helpers for managing mountpoints
 pub mod composefs;
+pub mod idmap;
+pub mod quota;
+use bincode::{Decode, Encode};
 use nix::mount::{MntFlags, umount2};
 use rustix::{
     fs::CWD,
@@ -8,6 +11,7 @@ use rustix::{
         fsconfig_set_string, fsmount, fsopen, move_mount,
     },
 };
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
     io::Result,
@@ -15,6 +19,21 @@ use std::{
     path::{Path, PathBuf},
 };
 
+/// Optional behaviour when mounting a stratum
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct MountOptions {
+    /// Keep the upperdir on a private tmpfs, discarded on unmount
+    pub tmpfs: bool,
+    /// Use overlay's `volatile` option, skipping syncs to the upperdir
+    ///
+    /// Faster, but the upperdir may be left inconsistent after a crash.
+    pub volatile: bool,
+    /// Mount without write access, even if there is an upperdir
+    pub read_only: bool,
+    /// Show on-disk UIDs/GIDs through this mapping
+    pub idmap: Option<idmap::IdMap>,
+}
+
 #[derive(Debug)]
 pub enum FsHandle {
     Fd(OwnedFd),
@@ -199,6 +218,79 @@ impl Drop for TempOvlMount {
     }
 }
 
+/// Find paths under `root` that are currently held open by any process
+///
+/// This walks `/proc/<pid>/fd/*` and `/proc/<pid>/cwd`, so it only sees processes
+/// we have permission to inspect. Working directories are returned as-is, open files
+/// are returned as the file path.
+pub fn open_paths_under(root: &Path) -> Result<HashSet<PathBuf>> {
+    let mut open_paths = HashSet::new();
+
+    for proc_entry in std::fs::read_dir("/proc")? {
+        let Ok(proc_entry) = proc_entry else {
+            continue;
+        };
+        let pid_dir = proc_entry.path();
+        if !proc_entry
+            .file_name()
+            .to_string_lossy()
+            .chars()
+            .all(|c| c.is_ascii_digit())
+        {
+            continue;
+        }
+
+        // Processes may exit while we're scanning, so ignore errors here
+        let mut links = vec![pid_dir.join("cwd")];
+        if let Ok(fds) = std::fs::read_dir(pid_dir.join("fd")) {
+            links.extend(fds.filter_map(|fd| fd.ok()).map(|fd| fd.path()));
+        }
+
+        for link in links {
+            if let Ok(target) = std::fs::read_link(&link) {
+                if target.starts_with(root) {
+                    open_paths.insert(target);
+                }
+            }
+        }
+    }
+
+    Ok(open_paths)
+}
+
+/// Mount a fresh tmpfs at the given path, holding at most `size` bytes if given
+pub fn mount_tmpfs_at(mountpoint: &Path, size: Option<u64>) -> Result<()> {
+    let tmpfs = FsHandle::new_from_fs_name("tmpfs")?;
+    fsconfig_set_string(tmpfs.as_fd(), "source", "stratum-tmpfs")?;
+    fsconfig_set_string(tmpfs.as_fd(), "mode", "0755")?;
+    if let Some(size) = size {
+        fsconfig_set_string(tmpfs.as_fd(), "size", size.to_string())?;
+    }
+    fsconfig_create(tmpfs.as_fd())?;
+    let mnt = fsmount(
+        tmpfs.as_fd(),
+        FsMountFlags::FSMOUNT_CLOEXEC,
+        MountAttrFlags::empty(),
+    )?;
+    mount_at(mnt, CWD, mountpoint)
+}
+
+/// Make a mount read-only in place, without unmounting it
+///
+/// Fails with `EBUSY` while files on the mount are open for writing.
+pub fn remount_read_only(mountpoint: &Path) -> Result<()> {
+    nix::mount::mount(
+        None::<&str>,
+        mountpoint,
+        None::<&str>,
+        nix::mount::MsFlags::MS_REMOUNT
+            | nix::mount::MsFlags::MS_BIND
+            | nix::mount::MsFlags::MS_RDONLY,
+        None::<&str>,
+    )?;
+    Ok(())
+}
+
 #[tracing::instrument(level = "trace", name = "fsync_dir")]
 pub fn fsync_dir(path: &Path) -> Result<()> {
     tracing::trace!("running fsync");
@@ -0,0 +1,122 @@
+//! Progress, cancellation and rollback for patchset builds
+//!
+//! Builds report what they're doing through [`BuildEvent`]s. They can be interrupted
+//! with Ctrl-C once [`handle_interrupts`] is installed: the build stops at the next
+//! step boundary, and the step in progress is rolled back by a [`StepGuard`], so no
+//! transient refs or unused commits are left behind. A commit that fails halfway
+//! through being written is left for `patchset clean`. Pressing Ctrl-C again kills
+//! the process right away.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::store::Store;
+
+/// What a patch being applied is currently doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildPhase {
+    /// Copying the patch's files into a staging directory
+    Staging,
+    /// Done staging, with the total size of the staged files
+    Staged { bytes: u64 },
+    /// Creating the union commit of the patch and the previous layers
+    Committing,
+    /// The patch is applied
+    Done,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildEvent<'a> {
+    /// Layers are resolved, and the bottom `cached` of `total` patches are reused
+    Started { total: usize, cached: usize },
+    /// The patch at `index` (starting from 1) moved to a new phase
+    Patch {
+        index: usize,
+        total: usize,
+        name: &'a str,
+        phase: BuildPhase,
+    },
+    /// The build produced `commit`
+    Finished { commit: &'a str },
+}
+
+pub use crate::interrupt::handle_interrupts;
+
+/// Fail if the build was interrupted
+pub fn check_interrupted() -> Result<(), String> {
+    if crate::interrupt::interrupted() {
+        return Err("Build interrupted".to_string());
+    }
+    Ok(())
+}
+
+/// Rolls back a single build step unless it's [`Self::commit`]ted
+///
+/// Removes the step's transient ref, and the commits recorded with [`Self::created`]
+/// that didn't exist before the step started. Commits that a tag, mount or worktree
+/// refers to by then are kept, since another command is using them.
+pub struct StepGuard<'s> {
+    store: &'s Store,
+    commits_before: HashSet<String>,
+    created: Vec<String>,
+    transient_label: Option<String>,
+    succeeded: bool,
+}
+
+impl<'s> StepGuard<'s> {
+    pub fn new(store: &'s Store, transient_label: Option<String>) -> Result<Self, String> {
+        Ok(Self {
+            store,
+            commits_before: store.list_commits()?.into_iter().collect(),
+            created: Vec::new(),
+            transient_label,
+            succeeded: false,
+        })
+    }
+
+    /// Record a commit the step produced, to be rolled back if the step fails
+    pub fn created(&mut self, commit_id: &str) {
+        if !self.commits_before.contains(commit_id) {
+            self.created.push(commit_id.to_string());
+        }
+    }
+
+    /// Keep the commits created during the step
+    pub fn commit(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for StepGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(label) = &self.transient_label {
+            let path = Path::new(self.store.base_path()).join("refs").join(label);
+            if path.exists() {
+                if let Err(e) = std::fs::remove_dir_all(&path) {
+                    tracing::warn!("Failed to remove transient ref '{}': {}", label, e);
+                }
+            }
+        }
+        if self.succeeded || self.created.is_empty() {
+            return;
+        }
+
+        let referenced = match self.store.referenced_commits() {
+            Ok(referenced) => referenced,
+            Err(e) => {
+                tracing::warn!("Failed to find referenced commits, not rolling back: {}", e);
+                return;
+            }
+        };
+        for commit in &self.created {
+            if referenced.contains(commit) {
+                tracing::info!("Keeping commit {}, it's in use", commit);
+                continue;
+            }
+            tracing::info!("Rolling back commit {}", commit);
+            if let Err(e) = self.store.discard_commit(commit) {
+                tracing::warn!("Failed to roll back commit {}: {}", commit, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Cache of intermediate patchset results
+//!
+//! Building a patchset applies its patches one at a time, each producing a new
+//! commit. Every intermediate commit is cached under a key derived from the base
+//! commit and the ordered layer keys (see [`super::PatchEntry::layer_key`]) of the
+//! patches applied so far, so a rebuild can resume from the longest prefix that
+//! hasn't changed.
+//!
+//! Entries live in `cache/patchsets/<key>.toml` inside the store. The cached commits
+//! are regular commits, so they're only removed by [`PatchsetCache::gc`].
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::store::Store;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CacheEntry {
+    /// The commit produced by applying the patches
+    pub commit: String,
+    /// When this entry was last created or reused
+    pub last_used: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct PatchsetCache<'s> {
+    store: &'s Store,
+    dir: PathBuf,
+}
+
+impl<'s> PatchsetCache<'s> {
+    const CACHE_DIR: &'static str = "cache/patchsets";
+
+    pub fn new(store: &'s Store) -> Self {
+        Self {
+            store,
+            dir: Path::new(store.base_path()).join(Self::CACHE_DIR),
+        }
+    }
+
+    /// Derive the cache key for a base commit with the given patches applied, in order
+    pub fn key(base_commit: &str, patch_commits: &[String]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(base_commit.as_bytes());
+        for patch in patch_commits {
+            hasher.update(b"\n");
+            hasher.update(patch.as_bytes());
+        }
+        hex::encode(hasher.finalize())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.toml", key))
+    }
+
+    fn load_entry(path: &Path) -> Result<CacheEntry, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read cache entry {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse cache entry {}: {}", path.display(), e))
+    }
+
+    /// Write an entry to a temporary file, then rename it into place, so concurrent
+    /// builds and crashes never leave a half-written entry behind
+    fn save_entry(&self, key: &str, entry: &CacheEntry) -> Result<(), String> {
+        std::fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("Failed to create cache directory: {}", e))?;
+        let content = toml::to_string(entry).map_err(|e| e.to_string())?;
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".entry_")
+            .tempfile_in(&self.dir)
+            .map_err(|e| format!("Failed to create temporary cache entry: {}", e))?;
+        tmp.write_all(content.as_bytes())
+            .map_err(|e| format!("Failed to write cache entry {}: {}", key, e))?;
+        tmp.persist(self.entry_path(key))
+            .map_err(|e| format!("Failed to replace cache entry {}: {}", key, e))?;
+        Ok(())
+    }
+
+    /// Paths of every entry, leaving out temporary files of entries being written
+    fn entry_paths(&self) -> Result<Vec<PathBuf>, String> {
+        let mut paths = Vec::new();
+        for dirent in std::fs::read_dir(&self.dir).map_err(|e| e.to_string())? {
+            let path = dirent.map_err(|e| e.to_string())?.path();
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Look up the commit for a base commit with the given patches applied
+    ///
+    /// Entries whose commit has since been deleted are ignored.
+    pub fn get(&self, base_commit: &str, patch_commits: &[String]) -> Option<String> {
+        self.lookup(base_commit, patch_commits, true)
+    }
+
+    fn lookup(&self, base_commit: &str, patch_commits: &[String], touch: bool) -> Option<String> {
+        let key = Self::key(base_commit, patch_commits);
+        let path = self.entry_path(&key);
+        if !path.exists() {
+            return None;
+        }
+
+        let mut entry = Self::load_entry(&path)
+            .inspect_err(|e| tracing::warn!("Ignoring cache entry: {}", e))
+            .ok()?;
+        if self.store.load_commit(&entry.commit).is_err() {
+            tracing::debug!("Cached commit {} no longer exists", entry.commit);
+            return None;
+        }
+
+        if touch {
+            entry.last_used = chrono::Utc::now();
+            if let Err(e) = self.save_entry(&key, &entry) {
+                tracing::warn!("Failed to update cache entry {}: {}", key, e);
+            }
+        }
+        Some(entry.commit)
+    }
+
+    /// Record the commit for a base commit with the given patches applied
+    pub fn insert(
+        &self,
+        base_commit: &str,
+        patch_commits: &[String],
+        commit: &str,
+    ) -> Result<(), String> {
+        let key = Self::key(base_commit, patch_commits);
+        self.save_entry(
+            &key,
+            &CacheEntry {
+                commit: commit.to_string(),
+                last_used: chrono::Utc::now(),
+            },
+        )
+    }
+
+    /// Find the longest prefix of `patch_commits` with a cached result
+    ///
+    /// Returns how many patches that prefix covers, along with its commit.
+    pub fn longest_prefix(
+        &self,
+        base_commit: &str,
+        patch_commits: &[String],
+    ) -> Option<(usize, String)> {
+        (1..=patch_commits.len()).rev().find_map(|len| {
+            self.get(base_commit, &patch_commits[..len])
+                .map(|commit| (len, commit))
+        })
+    }
+
+    /// Like [`Self::longest_prefix`], but without marking the entry as used
+    pub fn peek_longest_prefix(
+        &self,
+        base_commit: &str,
+        patch_commits: &[String],
+    ) -> Option<(usize, String)> {
+        (1..=patch_commits.len()).rev().find_map(|len| {
+            self.lookup(base_commit, &patch_commits[..len], false)
+                .map(|commit| (len, commit))
+        })
+    }
+
+    /// Evict entries not used within `max_age`, deleting their commits unless
+    /// something else still references them
+    ///
+    /// Returns the number of evicted entries.
+    pub fn gc(&self, max_age: chrono::Duration) -> Result<usize, String> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+
+        let cutoff = chrono::Utc::now() - max_age;
+        let mut entries = Vec::new();
+        for path in self.entry_paths()? {
+            match Self::load_entry(&path) {
+                Ok(entry) => entries.push((path, entry)),
+                Err(e) => {
+                    tracing::warn!("Removing unreadable cache entry: {}", e);
+                    std::fs::remove_file(&path).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+
+        let (expired, kept): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|(_, entry)| entry.last_used < cutoff);
+
+        let referenced = if expired.is_empty() {
+            HashSet::new()
+        } else {
+            self.store.referenced_commits()?
+        };
+        for (path, entry) in &expired {
+            std::fs::remove_file(path)
+                .map_err(|e| format!("Failed to remove cache entry {}: {}", path.display(), e))?;
+
+            // The same commit may be cached under several keys, or be a tagged build
+            let still_cached = kept.iter().any(|(_, e)| e.commit == entry.commit);
+            if still_cached || referenced.contains(&entry.commit) {
+                continue;
+            }
+            if self.store.load_commit(&entry.commit).is_ok() {
+                tracing::debug!("Deleting cached commit {}", entry.commit);
+                self.store.delete_commit(&entry.commit)?;
+            }
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Remove entries whose commit is missing or was never fully written
+    ///
+    /// Returns the number of removed entries.
+    pub fn prune_missing(&self) -> Result<usize, String> {
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+
+        let mut removed = 0;
+        for path in self.entry_paths()? {
+            let dangling = match Self::load_entry(&path) {
+                Ok(entry) => !self.store.is_commit_complete(&entry.commit),
+                Err(e) => {
+                    tracing::warn!("Removing unreadable cache entry: {}", e);
+                    true
+                }
+            };
+            if dangling {
+                std::fs::remove_file(&path).map_err(|e| {
+                    format!("Failed to remove cache entry {}: {}", path.display(), e)
+                })?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key_depends_on_order() {
+        let a = "a".repeat(64);
+        let b = "b".repeat(64);
+        let base = "0".repeat(64);
+
+        assert_eq!(
+            PatchsetCache::key(&base, &[a.clone(), b.clone()]),
+            PatchsetCache::key(&base, &[a.clone(), b.clone()])
+        );
+        assert_ne!(
+            PatchsetCache::key(&base, &[a.clone(), b.clone()]),
+            PatchsetCache::key(&base, &[b.clone(), a.clone()])
+        );
+        assert_ne!(
+            PatchsetCache::key(&base, &[a.clone()]),
+            PatchsetCache::key(&a, &[])
+        );
+    }
+}
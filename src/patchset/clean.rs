@@ -0,0 +1,151 @@
+//! Cleaning up after interrupted patchset builds
+//!
+//! Builds roll back failed steps themselves, but a build that's killed outright (or
+//! a machine that loses power) leaves transient refs, half-written commits, staging
+//! directories and possibly mounts behind. [`clean`] removes all of them.
+//!
+//! Everything a running command is working on looks like a leftover, so [`clean`]
+//! takes the store lock exclusively and refuses to run while any other stratum
+//! command holds it (see [`crate::store::lock`]).
+
+use std::fmt;
+use std::path::Path;
+
+use nix::mount::{MntFlags, umount2};
+
+use super::cache::PatchsetCache;
+use crate::store::Store;
+
+/// What [`clean`] removed
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CleanReport {
+    pub transient_refs: Vec<String>,
+    pub incomplete_commits: Vec<String>,
+    pub temp_dirs: Vec<String>,
+    pub cache_entries: usize,
+}
+
+impl CleanReport {
+    pub fn is_empty(&self) -> bool {
+        self.transient_refs.is_empty()
+            && self.incomplete_commits.is_empty()
+            && self.temp_dirs.is_empty()
+            && self.cache_entries == 0
+    }
+}
+
+/// Remove leftovers of interrupted builds from the store
+pub fn clean(store: &Store) -> Result<CleanReport, String> {
+    let _lock = store.try_lock_exclusive()?;
+    let mut report = CleanReport::default();
+
+    // Staging directories may still have a patch mounted on them
+    let base = Path::new(store.base_path());
+    for (dir, prefix) in [
+        (base.to_path_buf(), "stratum_staging_"),
+        (Path::new(&store.temp_path()).to_path_buf(), "stratum_temp_"),
+    ] {
+        for path in list_prefixed(&dir, prefix)? {
+            if umount2(&path, MntFlags::MNT_DETACH).is_ok() {
+                tracing::debug!("Unmounted stale mount at {}", path.display());
+            }
+            std::fs::remove_dir_all(&path)
+                .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+            report.temp_dirs.push(path.display().to_string());
+        }
+    }
+
+    for path in list_prefixed(&base.join("refs"), "transient_")? {
+        std::fs::remove_dir_all(&path)
+            .map_err(|e| format!("Failed to remove transient ref {}: {}", path.display(), e))?;
+        report
+            .transient_refs
+            .push(path.file_name().unwrap().to_string_lossy().to_string());
+    }
+
+    for commit in store.list_commits()? {
+        if !store.is_commit_complete(&commit) {
+            store.discard_commit(&commit)?;
+            report.incomplete_commits.push(commit);
+        }
+    }
+
+    report.cache_entries = PatchsetCache::new(store).prune_missing()?;
+
+    Ok(report)
+}
+
+/// List the entries of `dir` whose name starts with `prefix`
+fn list_prefixed(dir: &Path, prefix: &str) -> Result<Vec<std::path::PathBuf>, String> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut paths = Vec::new();
+    for dirent in
+        std::fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+    {
+        let dirent = dirent.map_err(|e| e.to_string())?;
+        if dirent.file_name().to_string_lossy().starts_with(prefix) {
+            paths.push(dirent.path());
+        }
+    }
+    Ok(paths)
+}
+
+impl fmt::Display for CleanReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "Nothing to clean up");
+        }
+        for label in &self.transient_refs {
+            writeln!(f, "Removed transient ref {}", label)?;
+        }
+        for commit in &self.incomplete_commits {
+            writeln!(f, "Removed incomplete commit {}", commit)?;
+        }
+        for dir in &self.temp_dirs {
+            writeln!(f, "Removed temporary directory {}", dir)?;
+        }
+        if self.cache_entries > 0 {
+            writeln!(f, "Removed {} dangling cache entries", self.cache_entries)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateManager;
+
+    #[test]
+    fn test_clean_leftovers() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store_dir = temp_dir.path().join("store");
+        let store = Store::new_with_state(
+            store_dir.to_string_lossy().to_string(),
+            StateManager::new_in(&temp_dir.path().join("run")).unwrap(),
+        );
+        let base = store_dir.as_path();
+
+        std::fs::create_dir_all(base.join("refs/transient_01ABC/latest")).unwrap();
+        std::fs::create_dir_all(base.join("refs/myapp")).unwrap();
+        std::fs::create_dir_all(base.join("stratum_staging_x1")).unwrap();
+        std::fs::create_dir_all(base.join("commits/deadbeef")).unwrap();
+        std::fs::write(base.join("commits/deadbeef/commit.cfs"), b"partial").unwrap();
+
+        let report = clean(&store).unwrap();
+        assert_eq!(report.transient_refs, vec!["transient_01ABC"]);
+        assert_eq!(report.incomplete_commits, vec!["deadbeef"]);
+        assert_eq!(report.temp_dirs.len(), 1);
+        assert!(base.join("refs/myapp").exists());
+        assert!(!base.join("commits/deadbeef").exists());
+
+        assert!(clean(&store).unwrap().is_empty());
+
+        let _lock = store.lock_shared().unwrap();
+        std::fs::create_dir_all(base.join("stratum_staging_x2")).unwrap();
+        assert!(clean(&store).is_err());
+        assert!(base.join("stratum_staging_x2").exists());
+    }
+}
@@ -0,0 +1,403 @@
+//! Conflict reports for patchsets
+//!
+//! Patches are layered last-write-wins, so when two patches provide the same path,
+//! the later one silently replaces the earlier one. This module works out which
+//! patch wins each overlapping path, which patches end up with nothing visible
+//! ("dead" patches), and which whiteouts delete files introduced by earlier patches.
+//!
+//! The report is computed from the patches' EROFS images directly, without mounting.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::composefs::erofs::OverlayEntryKind;
+use crate::util::{OPAQUE_MARKER, WHITEOUT_PREFIX};
+
+/// A path provided by more than one patch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PathConflict {
+    pub path: String,
+    /// Patches providing the path, in the order they are applied
+    pub providers: Vec<String>,
+    /// The patch whose version is visible in the result, if it wasn't deleted later
+    pub winner: Option<String>,
+}
+
+/// A whiteout, opaque directory or file replacing a directory, hiding files
+/// introduced by earlier patches
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WhiteoutConflict {
+    pub path: String,
+    /// The patch containing the whiteout
+    pub deleted_by: String,
+    /// Deleted paths, along with the patch that introduced them
+    pub deleted: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConflictReport {
+    pub overlaps: Vec<PathConflict>,
+    /// Patches whose files are all overridden or deleted by later patches
+    pub dead_patches: Vec<String>,
+    pub whiteouts: Vec<WhiteoutConflict>,
+}
+
+impl ConflictReport {
+    /// Build a report from each patch's entries, in the order the patches are applied
+    pub fn from_layers(layers: &[(String, Vec<(String, OverlayEntryKind)>)]) -> Self {
+        // path -> index of the layer whose version is currently visible
+        let mut visible: BTreeMap<String, usize> = BTreeMap::new();
+        // path -> every layer that provided it
+        let mut providers: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+        let mut whiteouts = Vec::new();
+
+        for (idx, (name, entries)) in layers.iter().enumerate() {
+            for (path, kind) in entries {
+                let (path, kind) = bare_whiteout(path, *kind);
+                let path = &path;
+                let hidden = match kind {
+                    // A directory replacing a file hides it
+                    OverlayEntryKind::Directory => Self::take_exact(&mut visible, path, idx),
+                    // A file replacing a directory hides everything that was beneath it
+                    OverlayEntryKind::File => {
+                        providers.entry(path.clone()).or_default().push(idx);
+                        let hidden = Self::take_under(&mut visible, path, false, idx);
+                        visible.insert(path.clone(), idx);
+                        hidden
+                    }
+                    OverlayEntryKind::Whiteout => Self::take_under(&mut visible, path, true, idx),
+                    OverlayEntryKind::OpaqueDirectory => {
+                        Self::take_under(&mut visible, path, false, idx)
+                    }
+                };
+
+                let deleted = hidden
+                    .into_iter()
+                    .map(|(path, layer)| (path, layers[layer].0.clone()))
+                    .collect::<Vec<_>>();
+                if !deleted.is_empty() {
+                    whiteouts.push(WhiteoutConflict {
+                        path: path.clone(),
+                        deleted_by: name.clone(),
+                        deleted,
+                    });
+                }
+            }
+        }
+
+        let overlaps = providers
+            .iter()
+            .filter(|(_, layers)| layers.len() > 1)
+            .map(|(path, idxs)| PathConflict {
+                path: path.clone(),
+                providers: idxs.iter().map(|i| layers[*i].0.clone()).collect(),
+                winner: visible.get(path).map(|i| layers[*i].0.clone()),
+            })
+            .collect();
+
+        let mut provided = vec![false; layers.len()];
+        let mut survives = vec![false; layers.len()];
+        for idxs in providers.values() {
+            for i in idxs {
+                provided[*i] = true;
+            }
+        }
+        for i in visible.values() {
+            survives[*i] = true;
+        }
+        let dead_patches = layers
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| provided[*i] && !survives[*i])
+            .map(|(_, (name, _))| name.clone())
+            .collect();
+
+        ConflictReport {
+            overlaps,
+            dead_patches,
+            whiteouts,
+        }
+    }
+
+    /// Remove what layers before `layer` provide beneath `path` (and at `path` itself
+    /// if `inclusive`) from `visible`
+    ///
+    /// Deleting things within the same patch isn't a conflict, so the layer's own
+    /// entries are left alone.
+    fn take_under(
+        visible: &mut BTreeMap<String, usize>,
+        path: &str,
+        inclusive: bool,
+        layer: usize,
+    ) -> Vec<(String, usize)> {
+        let mut hidden = if inclusive {
+            Self::take_exact(visible, path, layer)
+        } else {
+            Vec::new()
+        };
+        let prefix = format!("{}/", path);
+        let keys = visible
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .filter(|(_, l)| **l < layer)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        hidden.extend(
+            keys.into_iter()
+                .filter_map(|k| visible.remove(&k).map(|layer| (k, layer))),
+        );
+        hidden
+    }
+
+    /// Remove `path` from `visible` if a layer before `layer` provides it
+    fn take_exact(
+        visible: &mut BTreeMap<String, usize>,
+        path: &str,
+        layer: usize,
+    ) -> Vec<(String, usize)> {
+        match visible.get(path) {
+            Some(l) if *l < layer => {
+                let l = *l;
+                visible.remove(path);
+                vec![(path.to_string(), l)]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.overlaps.is_empty() && self.dead_patches.is_empty() && self.whiteouts.is_empty()
+    }
+
+    /// Count how many paths each patch overrides from each earlier patch,
+    /// as `(winner, loser) -> count`
+    pub fn override_counts(&self) -> BTreeMap<(String, String), usize> {
+        let mut counts = BTreeMap::new();
+        for overlap in &self.overlaps {
+            let Some(winner) = &overlap.winner else {
+                continue;
+            };
+            let mut seen = HashSet::new();
+            for loser in overlap.providers.iter().filter(|p| *p != winner) {
+                // A patch may be listed several times if it's applied more than once
+                if seen.insert(loser) {
+                    *counts
+                        .entry((winner.clone(), loser.clone()))
+                        .or_insert(0usize) += 1;
+                }
+            }
+        }
+        counts
+    }
+}
+
+/// Treat bare-patch whiteouts like overlay ones
+///
+/// Bare patches carry deletions as `.wh.<name>` files and opaque directories as a
+/// `.wh..wh..opq` file inside them, which end up in their images as regular files.
+fn bare_whiteout(path: &str, kind: OverlayEntryKind) -> (String, OverlayEntryKind) {
+    if kind != OverlayEntryKind::File {
+        return (path.to_string(), kind);
+    }
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, path),
+    };
+    if name == OPAQUE_MARKER {
+        if let Some(parent) = parent {
+            return (parent.to_string(), OverlayEntryKind::OpaqueDirectory);
+        }
+    } else if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX)
+        && !name.is_empty()
+    {
+        let path = match parent {
+            Some(parent) => format!("{}/{}", parent, name),
+            None => name.to_string(),
+        };
+        return (path, OverlayEntryKind::Whiteout);
+    }
+    (path.to_string(), kind)
+}
+
+impl fmt::Display for ConflictReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No conflicts");
+        }
+
+        for ((winner, loser), count) in self.override_counts() {
+            writeln!(f, "{} overrides {} files from {}", winner, count, loser)?;
+        }
+        for dead in &self.dead_patches {
+            writeln!(f, "{} is fully overridden by later patches", dead)?;
+        }
+        for whiteout in &self.whiteouts {
+            writeln!(
+                f,
+                "{} deletes {} ({} paths from earlier patches)",
+                whiteout.deleted_by,
+                whiteout.path,
+                whiteout.deleted.len()
+            )?;
+        }
+
+        if !self.overlaps.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Overlapping paths:")?;
+            for overlap in &self.overlaps {
+                writeln!(
+                    f,
+                    "  /{}: {} -> {}",
+                    overlap.path,
+                    overlap.providers.join(", "),
+                    overlap.winner.as_deref().unwrap_or("(deleted)")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(
+        name: &str,
+        entries: &[(&str, OverlayEntryKind)],
+    ) -> (String, Vec<(String, OverlayEntryKind)>) {
+        (
+            name.to_string(),
+            entries
+                .iter()
+                .map(|(path, kind)| (path.to_string(), *kind))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_conflict_report() {
+        use OverlayEntryKind::*;
+        let layers = vec![
+            layer(
+                "modX",
+                &[("data", Directory), ("data/a", File), ("data/b", File)],
+            ),
+            layer(
+                "modY",
+                &[("data", Directory), ("data/a", File), ("readme", File)],
+            ),
+            layer(
+                "modZ",
+                &[("data", Directory), ("data/b", File), ("readme", Whiteout)],
+            ),
+            layer("modW", &[("data", OpaqueDirectory), ("data/c", File)]),
+        ];
+
+        let report = ConflictReport::from_layers(&layers);
+
+        assert_eq!(report.overlaps.len(), 2);
+        assert_eq!(report.overlaps[0].path, "data/a");
+        assert_eq!(report.overlaps[0].providers, vec!["modX", "modY"]);
+        // data/a is hidden by modW's opaque directory
+        assert_eq!(report.overlaps[0].winner, None);
+
+        // modZ deletes readme from modY, modW hides everything under data/
+        assert_eq!(report.whiteouts.len(), 2);
+        assert_eq!(report.whiteouts[0].deleted_by, "modZ");
+        assert_eq!(
+            report.whiteouts[0].deleted,
+            vec![("readme".to_string(), "modY".to_string())]
+        );
+        assert_eq!(report.whiteouts[1].deleted_by, "modW");
+        assert_eq!(report.whiteouts[1].deleted.len(), 2);
+
+        assert_eq!(report.dead_patches, vec!["modX", "modY", "modZ"]);
+    }
+
+    #[test]
+    fn test_replaced_directory() {
+        use OverlayEntryKind::*;
+        let layers = vec![
+            layer(
+                "modX",
+                &[("etc", Directory), ("etc/a", File), ("bin", File)],
+            ),
+            layer(
+                "modY",
+                &[("etc", File), ("bin", Directory), ("bin/sh", File)],
+            ),
+        ];
+
+        let report = ConflictReport::from_layers(&layers);
+        assert_eq!(report.whiteouts.len(), 2);
+        assert_eq!(report.whiteouts[0].path, "etc");
+        assert_eq!(
+            report.whiteouts[0].deleted,
+            vec![("etc/a".to_string(), "modX".to_string())]
+        );
+        assert_eq!(report.whiteouts[1].path, "bin");
+        assert_eq!(
+            report.whiteouts[1].deleted,
+            vec![("bin".to_string(), "modX".to_string())]
+        );
+        assert_eq!(report.dead_patches, vec!["modX"]);
+    }
+
+    #[test]
+    fn test_bare_whiteouts() {
+        use OverlayEntryKind::*;
+        let layers = vec![
+            layer(
+                "modX",
+                &[("readme", File), ("data", Directory), ("data/a", File)],
+            ),
+            layer(
+                "bare",
+                &[
+                    (".wh.readme", File),
+                    ("data", Directory),
+                    ("data/.wh..wh..opq", File),
+                    ("data/b", File),
+                ],
+            ),
+        ];
+
+        let report = ConflictReport::from_layers(&layers);
+        assert!(report.overlaps.is_empty());
+        assert_eq!(report.whiteouts.len(), 2);
+        assert_eq!(report.whiteouts[0].path, "readme");
+        assert_eq!(report.whiteouts[1].path, "data");
+        assert_eq!(
+            report.whiteouts[1].deleted,
+            vec![("data/a".to_string(), "modX".to_string())]
+        );
+        // The bare patch's own file survives its opaque marker
+        assert_eq!(report.dead_patches, vec!["modX"]);
+    }
+
+    #[test]
+    fn test_override_counts() {
+        use OverlayEntryKind::*;
+        let layers = vec![
+            layer("modY", &[("a", File), ("b", File), ("c", File)]),
+            layer("modZ", &[("a", File), ("b", File)]),
+        ];
+
+        let report = ConflictReport::from_layers(&layers);
+        assert!(report.dead_patches.is_empty());
+        assert_eq!(
+            report
+                .override_counts()
+                .get(&("modZ".to_string(), "modY".to_string())),
+            Some(&2)
+        );
+        assert!(
+            report
+                .to_string()
+                .contains("modZ overrides 2 files from modY")
+        );
+    }
+}
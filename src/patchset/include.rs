@@ -0,0 +1,283 @@
+//! Patchsets including other patchsets
+//!
+//! A patchset file can pull in the patches of other patchset files, either all at
+//! once before its own patches:
+//!
+//! ```toml
+//! [patchset]
+//! includes = ["../common.patchset.toml"]
+//! patches = ["myapp:profile-tweaks"]
+//! ```
+//!
+//! or at a specific position in its patch list:
+//!
+//! ```toml
+//! [patchset]
+//! patches = ["myapp:modloader", { patchset = "fixes.patchset.toml" }, "myapp:ui"]
+//! ```
+//!
+//! Paths are relative to the file that includes them. Includes are expanded
+//! recursively into a plain [`Patchset`], and patches included more than once through
+//! different files are only applied the first time. Including the same ref with
+//! different options is an error, since only one of them could be applied.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::{PatchEntry, PatchOptions, Patchset};
+
+/// A patchset file as written, before includes are expanded
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchsetFile {
+    pub patchset: PatchsetFileData,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchsetFileData {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_commit: Option<String>,
+    /// Patchset files whose patches are applied before this file's own
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<String>,
+    #[serde(default)]
+    pub patches: Vec<PatchsetFileEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PatchsetFileEntry {
+    Include(IncludeEntry),
+    Patch(PatchEntry),
+}
+
+/// A patch entry pulling in all patches of another patchset file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IncludeEntry {
+    pub patchset: String,
+    #[serde(default = "super::default_enabled")]
+    pub enabled: bool,
+}
+
+impl PatchsetFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read patchset file {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse patchset {}: {}", path.display(), e))
+    }
+}
+
+/// Load a patchset file, expanding its includes recursively
+pub fn load_expanded(path: &Path) -> Result<Patchset, String> {
+    let mut base_commit = None;
+    let mut patches = Vec::new();
+    expand(path, &mut Vec::new(), &mut base_commit, &mut patches)?;
+    Ok(Patchset::new_with_entries(base_commit, patches))
+}
+
+/// Append the patches of the patchset at `path` to `patches`
+///
+/// `stack` holds the files currently being expanded, to detect cycles. The first base
+/// commit found is kept, with the including file's own base taking precedence over
+/// those of the files it includes.
+fn expand(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    base_commit: &mut Option<String>,
+    patches: &mut Vec<PatchEntry>,
+) -> Result<(), String> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| format!("Failed to read patchset file {}: {}", path.display(), e))?;
+    if let Some(pos) = stack.iter().position(|p| *p == canonical) {
+        let cycle = stack[pos..]
+            .iter()
+            .chain(std::iter::once(&canonical))
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        return Err(format!("Patchset include cycle: {}", cycle.join(" -> ")));
+    }
+
+    let file = PatchsetFile::load(path)?;
+    let dir = canonical.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+    if base_commit.is_none() {
+        base_commit.clone_from(&file.patchset.base_commit);
+    } else if let Some(ignored) = &file.patchset.base_commit {
+        tracing::warn!(
+            "Ignoring base commit {} of included patchset {}",
+            ignored,
+            path.display()
+        );
+    }
+
+    stack.push(canonical);
+    for include in &file.patchset.includes {
+        expand(&dir.join(include), stack, base_commit, patches)?;
+    }
+    for entry in file.patchset.patches {
+        match entry {
+            PatchsetFileEntry::Include(include) if include.enabled => {
+                expand(&dir.join(&include.patchset), stack, base_commit, patches)?;
+            }
+            PatchsetFileEntry::Include(include) => {
+                tracing::debug!("Skipping disabled include {}", include.patchset);
+            }
+            PatchsetFileEntry::Patch(patch) => {
+                match patches.iter().find(|p| p.reference() == patch.reference()) {
+                    Some(existing) if options_of(existing) == options_of(&patch) => {
+                        tracing::debug!("Skipping duplicate patch {}", patch.reference());
+                    }
+                    Some(_) => {
+                        return Err(format!(
+                            "Patch {} is included more than once with different options (in {})",
+                            patch.reference(),
+                            path.display()
+                        ));
+                    }
+                    None => patches.push(patch),
+                }
+            }
+        }
+    }
+    stack.pop();
+
+    Ok(())
+}
+
+/// A patch's options, with plain refs spelled out as the defaults they stand for
+fn options_of(patch: &PatchEntry) -> PatchOptions {
+    match patch {
+        PatchEntry::Ref(reference) => PatchOptions {
+            reference: reference.clone(),
+            enabled: super::default_enabled(),
+            name: None,
+            version: None,
+            url: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            prefix: None,
+            relations: Default::default(),
+        },
+        PatchEntry::Table(options) => options.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_expand_includes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        write(
+            temp_dir.path(),
+            "common/fixes.patchset.toml",
+            r#"
+            [patchset]
+            base_commit = "game:vanilla"
+            patches = ["game:fixes"]
+            "#,
+        );
+        write(
+            temp_dir.path(),
+            "common/ui.patchset.toml",
+            r#"
+            [patchset]
+            includes = ["fixes.patchset.toml"]
+            patches = ["game:ui"]
+            "#,
+        );
+        let profile = write(
+            temp_dir.path(),
+            "profiles/hd.patchset.toml",
+            r#"
+            [patchset]
+            includes = ["../common/fixes.patchset.toml"]
+            patches = [
+                "game:loader",
+                { patchset = "../common/ui.patchset.toml" },
+                "game:textures",
+            ]
+            "#,
+        );
+
+        let patchset = load_expanded(&profile).unwrap();
+        assert_eq!(
+            patchset.patchset.base_commit.as_deref(),
+            Some("game:vanilla")
+        );
+        assert_eq!(
+            patchset.refs(),
+            vec![
+                "game:vanilla",
+                "game:fixes",
+                "game:loader",
+                "game:ui",
+                "game:textures"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_includes() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        write(
+            temp_dir.path(),
+            "fixes.patchset.toml",
+            "[patchset]\npatches = [\"game:fixes\", { ref = \"game:ui\", prefix = \"Data\" }]\n",
+        );
+        let same = write(
+            temp_dir.path(),
+            "same.patchset.toml",
+            r#"
+            [patchset]
+            includes = ["fixes.patchset.toml"]
+            patches = [{ ref = "game:fixes" }, { ref = "game:ui", prefix = "Data" }]
+            "#,
+        );
+        let different = write(
+            temp_dir.path(),
+            "different.patchset.toml",
+            r#"
+            [patchset]
+            includes = ["fixes.patchset.toml"]
+            patches = [{ ref = "game:ui", prefix = "Other" }]
+            "#,
+        );
+
+        assert_eq!(
+            load_expanded(&same).unwrap().refs(),
+            vec!["game:fixes", "game:ui"]
+        );
+        let err = load_expanded(&different).unwrap_err();
+        assert!(err.contains("different options"), "{}", err);
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        write(
+            temp_dir.path(),
+            "a.patchset.toml",
+            "[patchset]\nincludes = [\"b.patchset.toml\"]\n",
+        );
+        write(
+            temp_dir.path(),
+            "b.patchset.toml",
+            "[patchset]\npatches = [{ patchset = \"a.patchset.toml\" }]\n",
+        );
+
+        let err = load_expanded(&temp_dir.path().join("a.patchset.toml")).unwrap_err();
+        assert!(err.starts_with("Patchset include cycle:"), "{}", err);
+        assert!(err.ends_with("a.patchset.toml"), "{}", err);
+    }
+}
@@ -0,0 +1,183 @@
+//! Patchset lockfiles
+//!
+//! Patchsets usually refer to patches by tag, so building the same patchset twice
+//! can silently pick up different commits if the tags moved in between. A lockfile
+//! (`foo.patchset.lock` next to `foo.patchset.toml`) pins every ref to the commit ID
+//! and merkle root it resolved to, much like `Cargo.lock`.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::Patchset;
+use crate::{commit::StratumRef, store::Store};
+
+/// A single pinned ref
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockEntry {
+    /// The ref as written in the patchset
+    pub reference: String,
+    /// The commit ID it resolved to
+    pub commit: String,
+    /// The commit's merkle root, to detect commits being replaced under the same ID
+    pub merkle_root: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchsetLock {
+    #[serde(default, rename = "entry")]
+    pub entries: Vec<LockEntry>,
+}
+
+impl PatchsetLock {
+    /// Returns the lockfile path for a patchset file
+    pub fn path_for(patchset_file: &Path) -> PathBuf {
+        patchset_file.with_extension("lock")
+    }
+
+    /// Load a lockfile, if it exists
+    pub fn load(path: &Path) -> Result<Option<Self>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read lockfile {}: {}", path.display(), e))?;
+        toml::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("Failed to parse lockfile {}: {}", path.display(), e))
+    }
+
+    /// Save the lockfile, through a temporary file renamed into place so an
+    /// interrupted save never leaves a truncated lockfile behind
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = toml::to_string(self).map_err(|e| e.to_string())?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".patchset_lock_")
+            .tempfile_in(dir)
+            .map_err(|e| format!("Failed to create temporary lockfile: {}", e))?;
+        tmp.write_all(content.as_bytes())
+            .map_err(|e| format!("Failed to write lockfile {}: {}", path.display(), e))?;
+        tmp.as_file()
+            .sync_all()
+            .map_err(|e| format!("Failed to sync lockfile {}: {}", path.display(), e))?;
+        tmp.persist(path)
+            .map_err(|e| format!("Failed to replace lockfile {}: {}", path.display(), e))?;
+        Ok(())
+    }
+
+    pub fn get(&self, reference: &str) -> Option<&LockEntry> {
+        self.entries.iter().find(|e| e.reference == reference)
+    }
+
+    /// Lock every ref of a patchset
+    ///
+    /// Entries already in `existing` are kept as-is, unless `refresh` returns true
+    /// for them, in which case they're resolved again.
+    pub fn resolve(
+        patchset: &Patchset,
+        store: &Store,
+        existing: Option<&PatchsetLock>,
+        refresh: impl Fn(&str) -> bool,
+    ) -> Result<Self, String> {
+        let mut entries = Vec::new();
+        for reference in patchset.refs() {
+            let entry = match existing.and_then(|lock| lock.get(&reference)) {
+                Some(entry) if !refresh(&reference) => {
+                    Self::verify_entry(entry, store)?;
+                    entry.clone()
+                }
+                _ => Self::resolve_entry(&reference, store)?,
+            };
+            entries.push(entry);
+        }
+        Ok(PatchsetLock { entries })
+    }
+
+    /// Check that the lock covers every ref of the patchset, and that each ref still
+    /// resolves to its pinned commit
+    ///
+    /// Returns a description of every ref that drifted.
+    pub fn drifted(&self, patchset: &Patchset, store: &Store) -> Result<Vec<String>, String> {
+        let mut drifted = Vec::new();
+        for reference in patchset.refs() {
+            let Some(entry) = self.get(&reference) else {
+                drifted.push(format!("{} is not in the lockfile", reference));
+                continue;
+            };
+            Self::verify_entry(entry, store)?;
+            let current = Self::resolve_entry(&reference, store)?;
+            if current.commit != entry.commit {
+                drifted.push(format!(
+                    "{} is locked to {} but now resolves to {}",
+                    reference, entry.commit, current.commit
+                ));
+            }
+        }
+        Ok(drifted)
+    }
+
+    fn resolve_entry(reference: &str, store: &Store) -> Result<LockEntry, String> {
+        let commit = StratumRef::from(reference).resolve_commit_id(store)?;
+        let merkle_root = store.load_commit(&commit)?.merkle_root().to_string();
+        Ok(LockEntry {
+            reference: reference.to_string(),
+            commit,
+            merkle_root,
+        })
+    }
+
+    /// Make sure a pinned commit still exists and hasn't been replaced
+    fn verify_entry(entry: &LockEntry, store: &Store) -> Result<(), String> {
+        let commit = store.load_commit(&entry.commit).map_err(|e| {
+            format!(
+                "Locked commit {} for {} is unavailable: {}",
+                entry.commit, entry.reference, e
+            )
+        })?;
+        if commit.merkle_root() != entry.merkle_root {
+            return Err(format!(
+                "Locked commit {} for {} has merkle root {}, expected {}",
+                entry.commit,
+                entry.reference,
+                commit.merkle_root(),
+                entry.merkle_root
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_path_and_roundtrip() {
+        assert_eq!(
+            PatchsetLock::path_for(Path::new("mods/game.patchset.toml")),
+            PathBuf::from("mods/game.patchset.lock")
+        );
+
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("game.patchset.lock");
+        assert_eq!(PatchsetLock::load(&path).unwrap(), None);
+
+        let lock = PatchsetLock {
+            entries: vec![LockEntry {
+                reference: "myapp:content-mod-x".to_string(),
+                commit: "a".repeat(64),
+                merkle_root: "b".repeat(64),
+            }],
+        };
+        lock.save(&path).unwrap();
+        let loaded = PatchsetLock::load(&path).unwrap().unwrap();
+        assert_eq!(loaded, lock);
+        assert!(loaded.get("myapp:content-mod-x").is_some());
+        assert!(loaded.get("myapp:other").is_none());
+    }
+}
@@ -8,27 +8,82 @@
 //! To use patchsets:
 //!
 //! - Create a patchset from a file, usually named `*.patchset.toml`
-
+//! - Include other patchset files in it, see [`include`]
+//! - Check it for conflicts between patches with [`Patchset::check`]
+//! - Pin the commits its refs resolve to with a [`lock::PatchsetLock`]
+//! - Sort it into load order with [`Patchset::ordered_entries`], see [`order`]
+//! - Preview a build with [`Patchset::plan`]
+//! - Build it with [`Patchset::generate_commit`], which reuses cached intermediate
+//!   results from [`cache::PatchsetCache`]
+pub mod build;
+pub mod cache;
+pub mod clean;
+pub mod conflicts;
+pub mod include;
+pub mod lock;
+pub mod order;
+pub mod plan;
+
+use std::collections::HashMap;
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{commit::StratumRef, store::Store, util::copy_dir_all};
+use crate::{
+    commit::{PatchRelations, StratumRef},
+    composefs::erofs::ErofsImage,
+    mount::{MountOptions, composefs::MAX_LOWER_LAYERS},
+    store::Store,
+    util::{calculate_total_size, copy_dir_all, copy_dir_filtered, glob_match},
+};
+use conflicts::ConflictReport;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 
 pub struct Patchset {
     pub patchset: PatchsetData,
+    /// Refs pinned to commit IDs by a lockfile, see [`Self::with_lock`]
+    #[serde(skip)]
+    pins: HashMap<String, String>,
 }
 
 impl Patchset {
     /// Creates a new patchset with the given base commit and patches.
     pub fn new(base_commit: Option<String>, patches: Vec<String>) -> Self {
+        Self::new_with_entries(
+            base_commit,
+            patches.into_iter().map(PatchEntry::from).collect(),
+        )
+    }
+
+    /// Creates a new patchset from full patch entries.
+    pub fn new_with_entries(base_commit: Option<String>, patches: Vec<PatchEntry>) -> Self {
         Self {
             patchset: PatchsetData {
                 base_commit,
                 patches,
             },
+            pins: HashMap::new(),
+        }
+    }
+
+    /// Pin refs to the commits recorded in a lockfile
+    ///
+    /// [`Self::base_commit`] and [`Self::patches`] then return the pinned commits
+    /// instead of whatever the refs point to now.
+    pub fn with_lock(mut self, lock: &lock::PatchsetLock) -> Self {
+        self.pins = lock
+            .entries
+            .iter()
+            .map(|entry| (entry.reference.clone(), entry.commit.clone()))
+            .collect();
+        self
+    }
+
+    fn pinned_ref(&self, reference: &str) -> StratumRef {
+        match self.pins.get(reference) {
+            Some(commit) => StratumRef::Commit(commit.clone()),
+            None => StratumRef::from(reference),
         }
     }
 
@@ -37,33 +92,156 @@ impl Patchset {
         self.patchset
             .base_commit
             .as_ref()
-            .map(|into| StratumRef::from(into.as_str()))
+            .map(|into| self.pinned_ref(into))
     }
 
-    /// Returns the list of patches in the patchset.
-    pub fn patches(&self) -> Vec<StratumRef> {
+    /// Returns the enabled patches in the patchset.
+    pub fn entries(&self) -> Vec<&PatchEntry> {
         self.patchset
             .patches
             .iter()
-            .map(|p| StratumRef::from(p.as_str()))
+            .filter(|p| p.enabled())
+            .collect()
+    }
+
+    /// Returns the enabled patches in load order
+    ///
+    /// Relations declared in the patchset are combined with those recorded in each
+    /// patch commit, see [`order`].
+    pub fn ordered_entries(&self, store: &Store) -> Result<Vec<&PatchEntry>, String> {
+        let patches = self
+            .entries()
+            .into_iter()
+            .map(|entry| {
+                let mut relations = entry.relations();
+                let commit_id = self
+                    .pinned_ref(entry.reference())
+                    .resolve_commit_id(store)?;
+                relations.merge(&store.load_commit(&commit_id)?.patch);
+                Ok((entry, relations))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        order::resolve(&patches)
+    }
+
+    /// Returns the list of enabled patches in the patchset.
+    pub fn patches(&self) -> Vec<StratumRef> {
+        self.entries()
+            .iter()
+            .map(|p| self.pinned_ref(p.reference()))
             .collect::<Vec<_>>()
     }
 
+    /// Returns every ref in use as written, base commit first, without duplicates
+    ///
+    /// Disabled patches are left out.
+    pub fn refs(&self) -> Vec<String> {
+        let mut refs: Vec<String> = Vec::new();
+        for reference in self
+            .patchset
+            .base_commit
+            .iter()
+            .map(String::as_str)
+            .chain(self.entries().iter().map(|p| p.reference()))
+        {
+            if !refs.iter().any(|r| r == reference) {
+                refs.push(reference.to_string());
+            }
+        }
+        refs
+    }
+
+    /// Load a patchset file, expanding any other patchset files it includes
     pub fn load_patchset_from_file(path: &Path) -> Result<Self, String> {
-        let content = std::fs::read_to_string(path)
-            .map_err(|e| format!("Failed to read patchset file: {}", e))?;
-        toml::from_str(&content).map_err(|e| format!("Failed to parse patchset: {}", e))
+        include::load_expanded(path)
     }
 
-    /// Apply a single patch on top of a base commit
+    /// Reconstruct a patchset from a commit's layer history
+    ///
+    /// Walks the commit's ancestry down to the first commit without a parent, which
+    /// becomes the base, and lists the patch applied at each step. Patches missing from
+    /// older commits' metadata are recovered, see [`Store::recover_applied_patch`].
+    /// Commits are referred to by tag where they have one.
+    pub fn from_history(store: &Store, sref: &StratumRef) -> Result<Self, String> {
+        let mut tags: HashMap<String, String> = HashMap::new();
+        for label in store.list_all_refs()? {
+            let mut label_tags = store.list_tags(&label)?;
+            label_tags.sort();
+            for tag in label_tags {
+                if let Ok(commit) = store.resolve_tag(&label, &tag) {
+                    tags.entry(commit)
+                        .or_insert_with(|| format!("{}:{}", label, tag));
+                }
+            }
+        }
+        let name = |commit: &str| {
+            tags.get(commit)
+                .cloned()
+                .unwrap_or_else(|| commit.to_string())
+        };
+
+        let mut patches = Vec::new();
+        let mut current = sref.resolve_commit_id(store)?;
+        let mut seen = vec![current.clone()];
+        while let Some(parent) = store.load_commit(&current)?.commit.parent_commit {
+            if seen.contains(&parent) {
+                return Err(format!("Commit history of {} loops at {}", sref, parent));
+            }
+            let patch = store.recover_applied_patch(&current)?;
+            tracing::debug!("{} = {} + {}", current, parent, patch);
+            patches.push(name(&patch));
+            seen.push(parent.clone());
+            current = parent;
+        }
+        patches.reverse();
+
+        Ok(Self::new(Some(name(&current)), patches))
+    }
+
+    /// Report which patches override or delete each other's files
+    ///
+    /// Reads the patches' composefs images directly, without mounting anything.
+    pub fn check(&self, store: &Store) -> Result<ConflictReport, String> {
+        let layers = self
+            .ordered_entries(store)?
+            .into_iter()
+            .map(|entry| {
+                let prefix = entry.prefix()?;
+                let commit_id = self
+                    .pinned_ref(entry.reference())
+                    .resolve_commit_id(store)?;
+                let image_path = store.commit_image_path(&commit_id);
+                let image = std::fs::read(&image_path)
+                    .map_err(|e| format!("Failed to read composefs image {}: {}", image_path, e))?;
+                let entries = ErofsImage::from_bytes(&image)
+                    .overlay_entries()
+                    .into_iter()
+                    .filter(|(path, _)| entry.path_included(Path::new(path)))
+                    .map(|(path, kind)| match &prefix {
+                        Some(prefix) => (prefix.join(&path).to_string_lossy().into_owned(), kind),
+                        None => (path, kind),
+                    })
+                    .collect();
+                Ok((entry.display_name().to_string(), entries))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(ConflictReport::from_layers(&layers))
+    }
+
+    /// Apply a single patch on top of a base commit, honouring the entry's path filters
+    /// and prefix
+    ///
+    /// The resulting commit registers its objects, since it's kept in the build cache.
     #[tracing::instrument(skip_all)]
     fn apply_patch(
         &self,
         store: &Store,
         patch: &StratumRef,
+        entry: &PatchEntry,
         base_commit: &StratumRef,
         label: &str,
-        is_transient: bool,
+        on_phase: &dyn Fn(build::BuildPhase),
     ) -> Result<String, String> {
         tracing::debug!("Applying patch: {} on base: {}", patch, base_commit);
 
@@ -82,6 +260,7 @@ impl Patchset {
         // We have to actually copy the files to disk
 
         // tmpfs is fine, but we want to be prepared for large patches
+        on_phase(build::BuildPhase::Staging);
         {
             tracing::trace!(
                 ?patch,
@@ -90,9 +269,23 @@ impl Patchset {
             let _eph_mount =
                 store.mount_ref_ephemeral(patch, &patch_mount.path().to_string_lossy())?;
 
-            copy_dir_all(patch_mount.path(), copy_workdir.path())
-                .map_err(|e| format!("Failed to copy directory: {}", e))?;
+            let target = match entry.prefix()? {
+                Some(prefix) => copy_workdir.path().join(prefix),
+                None => copy_workdir.path().to_path_buf(),
+            };
+            if entry.is_verbatim() {
+                copy_dir_all(patch_mount.path(), &target)
+            } else {
+                copy_dir_filtered(patch_mount.path(), &target, &|path| {
+                    entry.path_included(path)
+                })
+            }
+            .map_err(|e| format!("Failed to copy directory: {}", e))?;
         }
+        on_phase(build::BuildPhase::Staged {
+            bytes: calculate_total_size(&copy_workdir.path().to_string_lossy())?,
+        });
+        build::check_interrupted()?;
 
         let base_commit_id = base_commit.resolve_commit_id(store)?;
         tracing::debug!(
@@ -101,24 +294,163 @@ impl Patchset {
             patch
         );
 
+        on_phase(build::BuildPhase::Committing);
         let new_commit = store.union_patch_commit(
             label,
             &copy_workdir.path().to_string_lossy(),
             &base_commit_id,
-            is_transient,
+            Some(&patch.resolve_commit_id(store)?),
+            false,
         )?;
 
         tracing::debug!("New commit created: {}", new_commit);
         Ok(new_commit)
     }
-    #[tracing::instrument(skip_all)]
-    pub fn generate_commit(&self, store: &Store, label: &str) -> Result<StratumRef, String> {
+    /// Resolve the base commit and the enabled patches, in load order, to commit IDs
+    ///
+    /// If the patchset has no base commit, the first patch is used as the base.
+    fn resolve_layers(
+        &self,
+        store: &Store,
+    ) -> Result<(String, Vec<(&PatchEntry, String)>), String> {
+        let mut entries = self.ordered_entries(store)?;
         // base commit can be either the first patch or a separate base commit
-        let base_commit = self.base_commit().or_else(|| {
-            tracing::warn!("No base commit specified, using first patch as base");
-            self.patches().first().cloned()
+        let base_commit = match self.base_commit() {
+            Some(base) => base,
+            None => {
+                tracing::warn!("No base commit specified, using first patch as base");
+                if entries.is_empty() {
+                    return Err("No base commit or patches to apply".to_string());
+                }
+                let base_entry = entries.remove(0);
+                if !base_entry.is_verbatim() {
+                    return Err(format!(
+                        "Patch {} is used as the base commit, so it can't have filters or a prefix",
+                        base_entry.reference()
+                    ));
+                }
+                self.pinned_ref(base_entry.reference())
+            }
+        };
+
+        tracing::debug!("Resolving base: {} and patches: {:?}", base_commit, entries);
+        let base_id = base_commit.resolve_commit_id(store)?;
+        let layers = entries
+            .into_iter()
+            .map(|entry| {
+                let id = self
+                    .pinned_ref(entry.reference())
+                    .resolve_commit_id(store)?;
+                Ok((entry, id))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok((base_id, layers))
+    }
+
+    /// Apply patches on top of a base commit in order, returning the resulting commit
+    ///
+    /// Every intermediate result is kept in the [`cache::PatchsetCache`], so only the
+    /// patches after the longest cached prefix are applied. The last commit is created
+    /// under `label` if one is given.
+    fn build_layers(
+        &self,
+        store: &Store,
+        base_id: &str,
+        layers: &[(&PatchEntry, String)],
+        label: Option<&str>,
+        progress: &dyn Fn(build::BuildEvent),
+    ) -> Result<String, String> {
+        let layer_keys = layers
+            .iter()
+            .map(|(entry, id)| entry.layer_key(id))
+            .collect::<Vec<_>>();
+
+        let cache = cache::PatchsetCache::new(store);
+        let (mut applied, mut current_commit) = match cache.longest_prefix(base_id, &layer_keys) {
+            Some((applied, commit)) => {
+                tracing::info!(
+                    "Reusing cached build of {} of {} patches: {}",
+                    applied,
+                    layers.len(),
+                    commit
+                );
+                (applied, commit)
+            }
+            None => (0, base_id.to_string()),
+        };
+
+        progress(build::BuildEvent::Started {
+            total: layers.len(),
+            cached: applied,
         });
 
+        // Apply the remaining patches in order
+        while applied < layers.len() {
+            build::check_interrupted()?;
+            let (entry, patch_id) = &layers[applied];
+            let final_label = label.filter(|_| applied + 1 == layers.len());
+            // Intermediate commits don't belong to any stratum, so they're built under
+            // a throwaway label which the guard removes right after
+            let transient_label = match final_label {
+                Some(_) => None,
+                None => Some(format!("transient_{}", ulid::Ulid::new())),
+            };
+            let patch_label = final_label
+                .or(transient_label.as_deref())
+                .unwrap_or_default()
+                .to_string();
+            let mut guard = build::StepGuard::new(store, transient_label)?;
+
+            let index = applied + 1;
+            let on_phase = |phase| {
+                progress(build::BuildEvent::Patch {
+                    index,
+                    total: layers.len(),
+                    name: entry.display_name(),
+                    phase,
+                })
+            };
+            let new_commit_id = self.apply_patch(
+                store,
+                &StratumRef::Commit(patch_id.clone()),
+                entry,
+                &StratumRef::Commit(current_commit.clone()),
+                &patch_label,
+                &on_phase,
+            )?;
+            guard.created(&new_commit_id);
+
+            applied += 1;
+            cache.insert(base_id, &layer_keys[..applied], &new_commit_id)?;
+            guard.commit();
+            on_phase(build::BuildPhase::Done);
+            current_commit = new_commit_id;
+        }
+
+        Ok(current_commit)
+    }
+
+    /// Build the patchset into a single commit tagged under `label`
+    ///
+    /// Every intermediate result is kept in the [`cache::PatchsetCache`], so a rebuild
+    /// only applies the patches after the longest unchanged prefix.
+    #[tracing::instrument(skip_all)]
+    pub fn generate_commit(&self, store: &Store, label: &str) -> Result<StratumRef, String> {
+        self.generate_commit_with_progress(store, label, &|_| {})
+    }
+
+    /// Like [`Self::generate_commit`], reporting progress through `progress`
+    ///
+    /// Fails with "Build interrupted" between steps once [`build::handle_interrupts`]
+    /// has caught a Ctrl-C. A failed step is rolled back, while the patches applied
+    /// before it stay in the cache.
+    #[tracing::instrument(skip_all)]
+    pub fn generate_commit_with_progress(
+        &self,
+        store: &Store,
+        label: &str,
+        progress: &dyn Fn(build::BuildEvent),
+    ) -> Result<StratumRef, String> {
         let (label, tag) = crate::util::parse_label(label)
             .map_err(|e| format!("Failed to parse label '{}': {}", label, e))?;
         // Actually check if the stratum with the label exists
@@ -130,131 +462,333 @@ impl Patchset {
             return Err(format!("Stratum with label '{}' does not exist", label));
         }
 
-        tracing::debug!(
-            "Generating commit with base: {:?} and patches: {:?}",
-            base_commit,
-            self.patches()
-        );
-        let mut transient_commits_to_clean = Vec::new();
+        let (base_id, layers) = self.resolve_layers(store)?;
+        if layers.is_empty() {
+            return Err("No patches to apply".to_string());
+        }
+
+        let final_commit = self.build_layers(store, &base_id, &layers, Some(&label), progress)?;
+        tracing::debug!("Final commit: {}", final_commit);
+        store
+            .tag_commit(
+                &label,
+                &final_commit,
+                &tag.unwrap_or_else(|| {
+                    tracing::warn!("No tag specified, using 'latest' as default");
+                    "latest".to_string()
+                }),
+            )
+            .map_err(|e| format!("Failed to tag final commit '{}': {}", final_commit, e))?;
+        progress(build::BuildEvent::Finished {
+            commit: &final_commit,
+        });
 
-        let mut current_commit: Option<StratumRef> = None;
-        let mut patches_to_apply = self.patches().to_vec();
-        let last_patch = patches_to_apply.last().cloned();
+        Ok(StratumRef::Commit(final_commit))
+    }
 
-        // If no base commit and using first patch as base, remove it from patches to apply
-        if self.base_commit().is_none() && !patches_to_apply.is_empty() {
-            patches_to_apply.remove(0);
-        }
-        // Remove the last patch from the list to apply
-        if !patches_to_apply.is_empty() {
-            patches_to_apply.pop();
+    /// Mount the patchset as a stack of its patches' images, without building a commit
+    ///
+    /// If `worktree` is given, its upperdir is stacked on top to capture changes.
+    /// Patchsets deeper than the kernel allows have their bottom-most patches collapsed
+    /// into a single (cached) commit first. Patches with path filters or a prefix can't
+    /// be stacked as-is, so they're rejected.
+    #[tracing::instrument(skip_all)]
+    pub fn mount(
+        &self,
+        store: &Store,
+        mountpoint: &str,
+        worktree: Option<&StratumRef>,
+        options: &MountOptions,
+    ) -> Result<(), String> {
+        if let Some(filtered) = self.entries().iter().find(|e| !e.is_verbatim()) {
+            return Err(format!(
+                "Patch {} has path filters or a prefix, which can only be applied by building the patchset",
+                filtered.display_name()
+            ));
         }
 
-        let mut final_commit: Option<String> = None;
+        let (base_id, layers) = self.resolve_layers(store)?;
 
-        // Now, let's finally apply each patch in order
-        for patch in patches_to_apply {
-            let commit_to_layer = if let Some(current) = &current_commit {
-                tracing::debug!("Current commit: {}", current);
-                current.clone()
-            } else {
-                tracing::debug!("No current commit, using base commit");
-                StratumRef::Commit(
-                    base_commit
-                        .as_ref()
-                        .ok_or_else(|| "No base commit or patches to apply".to_string())?
-                        .resolve_commit_id(store)?,
-                )
-            };
+        // Leave room for the object data layer, and the upperdir of read-only mounts
+        let max_layers = MAX_LOWER_LAYERS - 2;
+        let (bottom, layers) = if layers.len() + 1 > max_layers {
+            let collapse = layers.len() + 1 - max_layers;
+            tracing::info!(
+                "Patchset has {} layers, collapsing the base and the first {} patches",
+                layers.len() + 1,
+                collapse
+            );
+            let collapsed =
+                self.build_layers(store, &base_id, &layers[..collapse], None, &|_| {})?;
+            (collapsed, &layers[collapse..])
+        } else {
+            (base_id, &layers[..])
+        };
+
+        let mut commits = std::iter::once(bottom)
+            .chain(layers.iter().map(|(_, id)| id.clone()))
+            .map(StratumRef::Commit)
+            .collect::<Vec<_>>();
+        let top = match worktree {
+            Some(worktree @ StratumRef::Worktree { .. }) => worktree.clone(),
+            Some(other) => return Err(format!("{} is not a worktree", other)),
+            None => commits.pop().expect("at least the base commit"),
+        };
+
+        store.mount_layers(&commits, &top, mountpoint, options)
+    }
+}
 
-            let transient_label = format!("transient_{}", ulid::Ulid::new().to_string());
-            let new_commit_id = self.apply_patch(
-                store,
-                &patch,
-                &commit_to_layer,
-                &transient_label,
-                true, // is_transient
-            )?;
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchsetData {
+    pub base_commit: Option<String>,
+    pub patches: Vec<PatchEntry>,
+}
 
-            current_commit = Some(StratumRef::Commit(new_commit_id.clone()));
-            // Delete transient ref after use
-            std::fs::remove_dir_all(
-                Path::new(store.base_path())
-                    .join("refs")
-                    .join(&transient_label),
-            )
-            .map_err(|e| {
-                format!(
-                    "Failed to remove transient ref '{}': {}",
-                    transient_label, e
-                )
-            })?;
-            transient_commits_to_clean.push(new_commit_id); // Store commit ID, not label
-        }
+/// A patch in a patchset
+///
+/// Either a plain ref, or a table with the ref and options for how it's applied:
+///
+/// ```toml
+/// [patchset]
+/// base_commit = "skyrim:vanilla"
+/// patches = [
+///     "skyrim:unofficial-patch",
+///     { ref = "skyrim:textures", name = "HD Textures", version = "1.2", include = ["**/*.dds"], prefix = "Data" },
+///     { ref = "skyrim:broken-mod", enabled = false },
+/// ]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PatchEntry {
+    Ref(String),
+    Table(PatchOptions),
+}
 
-        // Now apply the final patch (if there is one) using a non-transient commit
-        if let Some(final_patch) = last_patch {
-            let commit_to_apply = if let Some(current) = &current_commit {
-                current.clone()
-            } else {
-                tracing::warn!("No current commit, using base commit");
-                StratumRef::Commit(
-                    base_commit
-                        .as_ref()
-                        .ok_or_else(|| "No base commit or patches to apply".to_string())?
-                        .resolve_commit_id(store)?,
-                )
-            };
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PatchOptions {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// Disabled patches are skipped entirely
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Display name of the patch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Where the patch came from, e.g. its download page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Only apply paths matching any of these globs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Never apply paths matching any of these globs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Subdirectory to apply the patch's contents into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// Load order constraints, on top of those recorded in the patch commit
+    #[serde(flatten)]
+    pub relations: PatchRelations,
+}
 
-            let commit = self.apply_patch(
-                store,
-                &final_patch,
-                &commit_to_apply,
-                &label,
-                false, // is_transient
-            )?;
+fn default_enabled() -> bool {
+    true
+}
+
+impl From<String> for PatchEntry {
+    fn from(reference: String) -> Self {
+        PatchEntry::Ref(reference)
+    }
+}
 
-            tracing::debug!("Final commit created: {}", commit);
-            final_commit.replace(commit);
-            // tag
-            store
-                .tag_commit(
-                    &label,
-                    final_commit.as_ref().unwrap(),
-                    &tag.unwrap_or_else(|| {
-                        tracing::warn!("No tag specified, using 'latest' as default");
-                        "latest".to_string()
-                    }),
-                )
-                .map_err(|e| {
-                    format!(
-                        "Failed to tag final commit '{}': {}",
-                        final_commit.as_ref().unwrap(),
-                        e
-                    )
-                })?;
+impl PatchEntry {
+    /// The ref as written in the patchset
+    pub fn reference(&self) -> &str {
+        match self {
+            PatchEntry::Ref(reference) => reference,
+            PatchEntry::Table(options) => &options.reference,
         }
+    }
 
-        // Clean up transient commits
-        for transient_commit in transient_commits_to_clean {
-            tracing::debug!("Cleaning up transient commit ID: {}", transient_commit);
-            store.delete_commit(&transient_commit).map_err(|e| {
-                format!(
-                    "Failed to remove transient commit '{}': {}",
-                    transient_commit, e
-                )
-            })?;
+    pub fn options(&self) -> Option<&PatchOptions> {
+        match self {
+            PatchEntry::Ref(_) => None,
+            PatchEntry::Table(options) => Some(options),
         }
+    }
 
-        // Return the final commit as a StratumRef
-        final_commit
-            .map(StratumRef::Commit)
-            .ok_or_else(|| "No final commit generated".to_string())
+    pub fn enabled(&self) -> bool {
+        self.options().is_none_or(|o| o.enabled)
+    }
+
+    /// Whether `id` refers to this patch, by ref or by name
+    pub fn matches(&self, id: &str) -> bool {
+        self.reference() == id || self.options().and_then(|o| o.name.as_deref()) == Some(id)
+    }
+
+    /// Load order constraints declared in the patchset
+    pub fn relations(&self) -> PatchRelations {
+        self.options()
+            .map(|o| o.relations.clone())
+            .unwrap_or_default()
+    }
+
+    /// The patch's name if it has one, otherwise its ref
+    pub fn display_name(&self) -> &str {
+        self.options()
+            .and_then(|o| o.name.as_deref())
+            .unwrap_or(self.reference())
+    }
+
+    /// Whether the patch is applied as-is, without filters or a prefix
+    pub fn is_verbatim(&self) -> bool {
+        self.options()
+            .is_none_or(|o| o.include.is_empty() && o.exclude.is_empty() && o.prefix.is_none())
+    }
+
+    /// Whether a path (relative to the patch root) should be applied
+    ///
+    /// A pattern matching a directory also matches everything beneath it.
+    pub fn path_included(&self, path: &Path) -> bool {
+        let Some(options) = self.options() else {
+            return true;
+        };
+        let matches = |patterns: &[String]| {
+            path.ancestors().filter(|p| *p != Path::new("")).any(|p| {
+                let p = p.to_string_lossy();
+                patterns.iter().any(|pattern| glob_match(pattern, &p))
+            })
+        };
+        (options.include.is_empty() || matches(&options.include)) && !matches(&options.exclude)
+    }
+
+    /// The subdirectory to apply the patch into, checked to stay within the tree
+    pub fn prefix(&self) -> Result<Option<&Path>, String> {
+        let Some(prefix) = self.options().and_then(|o| o.prefix.as_deref()) else {
+            return Ok(None);
+        };
+        let path = Path::new(prefix.trim_start_matches('/'));
+        if path
+            .components()
+            .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(format!(
+                "Invalid prefix '{}' for patch {}",
+                prefix,
+                self.reference()
+            ));
+        }
+        Ok(Some(path))
+    }
+
+    /// Identifies the layer this patch produces from a resolved commit, for caching
+    ///
+    /// The same commit applied with different filters produces a different layer.
+    /// Every pattern is length-prefixed, so patterns containing separators can't make
+    /// two different filters look the same.
+    pub fn layer_key(&self, commit_id: &str) -> String {
+        fn encode<'a>(values: impl IntoIterator<Item = &'a String>) -> String {
+            values
+                .into_iter()
+                .map(|v| format!("{}:{}", v.len(), v))
+                .collect()
+        }
+        match self.options() {
+            Some(o) if !self.is_verbatim() => format!(
+                "{}?include={}&exclude={}&prefix={}",
+                commit_id,
+                encode(&o.include),
+                encode(&o.exclude),
+                encode(&o.prefix)
+            ),
+            _ => commit_id.to_string(),
+        }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct PatchsetData {
-    pub base_commit: Option<String>,
-    pub patches: Vec<String>,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_entries() {
+        let patchset: Patchset = toml::from_str(
+            r#"
+            [patchset]
+            base_commit = "game:vanilla"
+            patches = [
+                "game:fixes",
+                { ref = "game:textures", name = "HD Textures", include = ["**/*.dds"], exclude = ["Docs"], prefix = "Data", after = ["game:fixes"] },
+                { ref = "game:broken", enabled = false },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            patchset.refs(),
+            vec!["game:vanilla", "game:fixes", "game:textures"]
+        );
+        let entries = patchset.entries();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_verbatim());
+        assert_eq!(entries[1].display_name(), "HD Textures");
+        assert_eq!(entries[1].prefix().unwrap(), Some(Path::new("Data")));
+        assert_eq!(entries[1].relations().after, vec!["game:fixes"]);
+        assert!(entries[0].relations().is_empty());
+
+        assert!(entries[1].path_included(Path::new("textures/sky.dds")));
+        assert!(!entries[1].path_included(Path::new("textures/readme.txt")));
+        assert!(!entries[1].path_included(Path::new("Docs/a.dds")));
+        assert_ne!(entries[1].layer_key("abc"), "abc");
+        assert_eq!(entries[0].layer_key("abc"), "abc");
+    }
+
+    #[test]
+    fn test_patch_prefix_must_stay_inside() {
+        let entry = PatchEntry::Table(PatchOptions {
+            reference: "game:mod".to_string(),
+            enabled: true,
+            name: None,
+            version: None,
+            url: None,
+            include: vec![],
+            exclude: vec![],
+            prefix: Some("../etc".to_string()),
+            relations: PatchRelations::default(),
+        });
+        assert!(entry.prefix().is_err());
+    }
+
+    #[test]
+    fn test_layer_key_separators() {
+        let entry = |include: &[&str], exclude: &[&str]| {
+            PatchEntry::Table(PatchOptions {
+                reference: "game:mod".to_string(),
+                enabled: true,
+                name: None,
+                version: None,
+                url: None,
+                include: include.iter().map(|p| p.to_string()).collect(),
+                exclude: exclude.iter().map(|p| p.to_string()).collect(),
+                prefix: None,
+                relations: PatchRelations::default(),
+            })
+        };
+        assert_ne!(
+            entry(&["a,b"], &[]).layer_key("abc"),
+            entry(&["a", "b"], &[]).layer_key("abc")
+        );
+        assert_ne!(
+            entry(&["a&exclude=b"], &[]).layer_key("abc"),
+            entry(&["a"], &["b"]).layer_key("abc")
+        );
+        assert_eq!(
+            entry(&["a", "b"], &[]).layer_key("abc"),
+            entry(&["a", "b"], &[]).layer_key("abc")
+        );
+    }
 }
@@ -0,0 +1,232 @@
+//! Load order resolution for patchsets
+//!
+//! Patches can declare how they relate to each other, either in the patchset entry
+//! or in the `[patch]` section of the patch commit's metadata:
+//!
+//! - `requires`: the other patch must be enabled, and is applied first
+//! - `conflicts`: the other patch must not be enabled
+//! - `after`: the other patch is applied first, if it's enabled
+//! - `before`: the other patch is applied later, if it's enabled
+//!
+//! Other patches are referred to by their ref as written in the patchset, or by their
+//! name. Patches are then sorted topologically, keeping the order they're listed in
+//! wherever no relation says otherwise, so the result is deterministic.
+
+use std::collections::BTreeSet;
+
+use super::PatchEntry;
+use crate::commit::PatchRelations;
+
+/// Sort patches into load order
+///
+/// Fails if a required patch is missing, two conflicting patches are both enabled,
+/// or the relations form a cycle.
+pub fn resolve<'a>(
+    patches: &[(&'a PatchEntry, PatchRelations)],
+) -> Result<Vec<&'a PatchEntry>, String> {
+    let find = |id: &str, this: usize| {
+        patches
+            .iter()
+            .enumerate()
+            .filter(|(i, (entry, _))| *i != this && entry.matches(id))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>()
+    };
+
+    // successors[i] holds every patch that must be applied after patch i
+    let mut successors = vec![BTreeSet::new(); patches.len()];
+    let mut errors = Vec::new();
+    for (i, (entry, relations)) in patches.iter().enumerate() {
+        for required in &relations.requires {
+            let found = find(required, i);
+            if found.is_empty() {
+                errors.push(format!(
+                    "{} requires {}, which isn't enabled in the patchset",
+                    entry.display_name(),
+                    required
+                ));
+            }
+            for j in found {
+                successors[j].insert(i);
+            }
+        }
+        for conflict in &relations.conflicts {
+            for j in find(conflict, i) {
+                // Report each conflicting pair once, even if both sides declare it
+                let declared_by_other = patches[j].1.conflicts.iter().any(|c| entry.matches(c));
+                if j > i || !declared_by_other {
+                    errors.push(format!(
+                        "{} conflicts with {}",
+                        entry.display_name(),
+                        patches[j].0.display_name()
+                    ));
+                }
+            }
+        }
+        for after in &relations.after {
+            for j in find(after, i) {
+                successors[j].insert(i);
+            }
+        }
+        for before in &relations.before {
+            for j in find(before, i) {
+                successors[i].insert(j);
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    let mut in_degree = vec![0usize; patches.len()];
+    for succ in &successors {
+        for j in succ {
+            in_degree[*j] += 1;
+        }
+    }
+
+    // Always take the earliest listed patch that's ready, to keep the written order
+    let mut ready = (0..patches.len())
+        .filter(|i| in_degree[*i] == 0)
+        .collect::<BTreeSet<_>>();
+    let mut order = Vec::with_capacity(patches.len());
+    while let Some(i) = ready.pop_first() {
+        order.push(i);
+        for j in &successors[i] {
+            in_degree[*j] -= 1;
+            if in_degree[*j] == 0 {
+                ready.insert(*j);
+            }
+        }
+    }
+
+    if order.len() < patches.len() {
+        return Err(format!(
+            "Load order has a cycle: {}",
+            find_cycle(&successors, &in_degree)
+                .iter()
+                .map(|i| patches[*i].0.display_name())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        ));
+    }
+
+    Ok(order.into_iter().map(|i| patches[i].0).collect())
+}
+
+/// Find a cycle among the patches left over after sorting, as a path that starts
+/// and ends on the same patch
+fn find_cycle(successors: &[BTreeSet<usize>], in_degree: &[usize]) -> Vec<usize> {
+    let remaining = |i: usize| in_degree[i] > 0;
+
+    // Every remaining patch has a remaining predecessor, so walking backwards from
+    // any of them must eventually revisit one
+    let predecessor = |i: usize| {
+        (0..successors.len())
+            .find(|j| remaining(*j) && successors[*j].contains(&i))
+            .expect("remaining patch without a remaining predecessor")
+    };
+
+    let start = (0..in_degree.len()).find(|i| remaining(*i)).unwrap_or(0);
+    let mut path = vec![start];
+    let mut current = start;
+    loop {
+        current = predecessor(current);
+        if let Some(pos) = path.iter().position(|p| *p == current) {
+            let mut cycle = path[pos..].to_vec();
+            cycle.reverse();
+            cycle.push(cycle[0]);
+            return cycle;
+        }
+        path.push(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relations(requires: &[&str], after: &[&str], before: &[&str]) -> PatchRelations {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        PatchRelations {
+            requires: strings(requires),
+            conflicts: Vec::new(),
+            after: strings(after),
+            before: strings(before),
+        }
+    }
+
+    fn names(order: &[&PatchEntry]) -> Vec<String> {
+        order.iter().map(|e| e.reference().to_string()).collect()
+    }
+
+    #[test]
+    fn test_resolve_order() {
+        let ui = PatchEntry::from("game:ui".to_string());
+        let loader = PatchEntry::from("game:loader".to_string());
+        let fixes = PatchEntry::from("game:fixes".to_string());
+        let textures = PatchEntry::from("game:textures".to_string());
+
+        // Without relations, the written order is kept
+        let patches = vec![
+            (&ui, PatchRelations::default()),
+            (&loader, PatchRelations::default()),
+        ];
+        assert_eq!(
+            names(&resolve(&patches).unwrap()),
+            vec!["game:ui", "game:loader"]
+        );
+
+        let patches = vec![
+            (&ui, relations(&["game:loader"], &[], &[])),
+            (&textures, relations(&[], &["game:fixes"], &[])),
+            (&fixes, relations(&[], &[], &["game:ui"])),
+            (&loader, PatchRelations::default()),
+        ];
+        assert_eq!(
+            names(&resolve(&patches).unwrap()),
+            vec!["game:fixes", "game:textures", "game:loader", "game:ui"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_order_errors() {
+        let ui = PatchEntry::from("game:ui".to_string());
+        let loader = PatchEntry::from("game:loader".to_string());
+
+        let missing = vec![(&ui, relations(&["game:skse"], &[], &[]))];
+        assert!(
+            resolve(&missing)
+                .unwrap_err()
+                .contains("game:ui requires game:skse")
+        );
+
+        let cycle = vec![
+            (&ui, relations(&[], &["game:loader"], &[])),
+            (&loader, relations(&[], &["game:ui"], &[])),
+        ];
+        let err = resolve(&cycle).unwrap_err();
+        assert!(err.contains("cycle"), "{}", err);
+        assert!(
+            err.contains("game:ui -> game:loader -> game:ui")
+                || err.contains("game:loader -> game:ui -> game:loader"),
+            "{}",
+            err
+        );
+
+        let conflict = vec![
+            (
+                &ui,
+                PatchRelations {
+                    conflicts: vec!["game:loader".to_string()],
+                    ..Default::default()
+                },
+            ),
+            (&loader, PatchRelations::default()),
+        ];
+        assert_eq!(
+            resolve(&conflict).unwrap_err(),
+            "game:ui conflicts with game:loader"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Build plans for patchsets
+//!
+//! A [`BuildPlan`] describes what building a patchset would do: which commit every
+//! ref resolves to, how big each patch is, how much of the build is already cached
+//! and how much temporary space staging the patches needs. Working it out only reads
+//! from the store, so it's safe to use as a dry run.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{PatchEntry, Patchset, cache::PatchsetCache};
+use crate::{store::Store, util::format_size};
+
+/// A base commit or patch, as it would be applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedLayer {
+    /// The ref as written in the patchset
+    pub reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The commit the ref resolves to, if it does
+    pub commit: Option<String>,
+    /// Number of files in the commit, from its metadata
+    pub files: Option<u64>,
+    /// Total size of the commit's files in bytes, from its metadata
+    pub size: Option<u64>,
+    /// Whether only part of the patch is applied, or it's moved under a prefix
+    pub filtered: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildPlan {
+    pub label: String,
+    pub tag: String,
+    pub label_exists: bool,
+    pub base: Option<PlannedLayer>,
+    /// Patches in the order they'd be applied
+    pub patches: Vec<PlannedLayer>,
+    /// How many patches (from the bottom) are already built in the cache
+    pub cached_patches: usize,
+    /// Upper bound of the temporary space needed to stage patches, in bytes
+    pub staging_bytes: u64,
+    /// Everything that would make the build fail
+    pub problems: Vec<String>,
+}
+
+impl BuildPlan {
+    /// Whether the build is expected to succeed
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Patchset {
+    /// Work out what building the patchset under `label` would do, without writing
+    /// anything
+    ///
+    /// Only an invalid label is an error, every other problem is listed in the plan.
+    pub fn plan(&self, store: &Store, label: &str) -> Result<BuildPlan, String> {
+        let (label, tag) = crate::util::parse_label(label)
+            .map_err(|e| format!("Failed to parse label '{}': {}", label, e))?;
+        let mut problems = Vec::new();
+
+        let label_exists = store.list_all_refs()?.contains(&label);
+        if !label_exists {
+            problems.push(format!("Stratum with label '{}' does not exist", label));
+        }
+
+        let plan_layer = |entry: Option<&PatchEntry>,
+                          reference: &str,
+                          problems: &mut Vec<String>| {
+            let commit = match self.pinned_ref(reference).resolve_commit_id(store) {
+                Ok(commit) => Some(commit),
+                Err(e) => {
+                    problems.push(format!("{} does not resolve: {}", reference, e));
+                    None
+                }
+            };
+            let metadata = commit.as_ref().and_then(|c| {
+                store
+                    .load_commit(c)
+                    .inspect_err(|e| problems.push(format!("Commit {} is unreadable: {}", c, e)))
+                    .ok()
+            });
+            PlannedLayer {
+                reference: reference.to_string(),
+                name: entry.and_then(|e| e.options()?.name.clone()),
+                commit,
+                files: metadata.as_ref().map(|m| m.files.count),
+                size: metadata.as_ref().map(|m| m.files.total_size),
+                filtered: entry.is_some_and(|e| !e.is_verbatim()),
+            }
+        };
+
+        let mut planned = self
+            .entries()
+            .into_iter()
+            .map(|entry| {
+                (
+                    entry,
+                    plan_layer(Some(entry), entry.reference(), &mut problems),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // Load order needs every patch commit, so only sort once they all resolve
+        if planned.iter().all(|(_, layer)| layer.commit.is_some()) {
+            match self.ordered_entries(store) {
+                Ok(order) => planned
+                    .sort_by_key(|(entry, _)| order.iter().position(|e| std::ptr::eq(*e, *entry))),
+                Err(e) => problems.push(e),
+            }
+        }
+
+        let base = match &self.patchset.base_commit {
+            Some(base) => Some(plan_layer(None, base, &mut problems)),
+            None if planned.is_empty() => None,
+            None => {
+                let (entry, layer) = planned.remove(0);
+                if !entry.is_verbatim() {
+                    problems.push(format!(
+                        "Patch {} is used as the base commit, so it can't have filters or a prefix",
+                        entry.reference()
+                    ));
+                }
+                Some(layer)
+            }
+        };
+        if planned.is_empty() {
+            problems.push("No patches to apply".to_string());
+        }
+
+        let cached_patches = match base.as_ref().and_then(|b| b.commit.as_ref()) {
+            Some(base_id) if planned.iter().all(|(_, layer)| layer.commit.is_some()) => {
+                let layer_keys = planned
+                    .iter()
+                    .map(|(entry, layer)| entry.layer_key(layer.commit.as_deref().unwrap()))
+                    .collect::<Vec<_>>();
+                PatchsetCache::new(store)
+                    .peek_longest_prefix(base_id, &layer_keys)
+                    .map_or(0, |(len, _)| len)
+            }
+            _ => 0,
+        };
+
+        // Each patch is copied into its own staging directory, which is removed again
+        // once it's applied, so only the largest one counts
+        let staging_bytes = planned
+            .iter()
+            .skip(cached_patches)
+            .filter_map(|(_, layer)| layer.size)
+            .max()
+            .unwrap_or(0);
+
+        Ok(BuildPlan {
+            label,
+            tag: tag.unwrap_or_else(|| "latest".to_string()),
+            label_exists,
+            base,
+            patches: planned.into_iter().map(|(_, layer)| layer).collect(),
+            cached_patches,
+            staging_bytes,
+            problems,
+        })
+    }
+}
+
+impl fmt::Display for PlannedLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", name, self.reference)?,
+            None => write!(f, "{}", self.reference)?,
+        }
+        match &self.commit {
+            Some(commit) => write!(f, " -> {}", commit)?,
+            None => write!(f, " -> (unresolved)")?,
+        }
+        if let (Some(files), Some(size)) = (self.files, self.size) {
+            write!(f, ", {} files, {}", files, format_size(size))?;
+        }
+        if self.filtered {
+            write!(f, ", filtered")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Build plan for {}:{}", self.label, self.tag)?;
+        match &self.base {
+            Some(base) => writeln!(f, "  base: {}", base)?,
+            None => writeln!(f, "  base: (none)")?,
+        }
+        for (i, patch) in self.patches.iter().enumerate() {
+            let cached = if i < self.cached_patches {
+                " [cached]"
+            } else {
+                ""
+            };
+            writeln!(f, "  {}. {}{}", i + 1, patch, cached)?;
+        }
+        writeln!(
+            f,
+            "{} of {} patches to apply, staging needs up to {}",
+            self.patches.len() - self.cached_patches,
+            self.patches.len(),
+            format_size(self.staging_bytes)
+        )?;
+        if !self.problems.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Problems:")?;
+            for problem in &self.problems {
+                writeln!(f, "  {}", problem)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -5,11 +5,19 @@
 //!
 //! This state should be stored in tmpfs, as mounts are ephemeral and should not persist
 //! across reboots.
+//!
+//! The state file is prefixed with a small header (magic bytes + schema version), so that
+//! newer versions of Stratum can still decode state written by older ones. All
+//! read-modify-write cycles go through [`StateManager::transaction`], which holds an
+//! exclusive `flock(2)` on a sidecar lock file, and saves are done by writing to a temporary
+//! file and atomically renaming it over the old state.
 
 use crate::commit::StratumRef;
+use crate::mount::MountOptions;
 use bincode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
@@ -30,42 +38,269 @@ pub struct MountedStratum {
     ///
     /// Note: there should only be one read-write mount per stratum,
     /// mounted snapshots should always be read-only.
+    ///
+    /// This is what the mount currently is, e.g. after the watchdog made it
+    /// read-only, so remounts go by it rather than by `options`.
     pub read_only: bool,
     /// Resolved path to the stratum commit
     pub base_commit: String,
+    /// Commit IDs stacked as lowerdirs, bottom-most first
+    ///
+    /// Empty for regular mounts, which only use `base_commit`.
+    pub layers: Vec<String>,
+    /// Options the stratum was mounted with
+    pub options: MountOptions,
+    /// Private tmpfs holding the upperdir, to be torn down on unmount
+    pub tmpfs_dir: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode, Default)]
+impl MountedStratum {
+    /// Check whether this mount uses the given commit in any of its layers
+    pub fn uses_commit(&self, commit_id: &str) -> bool {
+        self.base_commit == commit_id || self.layers.iter().any(|c| c == commit_id)
+    }
+}
+
+/// Magic bytes at the start of every versioned state file
+const STATE_MAGIC: &[u8; 4] = b"STRS";
+
+/// Current schema version of [`StratumState`]
+///
+/// Bump this whenever the encoded layout of [`StratumState`] changes, and teach
+/// [`StratumState::decode`] how to read the previous version.
+pub const STATE_VERSION: u32 = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Encode, Decode)]
 pub struct StratumState {
+    /// Schema version this state was written with
+    pub version: u32,
     /// Map of mount points to mounted strata
     pub mounts: HashMap<PathBuf, MountedStratum>,
 }
 
+impl Default for StratumState {
+    fn default() -> Self {
+        StratumState {
+            version: STATE_VERSION,
+            mounts: HashMap::new(),
+        }
+    }
+}
+
+impl StratumState {
+    /// Encode the state, including the magic/version header
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut content = STATE_MAGIC.to_vec();
+        bincode::encode_into_std_write(self, &mut content, bincode::config::standard())
+            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        Ok(content)
+    }
+
+    /// Decode a state file, migrating older schema versions if needed
+    pub fn decode(content: &[u8]) -> Result<Self, String> {
+        let config = bincode::config::standard();
+
+        let Some(payload) = content.strip_prefix(STATE_MAGIC) else {
+            // Unversioned state files from before the header was introduced only
+            // contained the mounts map.
+            let mounts: HashMap<PathBuf, legacy::MountedStratumV1> =
+                bincode::decode_from_slice(content, config)
+                    .map_err(|e| format!("Failed to parse legacy state file: {}", e))?
+                    .0;
+            tracing::debug!(
+                "Migrating unversioned state file to version {}",
+                STATE_VERSION
+            );
+            return Ok(legacy::migrate_mounts(mounts));
+        };
+
+        // The version is always the first field, so we can peek at it before
+        // committing to a layout.
+        let (version, _): (u32, usize) = bincode::decode_from_slice(payload, config)
+            .map_err(|e| format!("Failed to read state file version: {}", e))?;
+
+        match version {
+            STATE_VERSION => {
+                let state: StratumState = bincode::decode_from_slice(payload, config)
+                    .map_err(|e| format!("Failed to parse state file: {}", e))?
+                    .0;
+                Ok(state)
+            }
+            1 => {
+                let state: legacy::StratumStateV1 = bincode::decode_from_slice(payload, config)
+                    .map_err(|e| format!("Failed to parse state file: {}", e))?
+                    .0;
+                tracing::debug!("Migrating state file from version 1 to {}", STATE_VERSION);
+                Ok(legacy::migrate_mounts(state.mounts))
+            }
+            2 => {
+                let state: legacy::StratumStateV2 = bincode::decode_from_slice(payload, config)
+                    .map_err(|e| format!("Failed to parse state file: {}", e))?
+                    .0;
+                tracing::debug!("Migrating state file from version 2 to {}", STATE_VERSION);
+                Ok(legacy::migrate_mounts(state.mounts))
+            }
+            v if v > STATE_VERSION => Err(format!(
+                "State file was written by a newer version of stratum (schema version {}, supported up to {})",
+                v, STATE_VERSION
+            )),
+            v => Err(format!("Unknown state file schema version {}", v)),
+        }
+    }
+}
+
+/// Layouts of previous state schema versions, kept around for migrations
+mod legacy {
+    use super::*;
+
+    /// [`MountedStratum`] as of schema version 1 (and unversioned state files)
+    #[derive(Debug, Clone, Encode, Decode)]
+    pub struct MountedStratumV1 {
+        pub stratum_ref: StratumMountRef,
+        pub mount_point: PathBuf,
+        pub read_only: bool,
+        pub base_commit: String,
+    }
+
+    /// [`StratumState`] as of schema version 1
+    #[derive(Debug, Clone, Encode, Decode)]
+    pub struct StratumStateV1 {
+        pub version: u32,
+        pub mounts: HashMap<PathBuf, MountedStratumV1>,
+    }
+
+    /// [`MountedStratum`] as of schema version 2
+    #[derive(Debug, Clone, Encode, Decode)]
+    pub struct MountedStratumV2 {
+        pub stratum_ref: StratumMountRef,
+        pub mount_point: PathBuf,
+        pub read_only: bool,
+        pub base_commit: String,
+        pub layers: Vec<String>,
+    }
+
+    /// [`StratumState`] as of schema version 2
+    #[derive(Debug, Clone, Encode, Decode)]
+    pub struct StratumStateV2 {
+        pub version: u32,
+        pub mounts: HashMap<PathBuf, MountedStratumV2>,
+    }
+
+    impl From<MountedStratumV1> for MountedStratum {
+        fn from(m: MountedStratumV1) -> Self {
+            MountedStratumV2 {
+                stratum_ref: m.stratum_ref,
+                mount_point: m.mount_point,
+                read_only: m.read_only,
+                base_commit: m.base_commit,
+                layers: Vec::new(),
+            }
+            .into()
+        }
+    }
+
+    impl From<MountedStratumV2> for MountedStratum {
+        fn from(m: MountedStratumV2) -> Self {
+            MountedStratum {
+                options: MountOptions {
+                    read_only: m.read_only,
+                    ..Default::default()
+                },
+                stratum_ref: m.stratum_ref,
+                mount_point: m.mount_point,
+                read_only: m.read_only,
+                base_commit: m.base_commit,
+                layers: m.layers,
+                tmpfs_dir: None,
+            }
+        }
+    }
+
+    pub fn migrate_mounts<M: Into<MountedStratum>>(mounts: HashMap<PathBuf, M>) -> StratumState {
+        let mounts = mounts
+            .into_iter()
+            .map(|(mount_point, m)| (mount_point, m.into()))
+            .collect();
+
+        StratumState {
+            version: STATE_VERSION,
+            mounts,
+        }
+    }
+}
+
+/// An exclusive lock on the state file, released when dropped
+pub struct StateLock {
+    _file: std::fs::File,
+}
+
 pub struct StateManager {
     state_file: PathBuf,
+    lock_file: PathBuf,
 }
 
 impl StateManager {
     const STATE_DIR: &'static str = "/run/stratum";
     const STATE_FILE: &'static str = "state";
+    const LOCK_FILE: &'static str = "state.lock";
 
     pub fn new() -> Result<Self, String> {
-        let state_dir = Path::new(Self::STATE_DIR);
+        Self::new_in(Path::new(Self::STATE_DIR))
+    }
+
+    /// Create a state manager that keeps its state in the given directory
+    pub fn new_in(state_dir: &Path) -> Result<Self, String> {
         std::fs::create_dir_all(state_dir)
             .map_err(|e| format!("Failed to create state directory: {}", e))?;
 
         let state_file = state_dir.join(Self::STATE_FILE);
+        let lock_file = state_dir.join(Self::LOCK_FILE);
+
+        Ok(StateManager {
+            state_file,
+            lock_file,
+        })
+    }
+
+    /// Take an exclusive lock on the state, blocking until it is available
+    pub fn lock(&self) -> Result<StateLock, String> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.lock_file)
+            .map_err(|e| format!("Failed to open state lock file: {}", e))?;
 
-        Ok(StateManager { state_file })
+        rustix::fs::flock(&file, rustix::fs::FlockOperation::LockExclusive)
+            .map_err(|e| format!("Failed to lock state file: {}", e))?;
+
+        Ok(StateLock { _file: file })
+    }
+
+    /// Run a load-modify-save cycle on the state while holding the state lock
+    ///
+    /// The state is only saved if `f` returns `Ok`.
+    pub fn transaction<T>(
+        &self,
+        f: impl FnOnce(&mut StratumState) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let _lock = self.lock()?;
+        let mut state = self.load_state()?;
+        let result = f(&mut state)?;
+        self.save_state(&state)?;
+        Ok(result)
     }
 
     /// Check if any commit with  the given ID is currently mounted
     pub fn get_commit_mounted(&self, commit_id: &str) -> Result<bool, String> {
         let state = self.load_state()?;
-        Ok(state.mounts.values().any(|m| m.base_commit == commit_id))
+        Ok(state.mounts.values().any(|m| m.uses_commit(commit_id)))
     }
 
     /// Load the current state from disk
+    ///
+    /// Saves are atomic renames, so this never observes a partially written file.
+    /// Use [`Self::transaction`] if the state is going to be modified.
     pub fn load_state(&self) -> Result<StratumState, String> {
         if !self.state_file.exists() {
             return Ok(StratumState::default());
@@ -74,20 +309,44 @@ impl StateManager {
         let content = std::fs::read(&self.state_file)
             .map_err(|e| format!("Failed to read state file: {}", e))?;
 
-        let state: StratumState = bincode::decode_from_slice(&content, bincode::config::standard())
-            .map_err(|e| format!("Failed to parse state file: {}", e))?
-            .0;
-
-        Ok(state)
+        StratumState::decode(&content)
     }
 
     /// Save the current state to disk
+    ///
+    /// The state is written to a temporary file in the state directory, synced,
+    /// then renamed over the old state file.
+    ///
+    /// Callers should hold the state lock, see [`Self::transaction`].
     pub fn save_state(&self, state: &StratumState) -> Result<(), String> {
-        let content = bincode::encode_to_vec(state, bincode::config::standard())
-            .map_err(|e| format!("Failed to serialize state: {}", e))?;
+        let content = state.encode()?;
 
-        std::fs::write(&self.state_file, content)
+        let state_dir = self
+            .state_file
+            .parent()
+            .ok_or_else(|| "State file has no parent directory".to_string())?;
+
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".state_")
+            .tempfile_in(state_dir)
+            .map_err(|e| format!("Failed to create temporary state file: {}", e))?;
+
+        tmp.write_all(&content)
             .map_err(|e| format!("Failed to write state file: {}", e))?;
+        tmp.as_file()
+            .sync_all()
+            .map_err(|e| format!("Failed to sync state file: {}", e))?;
+
+        tmp.persist(&self.state_file)
+            .map_err(|e| format!("Failed to replace state file: {}", e))?;
+
+        if let Err(e) = crate::mount::fsync_dir(state_dir) {
+            tracing::warn!(
+                "Failed to fsync state directory {}: {}",
+                state_dir.display(),
+                e
+            );
+        }
 
         Ok(())
     }
@@ -98,18 +357,18 @@ impl StateManager {
         mount_point: PathBuf,
         mounted_stratum: MountedStratum,
     ) -> Result<(), String> {
-        let mut state = self.load_state()?;
-        state.mounts.insert(mount_point, mounted_stratum);
-        self.save_state(&state)?;
-        Ok(())
+        self.transaction(|state| {
+            state.mounts.insert(mount_point, mounted_stratum);
+            Ok(())
+        })
     }
 
     /// Remove a mounted stratum from the state
     pub fn remove_mount(&self, mount_point: &Path) -> Result<(), String> {
-        let mut state = self.load_state()?;
-        state.mounts.remove(mount_point);
-        self.save_state(&state)?;
-        Ok(())
+        self.transaction(|state| {
+            state.mounts.remove(mount_point);
+            Ok(())
+        })
     }
 
     /// Find a mounted stratum by worktree
@@ -152,3 +411,117 @@ impl StateManager {
         Ok(state.mounts)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn mounted(mount_point: &str) -> MountedStratum {
+        MountedStratum {
+            stratum_ref: StratumMountRef::Worktree {
+                label: "myapp".to_string(),
+                worktree: "main".to_string(),
+            },
+            mount_point: PathBuf::from(mount_point),
+            read_only: false,
+            base_commit: "abc123".to_string(),
+            layers: Vec::new(),
+            options: MountOptions::default(),
+            tmpfs_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_state_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let manager = StateManager::new_in(temp_dir.path()).unwrap();
+
+        manager
+            .add_mount(PathBuf::from("/mnt/a"), mounted("/mnt/a"))
+            .unwrap();
+
+        let state = manager.load_state().unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.mounts.contains_key(Path::new("/mnt/a")));
+
+        manager.remove_mount(Path::new("/mnt/a")).unwrap();
+        assert!(manager.load_state().unwrap().mounts.is_empty());
+    }
+
+    fn mounted_v1(mount_point: &str) -> legacy::MountedStratumV1 {
+        let m = mounted(mount_point);
+        legacy::MountedStratumV1 {
+            stratum_ref: m.stratum_ref,
+            mount_point: m.mount_point,
+            read_only: m.read_only,
+            base_commit: m.base_commit,
+        }
+    }
+
+    #[test]
+    fn test_legacy_state_migration() {
+        let mut mounts = HashMap::new();
+        mounts.insert(PathBuf::from("/mnt/a"), mounted_v1("/mnt/a"));
+        let legacy = bincode::encode_to_vec(&mounts, bincode::config::standard()).unwrap();
+
+        let state = StratumState::decode(&legacy).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.mounts.len(), 1);
+    }
+
+    #[test]
+    fn test_v1_state_migration() {
+        let mut mounts = HashMap::new();
+        mounts.insert(PathBuf::from("/mnt/a"), mounted_v1("/mnt/a"));
+        let mut content = STATE_MAGIC.to_vec();
+        bincode::encode_into_std_write(
+            legacy::StratumStateV1 { version: 1, mounts },
+            &mut content,
+            bincode::config::standard(),
+        )
+        .unwrap();
+
+        let state = StratumState::decode(&content).unwrap();
+        assert_eq!(state.version, STATE_VERSION);
+        assert!(state.mounts[Path::new("/mnt/a")].layers.is_empty());
+    }
+
+    #[test]
+    fn test_newer_state_version_rejected() {
+        let mut content = STATE_MAGIC.to_vec();
+        bincode::encode_into_std_write(
+            STATE_VERSION + 1,
+            &mut content,
+            bincode::config::standard(),
+        )
+        .unwrap();
+
+        assert!(StratumState::decode(&content).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_transactions_keep_all_mounts() {
+        let temp_dir = TempDir::new().unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = temp_dir.path().to_path_buf();
+                std::thread::spawn(move || {
+                    let manager = StateManager::new_in(&dir).unwrap();
+                    let mount_point = format!("/mnt/{}", i);
+                    manager
+                        .add_mount(PathBuf::from(&mount_point), mounted(&mount_point))
+                        .unwrap();
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let manager = StateManager::new_in(temp_dir.path()).unwrap();
+        assert_eq!(manager.load_state().unwrap().mounts.len(), 8);
+    }
+}
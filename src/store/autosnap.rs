@@ -0,0 +1,337 @@
+//! Automatic snapshots of worktrees
+//!
+//! Self-modifying apps can wreck their own state, so worktrees can be committed
+//! periodically, time machine style. Each snapshot commits the worktree's changes on
+//! top of its base (like `stratum commit`, so ignored paths are left out), without
+//! touching the worktree itself, and tags the commit as `auto-<timestamp>` in the
+//! worktree's label, e.g. `myapp:auto-2026-10-16T12:00:05`. Should that tag already
+//! exist, a counter is appended, as in `myapp:auto-2026-10-16T12:00:05-2`.
+//!
+//! A worktree is only snapshotted if something in its upperdir changed since it was
+//! last committed. Old snapshots are pruned with keep-hourly, keep-daily and
+//! keep-weekly rules: for each of the last N hours (days, weeks) that have
+//! snapshots, the newest one is kept. The newest snapshot is always kept.
+
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::Store;
+use super::worktree_commit::CommitOptions;
+use crate::ignore::IgnoreRules;
+use crate::state::StratumMountRef;
+
+/// Prefix of the tags given to automatic snapshots
+pub const AUTOSNAP_TAG_PREFIX: &str = "auto-";
+
+/// Timestamp format of automatic snapshot tags, in UTC
+const AUTOSNAP_TAG_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Timestamp format of automatic snapshot tags from before they had seconds
+const LEGACY_AUTOSNAP_TAG_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+/// How many automatic snapshots to keep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Keep the newest snapshot of each of the last N hours
+    pub hourly: usize,
+    /// Keep the newest snapshot of each of the last N days
+    pub daily: usize,
+    /// Keep the newest snapshot of each of the last N ISO weeks
+    pub weekly: usize,
+}
+
+impl Retention {
+    /// Whether no rules are set, in which case nothing is pruned
+    pub fn is_empty(&self) -> bool {
+        self.hourly == 0 && self.daily == 0 && self.weekly == 0
+    }
+}
+
+/// The tag for an automatic snapshot taken at `time`
+pub fn autosnap_tag(time: DateTime<Utc>) -> String {
+    format!(
+        "{}{}",
+        AUTOSNAP_TAG_PREFIX,
+        time.format(AUTOSNAP_TAG_FORMAT)
+    )
+}
+
+/// When the automatic snapshot with the given tag was taken, or `None` if the tag
+/// isn't one
+pub fn parse_autosnap_tag(tag: &str) -> Option<DateTime<Utc>> {
+    let timestamp = tag.strip_prefix(AUTOSNAP_TAG_PREFIX)?;
+    let parse = |timestamp: &str| {
+        [AUTOSNAP_TAG_FORMAT, LEGACY_AUTOSNAP_TAG_FORMAT]
+            .into_iter()
+            .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+            .map(|t| t.and_utc())
+    };
+    parse(timestamp).or_else(|| {
+        // Snapshots taken within the same second get a counter
+        let (timestamp, counter) = timestamp.rsplit_once('-')?;
+        counter.parse::<u32>().ok()?;
+        parse(timestamp)
+    })
+}
+
+impl Store {
+    /// List the worktrees that are mounted writable with their upperdir in the store
+    ///
+    /// Worktrees mounted with `--tmpfs` keep their changes elsewhere, and are skipped.
+    pub fn mounted_worktrees(&self) -> Result<Vec<(String, String)>, String> {
+        let mut worktrees = self
+            .state_manager
+            .get_all_mounts()?
+            .into_values()
+            .filter(|m| !m.read_only && m.tmpfs_dir.is_none())
+            .filter_map(|m| match m.stratum_ref {
+                StratumMountRef::Worktree { label, worktree } => Some((label, worktree)),
+                StratumMountRef::Snapshot(_) => None,
+            })
+            .collect::<Vec<_>>();
+        worktrees.sort();
+        worktrees.dedup();
+        Ok(worktrees)
+    }
+
+    /// Whether a worktree has changes that haven't been committed yet
+    ///
+    /// Unlike [`Store::worktree_has_changes`], changes that were already committed
+    /// (and are still in the upperdir) don't count.
+    pub fn worktree_changed_since_commit(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<bool, String> {
+        if !self.worktree_has_changes(label, worktree_name)? {
+            return Ok(false);
+        }
+        let Some(last_committed) = self
+            .load_worktree(label, worktree_name)?
+            .worktree
+            .last_committed
+        else {
+            return Ok(true);
+        };
+
+        let rules = self.worktree_ignore_rules(label, worktree_name)?;
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        let newest = newest_change(Path::new(&upperdir), Path::new(""), &rules)
+            .map_err(|e| format!("Failed to read upperdir {}: {}", upperdir, e))?;
+        Ok(newest.is_some_and(|t| t > last_committed))
+    }
+
+    /// Snapshot a worktree if it changed since it was last committed
+    ///
+    /// Returns the tag of the snapshot, or `None` if there was nothing to snapshot.
+    pub fn autosnap_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<Option<String>, String> {
+        if !self.worktree_changed_since_commit(label, worktree_name)? {
+            tracing::debug!("Worktree {}:{} is unchanged", label, worktree_name);
+            return Ok(None);
+        }
+
+        let tag = unique_tag(autosnap_tag(Utc::now()), &self.list_tags(label)?);
+        let commit_id = self.commit_worktree(label, worktree_name, &CommitOptions::default())?;
+        self.tag_commit(label, &commit_id, &tag)?;
+        tracing::info!(
+            "Snapshotted worktree {}:{} as {}:{}",
+            label,
+            worktree_name,
+            label,
+            tag
+        );
+        Ok(Some(tag))
+    }
+
+    /// Remove the automatic snapshot tags of a label that `retention` doesn't keep
+    ///
+    /// Returns the removed tags. The commits themselves are left for garbage
+    /// collection.
+    pub fn prune_autosnaps(
+        &self,
+        label: &str,
+        retention: &Retention,
+    ) -> Result<Vec<String>, String> {
+        if retention.is_empty() {
+            return Ok(Vec::new());
+        }
+        let snapshots = self
+            .list_tags(label)?
+            .into_iter()
+            .filter_map(|tag| parse_autosnap_tag(&tag).map(|time| (tag, time)))
+            .collect::<Vec<_>>();
+
+        let pruned = autosnaps_to_prune(snapshots, retention);
+        for tag in &pruned {
+            self.untag(tag, label)?;
+        }
+        Ok(pruned)
+    }
+}
+
+/// `tag`, or `tag` with the lowest counter from 2 up that isn't in `existing`
+fn unique_tag(tag: String, existing: &[String]) -> String {
+    if !existing.contains(&tag) {
+        return tag;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", tag, n))
+        .find(|t| !existing.contains(t))
+        .expect("counters don't run out")
+}
+
+/// Pick the snapshots `retention` doesn't keep
+fn autosnaps_to_prune(
+    mut snapshots: Vec<(String, DateTime<Utc>)>,
+    retention: &Retention,
+) -> Vec<String> {
+    // Newest first
+    snapshots.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+
+    let mut keep = HashSet::new();
+    if let Some((tag, _)) = snapshots.first() {
+        keep.insert(tag.clone());
+    }
+    for (count, period) in [
+        (retention.hourly, "%Y-%m-%dT%H"),
+        (retention.daily, "%Y-%m-%d"),
+        (retention.weekly, "%G-W%V"),
+    ] {
+        let mut last_period = None;
+        let mut kept = 0;
+        for (tag, time) in &snapshots {
+            if kept == count {
+                break;
+            }
+            let current = time.format(period).to_string();
+            if last_period.as_ref() != Some(&current) {
+                keep.insert(tag.clone());
+                kept += 1;
+                last_period = Some(current);
+            }
+        }
+    }
+
+    snapshots
+        .into_iter()
+        .filter(|(tag, _)| !keep.contains(tag))
+        .map(|(tag, _)| tag)
+        .collect()
+}
+
+/// Find when anything in an upperdir last changed, skipping ignored paths
+///
+/// Uses the change time rather than the modification time, since copying a file up
+/// keeps its modification time. A directory's own change time covers entries
+/// removed from it.
+fn newest_change(
+    upperdir: &Path,
+    relative: &Path,
+    rules: &IgnoreRules,
+) -> std::io::Result<Option<DateTime<Utc>>> {
+    let change_time = |metadata: &std::fs::Metadata| {
+        DateTime::from_timestamp(metadata.ctime(), metadata.ctime_nsec() as u32)
+    };
+
+    let dir = upperdir.join(relative);
+    let mut newest = change_time(&std::fs::symlink_metadata(&dir)?);
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        if rules.is_ignored(&path, metadata.is_dir()) {
+            continue;
+        }
+        let changed = if metadata.is_dir() {
+            newest_change(upperdir, &path, rules)?
+        } else {
+            change_time(&metadata)
+        };
+        newest = newest.max(changed);
+    }
+    Ok(newest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_autosnap_retention() {
+        let snapshots = [
+            "2026-10-16T12:40:00",
+            "2026-10-16T12:20:00",
+            "2026-10-16T11:50:00",
+            "2026-10-16T10:10:00",
+            "2026-10-15T23:00:00",
+            "2026-10-14T08:00:00",
+            "2026-10-05T08:00:00",
+        ]
+        .into_iter()
+        .map(|t| {
+            let time = NaiveDateTime::parse_from_str(t, AUTOSNAP_TAG_FORMAT)
+                .unwrap()
+                .and_utc();
+            let tag = autosnap_tag(time);
+            assert_eq!(parse_autosnap_tag(&tag), Some(time));
+            (tag, time)
+        })
+        .collect::<Vec<_>>();
+
+        let retention = Retention {
+            hourly: 2,
+            daily: 2,
+            weekly: 0,
+        };
+        let mut pruned = autosnaps_to_prune(snapshots.clone(), &retention);
+        pruned.sort();
+        assert_eq!(
+            pruned,
+            vec![
+                "auto-2026-10-05T08:00:00",
+                "auto-2026-10-14T08:00:00",
+                "auto-2026-10-16T10:10:00",
+                "auto-2026-10-16T12:20:00",
+            ]
+        );
+
+        let retention = Retention {
+            weekly: 2,
+            ..Default::default()
+        };
+        let pruned = autosnaps_to_prune(snapshots, &retention);
+        assert!(!pruned.contains(&"auto-2026-10-16T12:40:00".to_string()));
+        assert!(!pruned.contains(&"auto-2026-10-05T08:00:00".to_string()));
+        assert_eq!(pruned.len(), 5);
+    }
+
+    #[test]
+    fn test_autosnap_tags() {
+        let time = NaiveDateTime::parse_from_str("2026-10-16T12:00:05", AUTOSNAP_TAG_FORMAT)
+            .unwrap()
+            .and_utc();
+        let tag = autosnap_tag(time);
+        assert_eq!(tag, "auto-2026-10-16T12:00:05");
+
+        let existing = vec![tag.clone(), format!("{}-2", tag)];
+        let next = unique_tag(tag.clone(), &existing);
+        assert_eq!(next, "auto-2026-10-16T12:00:05-3");
+        assert_eq!(parse_autosnap_tag(&next), Some(time));
+        assert_eq!(unique_tag(tag.clone(), &[]), tag);
+
+        // Tags from before seconds were included
+        assert_eq!(
+            parse_autosnap_tag("auto-2026-10-16T12:00"),
+            Some(time - chrono::Duration::seconds(5))
+        );
+        assert_eq!(parse_autosnap_tag("auto-latest"), None);
+        assert_eq!(parse_autosnap_tag("manual-2026-10-16T12:00:05"), None);
+    }
+}
@@ -0,0 +1,89 @@
+//! Store-wide configuration
+//!
+//! Kept as `config.toml` at the root of the store. Missing files and missing
+//! keys fall back to their defaults, so older stores keep working.
+
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// How strictly the store uses kernel fs-verity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VerityPolicy {
+    /// Never enable or check fs-verity
+    Off,
+    /// Enable fs-verity on import where the backing filesystem supports it, and
+    /// enforce it when mounting commits that have it
+    #[default]
+    Opportunistic,
+    /// Fail imports that can't enable fs-verity, and refuse to mount commits
+    /// without it
+    Required,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreConfig {
+    /// fs-verity policy for imports and mounts
+    #[serde(default)]
+    pub verity: VerityPolicy,
+}
+
+impl StoreConfig {
+    pub const FILE_NAME: &'static str = "config.toml";
+
+    /// Load the configuration of the store at `base_path`
+    pub fn load(base_path: &Path) -> Result<Self, String> {
+        let path = base_path.join(Self::FILE_NAME);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Save the configuration to the store at `base_path`
+    ///
+    /// The configuration is written to a temporary file next to it, synced, then
+    /// renamed over the old one, so a crash never leaves the store unable to open.
+    pub fn save(&self, base_path: &Path) -> Result<(), String> {
+        let path = base_path.join(Self::FILE_NAME);
+        let content = toml::to_string(self).map_err(|e| e.to_string())?;
+        let mut tmp = tempfile::Builder::new()
+            .prefix(".config_")
+            .tempfile_in(base_path)
+            .map_err(|e| format!("Failed to create temporary {}: {}", path.display(), e))?;
+        tmp.write_all(content.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        tmp.as_file()
+            .sync_all()
+            .map_err(|e| format!("Failed to sync {}: {}", path.display(), e))?;
+        tmp.persist(&path)
+            .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_config_roundtrip() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        assert_eq!(
+            StoreConfig::load(temp_dir.path()).unwrap().verity,
+            VerityPolicy::Opportunistic
+        );
+
+        let config = StoreConfig {
+            verity: VerityPolicy::Required,
+        };
+        config.save(temp_dir.path()).unwrap();
+        let content =
+            std::fs::read_to_string(temp_dir.path().join(StoreConfig::FILE_NAME)).unwrap();
+        assert!(content.contains("verity = \"required\""));
+        assert_eq!(StoreConfig::load(temp_dir.path()).unwrap(), config);
+    }
+}
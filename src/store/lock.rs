@@ -0,0 +1,77 @@
+//! Store-wide lock between `patchset clean` and everything else
+//!
+//! Temporary directories and commits being written look exactly like the leftovers of
+//! a killed command, so cleaning up while another command runs would pull them out
+//! from under it. Commands hold a shared lock on the store for as long as they run,
+//! and [`crate::patchset::clean::clean`] only goes ahead if it can take the lock
+//! exclusively.
+
+use std::path::Path;
+
+use rustix::fs::{FlockOperation, flock};
+
+use super::Store;
+
+/// A lock on the store, released when dropped
+pub struct StoreLock {
+    _file: std::fs::File,
+}
+
+impl Store {
+    const LOCK_FILE: &'static str = "store.lock";
+
+    fn open_lock_file(&self) -> Result<std::fs::File, String> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(Path::new(&self.base_path).join(Self::LOCK_FILE))
+            .map_err(|e| format!("Failed to open store lock file: {}", e))
+    }
+
+    /// Take a shared lock on the store, waiting for a running cleanup to finish
+    pub fn lock_shared(&self) -> Result<StoreLock, String> {
+        let file = self.open_lock_file()?;
+        flock(&file, FlockOperation::LockShared)
+            .map_err(|e| format!("Failed to lock store: {}", e))?;
+        Ok(StoreLock { _file: file })
+    }
+
+    /// Take an exclusive lock on the store, failing right away if any other command
+    /// holds it
+    pub fn try_lock_exclusive(&self) -> Result<StoreLock, String> {
+        let file = self.open_lock_file()?;
+        flock(&file, FlockOperation::NonBlockingLockExclusive).map_err(|e| {
+            if e == rustix::io::Errno::WOULDBLOCK {
+                "Another stratum command is using the store, try again once it's done".to_string()
+            } else {
+                format!("Failed to lock store: {}", e)
+            }
+        })?;
+        Ok(StoreLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::StateManager;
+
+    #[test]
+    fn test_store_lock() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let store = Store::new_with_state(
+            temp_dir.path().join("store").to_string_lossy().to_string(),
+            StateManager::new_in(&temp_dir.path().join("run")).unwrap(),
+        );
+
+        let shared = store.lock_shared().unwrap();
+        let other = store.lock_shared().unwrap();
+        let err = store.try_lock_exclusive().err().unwrap();
+        assert!(err.contains("Another stratum command"), "{}", err);
+
+        drop(shared);
+        drop(other);
+        let _exclusive = store.try_lock_exclusive().unwrap();
+    }
+}
@@ -0,0 +1,434 @@
+//! Three-way merges of commits and worktrees
+//!
+//! Each side of a merge is a commit, or a worktree (its base commit plus the
+//! changes in its upperdir, except ignored paths). Both sides are diffed against
+//! their common ancestor, found by following parent commits, into upperdir-style
+//! deltas. Changes only one side made are taken as-is. Paths both sides changed
+//! differently are conflicts, resolved by a [`MergePolicy`]. The merged delta is then
+//! applied on top of the ancestor, producing a commit with both sides as parents.
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::Store;
+use crate::commit::StratumRef;
+use crate::util::{apply_upper_layer, copy_upper_entry, is_opaque_dir, is_whiteout};
+
+/// How to resolve paths both sides of a merge changed differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum MergePolicy {
+    /// Keep the first side's version
+    Ours,
+    /// Keep the second side's version
+    Theirs,
+    /// Keep whichever version was modified last
+    Newest,
+    /// Keep the first side's version, and save the second's next to it as
+    /// `<name>.conflict-<ref>`
+    Copies,
+}
+
+/// What a merge did with a conflicting path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    Ours,
+    Theirs,
+    /// The second side's version was saved at this path
+    Copy(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub path: String,
+    pub outcome: MergeOutcome,
+}
+
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            MergeOutcome::Ours => write!(f, "{}: kept ours", self.path),
+            MergeOutcome::Theirs => write!(f, "{}: took theirs", self.path),
+            MergeOutcome::Copy(copy) => {
+                write!(f, "{}: kept ours, theirs saved as {}", self.path, copy)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    pub commit: String,
+    /// The common ancestor both sides were diffed against
+    pub ancestor: String,
+    pub conflicts: Vec<MergeConflict>,
+}
+
+/// One side of a merge
+struct MergeSide {
+    name: String,
+    commit: String,
+    /// Label and name of the worktree, whose uncommitted changes go on top of
+    /// `commit`
+    worktree: Option<(String, String)>,
+}
+
+impl MergeSide {
+    /// The result of merging `theirs` into this side, if one side already contains
+    /// the other and no new commit is needed
+    ///
+    /// Worktrees always need one, since their uncommitted changes aren't in any
+    /// commit yet.
+    fn trivial_merge(&self, theirs: &MergeSide, ancestor: &str) -> Option<String> {
+        if self.worktree.is_some() || theirs.worktree.is_some() {
+            None
+        } else if theirs.commit == ancestor {
+            // Merging a commit into itself or one of its descendants
+            Some(self.commit.clone())
+        } else if self.commit == ancestor {
+            // Fast-forward
+            Some(theirs.commit.clone())
+        } else {
+            None
+        }
+    }
+}
+
+impl Store {
+    /// Merge `theirs` into `ours`, committing the result under `label`
+    ///
+    /// Conflicts are resolved with `policy`. Without one, the merge fails if there
+    /// are any, listing them. Worktrees are merged with their uncommitted changes,
+    /// and their base commit is recorded as the parent.
+    pub fn merge(
+        &self,
+        ours: &StratumRef,
+        theirs: &StratumRef,
+        label: &str,
+        policy: Option<MergePolicy>,
+    ) -> Result<MergeResult, String> {
+        let ours = self.merge_side(ours)?;
+        let theirs = self.merge_side(theirs)?;
+        let ancestor = self.merge_base(&ours.commit, &theirs.commit)?;
+        if let Some(commit) = ours.trivial_merge(&theirs, &ancestor) {
+            tracing::info!(
+                "{} and {} need no merge commit, the result is {}",
+                ours.name,
+                theirs.name,
+                commit
+            );
+            return Ok(MergeResult {
+                commit,
+                ancestor,
+                conflicts: Vec::new(),
+            });
+        }
+        tracing::info!(
+            "Merging {} into {} with common ancestor {}",
+            theirs.name,
+            ours.name,
+            ancestor
+        );
+
+        let work = self.new_tempdir();
+        let ours_delta = work.path().join("ours");
+        let theirs_delta = work.path().join("theirs");
+        let merged = work.path().join("merged");
+        self.side_delta(&ours, &ancestor, &ours_delta)?;
+        self.side_delta(&theirs, &ancestor, &theirs_delta)?;
+
+        let suffix = theirs.name.replace('/', "_");
+        let conflicts = merge_deltas(
+            &ours_delta,
+            &theirs_delta,
+            &merged,
+            policy.unwrap_or(MergePolicy::Ours),
+            &suffix,
+        )
+        .map_err(|e| format!("Failed to merge changes: {}", e))?;
+        if policy.is_none() && !conflicts.is_empty() {
+            return Err(format!(
+                "{} and {} both changed:\n  {}\nChoose how to resolve them with --strategy",
+                ours.name,
+                theirs.name,
+                conflicts
+                    .iter()
+                    .map(|c| c.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n  ")
+            ));
+        }
+
+        // Commit IDs are derived from their contents, so the result may be a commit
+        // that already exists, whose history mustn't be rewritten
+        let existing = self.list_commits()?.into_iter().collect::<HashSet<_>>();
+        let commit =
+            self.union_patch_commit(label, &merged.to_string_lossy(), &ancestor, None, false)?;
+        if existing.contains(&commit) {
+            tracing::info!("The merge result is the existing commit {}", commit);
+        } else {
+            self.update_commit(&commit, |c| {
+                c.commit.parent_commit = Some(ours.commit.clone());
+                c.commit.merged_parents = vec![theirs.commit.clone()];
+            })?;
+        }
+
+        Ok(MergeResult {
+            commit,
+            ancestor,
+            conflicts,
+        })
+    }
+
+    fn merge_side(&self, sref: &StratumRef) -> Result<MergeSide, String> {
+        match sref {
+            StratumRef::Worktree { label, worktree } => {
+                let wt = self.load_worktree(label, worktree)?;
+                if self.is_worktree_mounted(label, worktree)? {
+                    tracing::warn!(
+                        "Worktree {} is mounted, changes made during the merge may be missed",
+                        sref
+                    );
+                }
+                Ok(MergeSide {
+                    name: sref.to_string(),
+                    commit: wt.base_commit().to_string(),
+                    worktree: Some((label.clone(), worktree.clone())),
+                })
+            }
+            _ => Ok(MergeSide {
+                name: sref.to_string(),
+                commit: sref.resolve_commit_id(self)?,
+                worktree: None,
+            }),
+        }
+    }
+
+    /// Find the closest commit both `a` and `b` descend from
+    pub fn merge_base(&self, a: &str, b: &str) -> Result<String, String> {
+        let ancestors = |start: &str| -> Result<Vec<String>, String> {
+            let mut seen = HashSet::new();
+            let mut order = Vec::new();
+            let mut queue = VecDeque::from([start.to_string()]);
+            while let Some(id) = queue.pop_front() {
+                if !seen.insert(id.clone()) {
+                    continue;
+                }
+                let commit = self.load_commit(&id)?;
+                queue.extend(commit.commit.parents().map(String::from));
+                order.push(id);
+            }
+            Ok(order)
+        };
+
+        let of_b = ancestors(b)?.into_iter().collect::<HashSet<_>>();
+        ancestors(a)?
+            .into_iter()
+            .find(|id| of_b.contains(id))
+            .ok_or_else(|| format!("Commits {} and {} have no common ancestor", a, b))
+    }
+
+    /// Write the changes `side` made since `ancestor` into `out`, as an upperdir
+    fn side_delta(&self, side: &MergeSide, ancestor: &str, out: &Path) -> Result<(), String> {
+        std::fs::create_dir_all(out).map_err(|e| e.to_string())?;
+        if side.commit != ancestor {
+            let old_mount = self.new_tempdir();
+            let new_mount = self.new_tempdir();
+            let _old = self.mount_ref_ephemeral(
+                &StratumRef::Commit(ancestor.to_string()),
+                &old_mount.path().to_string_lossy(),
+            )?;
+            let _new = self.mount_ref_ephemeral(
+                &StratumRef::Commit(side.commit.clone()),
+                &new_mount.path().to_string_lossy(),
+            )?;
+            crate::util::write_tree_delta(old_mount.path(), new_mount.path(), out)
+                .map_err(|e| format!("Failed to diff {} against {}: {}", side.name, ancestor, e))?;
+        }
+        if let Some((label, worktree)) = &side.worktree {
+            // Ignored paths are left out, as they would be when committing the worktree
+            let staging = self.new_tempdir();
+            self.stage_worktree_changes(label, worktree, staging.path())?;
+            apply_upper_layer(staging.path(), out)
+                .map_err(|e| format!("Failed to read changes of {}: {}", side.name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Merge two upperdir-style deltas into `out`
+///
+/// Returns the paths both deltas changed differently, along with how `policy`
+/// resolved them. Conflict copies are named after `theirs_name`.
+fn merge_deltas(
+    ours: &Path,
+    theirs: &Path,
+    out: &Path,
+    policy: MergePolicy,
+    theirs_name: &str,
+) -> io::Result<Vec<MergeConflict>> {
+    apply_upper_layer(ours, out)?;
+    let mut conflicts = Vec::new();
+    merge_dir(ours, theirs, out, "", policy, theirs_name, &mut conflicts)?;
+    Ok(conflicts)
+}
+
+fn merge_dir(
+    ours: &Path,
+    theirs: &Path,
+    out: &Path,
+    prefix: &str,
+    policy: MergePolicy,
+    theirs_name: &str,
+    conflicts: &mut Vec<MergeConflict>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(theirs)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let path = format!("{}{}", prefix, name.to_string_lossy());
+        let theirs_path = entry.path();
+        let ours_path = ours.join(&name);
+        let out_path = out.join(&name);
+        let theirs_meta = std::fs::symlink_metadata(&theirs_path)?;
+
+        let Ok(ours_meta) = std::fs::symlink_metadata(&ours_path) else {
+            copy_upper_entry(&theirs_path, &out_path)?;
+            continue;
+        };
+        if ours_meta.is_dir()
+            && theirs_meta.is_dir()
+            && is_opaque_dir(&ours_path) == is_opaque_dir(&theirs_path)
+        {
+            let prefix = format!("{}/", path);
+            merge_dir(
+                &ours_path,
+                &theirs_path,
+                &out_path,
+                &prefix,
+                policy,
+                theirs_name,
+                conflicts,
+            )?;
+            continue;
+        }
+        if same_entry(&ours_path, &ours_meta, &theirs_path, &theirs_meta)? {
+            continue;
+        }
+
+        let outcome = match policy {
+            MergePolicy::Ours => MergeOutcome::Ours,
+            MergePolicy::Theirs => MergeOutcome::Theirs,
+            MergePolicy::Newest if theirs_meta.mtime() > ours_meta.mtime() => MergeOutcome::Theirs,
+            MergePolicy::Newest => MergeOutcome::Ours,
+            // A deletion leaves nothing to copy
+            MergePolicy::Copies if is_whiteout(&theirs_meta) => MergeOutcome::Ours,
+            MergePolicy::Copies => {
+                let copy = format!("{}.conflict-{}", name.to_string_lossy(), theirs_name);
+                copy_upper_entry(&theirs_path, &out.join(&copy))?;
+                MergeOutcome::Copy(format!("{}{}", prefix, copy))
+            }
+        };
+        if outcome == MergeOutcome::Theirs {
+            copy_upper_entry(&theirs_path, &out_path)?;
+        }
+        tracing::debug!("Conflict at {}: {:?}", path, outcome);
+        conflicts.push(MergeConflict { path, outcome });
+    }
+    Ok(())
+}
+
+/// Whether both sides made the same change to a path
+fn same_entry(
+    a: &Path,
+    a_meta: &std::fs::Metadata,
+    b: &Path,
+    b_meta: &std::fs::Metadata,
+) -> io::Result<bool> {
+    if is_whiteout(a_meta) || is_whiteout(b_meta) {
+        return Ok(is_whiteout(a_meta) && is_whiteout(b_meta));
+    }
+    if a_meta.file_type() != b_meta.file_type() || a_meta.mode() != b_meta.mode() {
+        return Ok(false);
+    }
+    if a_meta.is_symlink() {
+        return Ok(std::fs::read_link(a)? == std::fs::read_link(b)?);
+    }
+    if a_meta.is_file() {
+        return Ok(a_meta.len() == b_meta.len() && std::fs::read(a)? == std::fs::read(b)?);
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trivial_merge() {
+        let side = |commit: &str, worktree: bool| MergeSide {
+            name: commit.to_string(),
+            commit: commit.to_string(),
+            worktree: worktree.then(|| ("myapp".to_string(), "main".to_string())),
+        };
+
+        // Into itself
+        assert_eq!(
+            side("a", false).trivial_merge(&side("a", false), "a"),
+            Some("a".to_string())
+        );
+        // An ancestor into its descendant
+        assert_eq!(
+            side("b", false).trivial_merge(&side("a", false), "a"),
+            Some("b".to_string())
+        );
+        // A descendant into its ancestor fast-forwards
+        assert_eq!(
+            side("a", false).trivial_merge(&side("b", false), "a"),
+            Some("b".to_string())
+        );
+        assert_eq!(side("b", false).trivial_merge(&side("c", false), "a"), None);
+        // A worktree's changes still need committing
+        assert_eq!(side("b", true).trivial_merge(&side("a", false), "a"), None);
+    }
+
+    #[test]
+    fn test_merge_deltas() {
+        let ours = tempfile::TempDir::new().unwrap();
+        let theirs = tempfile::TempDir::new().unwrap();
+        let work = tempfile::TempDir::new().unwrap();
+
+        for dir in [ours.path(), theirs.path()] {
+            std::fs::create_dir_all(dir.join("saves")).unwrap();
+            std::fs::write(dir.join("same.ini"), b"identical").unwrap();
+        }
+        std::fs::write(ours.path().join("saves/alice.sav"), b"alice").unwrap();
+        std::fs::write(theirs.path().join("saves/bob.sav"), b"bob").unwrap();
+        std::fs::write(ours.path().join("settings.ini"), b"ours").unwrap();
+        std::fs::write(theirs.path().join("settings.ini"), b"theirs").unwrap();
+
+        let out = work.path().join("copies");
+        let conflicts =
+            merge_deltas(ours.path(), theirs.path(), &out, MergePolicy::Copies, "bob").unwrap();
+        assert_eq!(
+            conflicts,
+            vec![MergeConflict {
+                path: "settings.ini".to_string(),
+                outcome: MergeOutcome::Copy("settings.ini.conflict-bob".to_string()),
+            }]
+        );
+        assert!(out.join("saves/alice.sav").exists());
+        assert!(out.join("saves/bob.sav").exists());
+        assert_eq!(std::fs::read(out.join("settings.ini")).unwrap(), b"ours");
+        assert_eq!(
+            std::fs::read(out.join("settings.ini.conflict-bob")).unwrap(),
+            b"theirs"
+        );
+
+        let out = work.path().join("theirs");
+        merge_deltas(ours.path(), theirs.path(), &out, MergePolicy::Theirs, "bob").unwrap();
+        assert_eq!(std::fs::read(out.join("settings.ini")).unwrap(), b"theirs");
+    }
+}
@@ -4,15 +4,25 @@
 //! functionality for loading and saving the store to disk.
 //!
 //! This is similar to composefs-rs' `Repository` type.
+pub mod autosnap;
 pub mod chunks;
+pub mod config;
+pub mod lock;
+pub mod merge;
+pub mod patch_archive;
+pub mod rebase;
 #[cfg(test)]
 pub mod tests;
+pub mod worktree_commit;
+pub mod worktree_limit;
+pub mod worktree_stats;
 
 use tempfile::TempDir;
 
 use crate::{
     commit::{StratumRef, Worktree},
-    mount::EphemeralMount,
+    ignore::{IGNORE_FILE, IgnoreRules},
+    mount::{EphemeralMount, MountOptions},
     object::ObjectDatabase,
     state::StateManager,
     util::{calculate_total_size, copy_dir_all, fsync_all_walk},
@@ -27,6 +37,7 @@ pub struct Store {
     pub base_path: String,
     object_database: ObjectDatabase,
     state_manager: StateManager,
+    config: config::StoreConfig,
 }
 
 impl Store {
@@ -49,6 +60,12 @@ impl Store {
     const COMMIT_FILE: &'static str = "commit.cfs";
 
     pub fn new(base_path: String) -> Self {
+        let state_manager = StateManager::new().expect("Failed to initialize state manager");
+        Self::new_with_state(base_path, state_manager)
+    }
+
+    /// Open the store at `base_path`, keeping mount state with `state_manager`
+    pub fn new_with_state(base_path: String, state_manager: StateManager) -> Self {
         std::fs::create_dir_all(&base_path).ok();
 
         // Sync only the store directory itself, not its entire parent tree
@@ -59,14 +76,27 @@ impl Store {
 
         let object_database =
             ObjectDatabase::new(&base_path).expect("Failed to initialize object database");
-        let state_manager = StateManager::new().expect("Failed to initialize state manager");
+        let config = config::StoreConfig::load(Path::new(&base_path))
+            .expect("Failed to load store configuration");
         Store {
             base_path,
             object_database,
             state_manager,
+            config,
         }
     }
 
+    pub fn config(&self) -> &config::StoreConfig {
+        &self.config
+    }
+
+    /// Change the store-wide configuration and save it to disk
+    pub fn set_config(&mut self, config: config::StoreConfig) -> Result<(), String> {
+        config.save(Path::new(&self.base_path))?;
+        self.config = config;
+        Ok(())
+    }
+
     pub fn base_path(&self) -> &str {
         &self.base_path
     }
@@ -89,6 +119,11 @@ impl Store {
         format!("{}/{}/{}", self.base_path, Self::REFS_DIR, label)
     }
 
+    /// Returns the path to a commit's composefs image
+    pub fn commit_image_path(&self, commit_id: &str) -> String {
+        format!("{}/{}", self.commit_path(commit_id), Self::COMMIT_FILE)
+    }
+
     /// Returns the path to the commits directory
     fn commits_path(&self) -> String {
         let path = format!("{}/{}", self.base_path, Self::COMMITS_DIR);
@@ -142,7 +177,7 @@ impl Store {
     }
 
     /// Returns the path to a worktree's upperdir
-    fn worktree_upperdir(&self, label: &str, worktree: &str) -> String {
+    pub(crate) fn worktree_upperdir(&self, label: &str, worktree: &str) -> String {
         let worktree_path = self.worktree_path(label, worktree);
         std::fs::create_dir_all(&worktree_path).ok();
         format!("{}/{}", worktree_path, Self::UPPERDIR)
@@ -164,28 +199,10 @@ impl Store {
         )
     }
 
-    /// Mount a reference at a given mountpoint.
-    ///
-    /// This keeps track of currently-mounted points using [`crate::state::StateManager`],
-    /// preventing the same worktree to be mounted mutably concurrently.
-    ///
-    /// If you're creating an ephemeral mount for the purpose of staging new commits,
-    /// consider [`Self::mount_ref_ephemeral`] instead.
+    /// Prepare a path to be mounted on, returning its canonical path
     ///
-    /// # Arguments
-    /// * `sref` - The stratum reference to mount
-    /// * `mountpoint` - The path where to mount the filesystem
-    /// * `worktree` - Optional worktree name. If provided, creates writable mount with upperdir. If None, creates read-only mount.
-    pub fn mount_ref(
-        &self,
-        sref: &StratumRef,
-        mountpoint: &str,
-        worktree: Option<&str>,
-    ) -> Result<(), String> {
-        let cid = sref
-            .resolve_commit_id(self)
-            .map_err(|e| format!("Failed to resolve commit ID: {}", e))?;
-
+    /// If the path is already a mountpoint, it is unmounted first.
+    fn prepare_mountpoint(&self, mountpoint: &str) -> Result<PathBuf, String> {
         let actual_mountpoint = PathBuf::from(mountpoint);
 
         // Check if the destination is already a mountpoint
@@ -221,7 +238,7 @@ impl Store {
         }
 
         // After unmounting (if needed), we can use the actual mountpoint directly
-        let mounted_mp = actual_mountpoint.clone();
+        let mounted_mp = actual_mountpoint;
 
         // Create mountpoint if it doesn't exist
         std::fs::create_dir_all(mountpoint)
@@ -236,21 +253,45 @@ impl Store {
             )
         })?;
 
+        Ok(canonical_mountpoint)
+    }
+
+    /// Mount a reference at a given mountpoint.
+    ///
+    /// This keeps track of currently-mounted points using [`crate::state::StateManager`],
+    /// preventing the same worktree to be mounted mutably concurrently.
+    ///
+    /// If you're creating an ephemeral mount for the purpose of staging new commits,
+    /// consider [`Self::mount_ref_ephemeral`] instead.
+    ///
+    /// # Arguments
+    /// * `sref` - The stratum reference to mount
+    /// * `mountpoint` - The path where to mount the filesystem
+    /// * `worktree` - Optional worktree name. If provided, creates writable mount with upperdir. If None, creates read-only mount.
+    /// * `options` - Extra mount options, see [`MountOptions`]. With `tmpfs`, read-only refs
+    ///   are mounted writable on top of a throwaway upperdir.
+    pub fn mount_ref(
+        &self,
+        sref: &StratumRef,
+        mountpoint: &str,
+        worktree: Option<&str>,
+        options: &MountOptions,
+    ) -> Result<(), String> {
+        let cid = sref
+            .resolve_commit_id(self)
+            .map_err(|e| format!("Failed to resolve commit ID: {}", e))?;
+
+        let mounted_mp = PathBuf::from(mountpoint);
+        let canonical_mountpoint = self.prepare_mountpoint(mountpoint)?;
+
         // Check if already mounted
         if self.is_mounted(&mounted_mp.to_string_lossy())? {
             tracing::info!("Already mounted at {}", mounted_mp.display());
             return Ok(());
         }
 
-        // Get the composefs file for this commit
-        let commit_file = format!("{}/commit.cfs", self.commit_path(&cid));
-        if !Path::new(&commit_file).exists() {
-            return Err(format!("Commit file not found: {}", commit_file));
-        }
-
-        // Open the composefs image file
-        let image_file = std::fs::File::open(&commit_file)
-            .map_err(|e| format!("Failed to open composefs image {}: {}", commit_file, e))?;
+        // Open the composefs image file for this commit
+        let (image_file, verity) = self.open_commit_image(&cid)?;
 
         // Extract label for source name construction and worktree operations
         let label = match sref {
@@ -277,37 +318,30 @@ impl Store {
                     ));
                 }
 
-                let source_name = format!("stratum:{}+{}", label, worktree_name);
-
                 // Create composefs configuration with worktree upperdir
-                let upperdir = self.worktree_upperdir(label, worktree_name);
-                let workdir = self.worktree_workdir(label, worktree_name);
-                let config = crate::mount::composefs::ComposeFsConfig::writable(
-                    image_file.into(),
-                    source_name.clone(),
-                    std::path::PathBuf::from(upperdir),
-                    Some(std::path::PathBuf::from(workdir)),
-                );
-
-                let config = config
-                    .with_basedir(std::path::PathBuf::from(self.objects_path()))
-                    .with_source_name(source_name);
+                let config = self
+                    .worktree_mount_config(label, worktree_name, image_file)
+                    .with_verity(verity);
+                let max_size = self.load_worktree(label, worktree_name)?.worktree.max_size;
+                let (config, tmpfs_dir) = self.apply_mount_options(config, options, max_size)?;
 
                 // Mount using native implementation
                 tracing::debug!("Mounting writable composefs at {}", mounted_mp.display());
-                crate::mount::composefs::mount_composefs_persistent_at(&config, &mounted_mp)
-                    .map_err(|e| format!("Failed to mount composefs: {}", e))?;
+                self.mount_composefs_with_tmpfs(&config, &mounted_mp, tmpfs_dir.as_deref())?;
 
                 // Update state manager with mount information using canonical path
                 let mounted_stratum = crate::state::MountedStratum {
                     stratum_ref: crate::state::StratumMountRef::Worktree {
-                        label: sref.to_string(),
+                        label: label.to_string(),
                         worktree: worktree_name.to_string(),
                     },
                     mount_point: canonical_mountpoint.clone(),
-                    read_only: false, // Worktrees are always writable
+                    read_only: config.read_only,
                     // Base commit of the worktree, useful for safety checks
                     base_commit: cid.clone(),
+                    layers: Vec::new(),
+                    options: options.clone(),
+                    tmpfs_dir,
                 };
                 self.state_manager
                     .add_mount(canonical_mountpoint.clone(), mounted_stratum)?;
@@ -338,19 +372,24 @@ impl Store {
 
                 let config = config
                     .with_basedir(std::path::PathBuf::from(self.objects_path()))
-                    .with_source_name(source_name);
+                    .with_source_name(source_name)
+                    .with_verity(verity);
+                let (config, tmpfs_dir) = self.apply_mount_options(config, options, None)?;
 
                 // Mount using native implementation
                 tracing::debug!("Mounting read-only composefs at {:?}", mounted_mp);
-                crate::mount::composefs::mount_composefs_persistent_at(&config, &mounted_mp)
-                    .map_err(|e| format!("Failed to mount composefs: {}", e))?;
+                self.mount_composefs_with_tmpfs(&config, &mounted_mp, tmpfs_dir.as_deref())?;
 
                 // Update state manager with mount information using canonical path
                 let mounted_stratum = crate::state::MountedStratum {
                     stratum_ref: crate::state::StratumMountRef::Snapshot(sref.clone()),
                     mount_point: canonical_mountpoint.clone(),
                     base_commit: cid.clone(),
-                    read_only: true, // Read-only snapshots
+                    // Snapshots are read-only, unless backed by a throwaway tmpfs upper
+                    read_only: tmpfs_dir.is_none(),
+                    layers: Vec::new(),
+                    options: options.clone(),
+                    tmpfs_dir,
                 };
                 self.state_manager
                     .add_mount(canonical_mountpoint, mounted_stratum)?;
@@ -366,6 +405,360 @@ impl Store {
         Ok(())
     }
 
+    /// Mount several commits stacked as OverlayFS lowerdirs, without committing them
+    ///
+    /// `layers` are given bottom-most first, so later layers override earlier ones,
+    /// the same as patchset order. If `sref` is a worktree, its upperdir is stacked
+    /// on top and the mount is writable; the worktree's own base commit is not used.
+    /// Otherwise `sref` is resolved and stacked as the topmost read-only layer.
+    pub fn mount_layers(
+        &self,
+        layers: &[StratumRef],
+        sref: &StratumRef,
+        mountpoint: &str,
+        options: &MountOptions,
+    ) -> Result<(), String> {
+        let mut layer_ids = layers
+            .iter()
+            .map(|layer| {
+                layer
+                    .resolve_commit_id(self)
+                    .map_err(|e| format!("Failed to resolve layer '{}': {}", layer, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let worktree = match sref {
+            StratumRef::Worktree { label, worktree } => {
+                if !self.worktree_exists(label, worktree) {
+                    return Err(format!("Worktree {}:{} does not exist", label, worktree));
+                }
+                Some((label.as_str(), worktree.as_str()))
+            }
+            _ => {
+                layer_ids.push(
+                    sref.resolve_commit_id(self)
+                        .map_err(|e| format!("Failed to resolve commit ID: {}", e))?,
+                );
+                None
+            }
+        };
+
+        if layer_ids.is_empty() {
+            return Err("No layers to mount".to_string());
+        }
+        // Leave room for the object data layer, and the upperdir of read-only mounts
+        let max_layers = crate::mount::composefs::MAX_LOWER_LAYERS - 2;
+        if layer_ids.len() > max_layers {
+            return Err(format!(
+                "Cannot stack {} layers, the kernel allows at most {}",
+                layer_ids.len(),
+                max_layers
+            ));
+        }
+
+        let canonical_mountpoint = self.prepare_mountpoint(mountpoint)?;
+        if self.is_mounted(mountpoint)? {
+            tracing::info!("Already mounted at {}", mountpoint);
+            return Ok(());
+        }
+
+        // The kernel wants lowerdirs topmost first
+        let mut images = layer_ids
+            .iter()
+            .rev()
+            .map(|cid| self.open_commit_image(cid))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Overlay's verity option applies to all layers at once
+        let verity = images.iter().all(|(_, verity)| *verity);
+        let (top_image, _) = images.remove(0);
+        let lower_images = images.into_iter().map(|(image, _)| image.into()).collect();
+
+        let config = match worktree {
+            Some((label, worktree_name)) => {
+                self.worktree_mount_config(label, worktree_name, top_image)
+            }
+            None => {
+                let source_name = format!("stratum:{}", sref);
+                crate::mount::composefs::ComposeFsConfig::read_only(
+                    top_image.into(),
+                    source_name.clone(),
+                )
+                .with_basedir(PathBuf::from(self.objects_path()))
+                .with_source_name(source_name)
+            }
+        }
+        .with_lower_images(lower_images)
+        .with_verity(verity);
+        let max_size = match worktree {
+            Some((label, worktree_name)) => {
+                self.load_worktree(label, worktree_name)?.worktree.max_size
+            }
+            None => None,
+        };
+        let (config, tmpfs_dir) = self.apply_mount_options(config, options, max_size)?;
+
+        tracing::debug!(
+            "Mounting {} layers at {}: {:?}",
+            layer_ids.len(),
+            canonical_mountpoint.display(),
+            layer_ids
+        );
+        self.mount_composefs_with_tmpfs(&config, &canonical_mountpoint, tmpfs_dir.as_deref())?;
+
+        let stratum_ref = match worktree {
+            Some((label, worktree_name)) => crate::state::StratumMountRef::Worktree {
+                label: label.to_string(),
+                worktree: worktree_name.to_string(),
+            },
+            None => crate::state::StratumMountRef::Snapshot(sref.clone()),
+        };
+        let mounted_stratum = crate::state::MountedStratum {
+            stratum_ref,
+            mount_point: canonical_mountpoint.clone(),
+            read_only: config.upperdir.is_none() || config.read_only,
+            base_commit: layer_ids[0].clone(),
+            layers: layer_ids,
+            options: options.clone(),
+            tmpfs_dir,
+        };
+        self.state_manager
+            .add_mount(canonical_mountpoint.clone(), mounted_stratum)?;
+
+        tracing::info!(
+            "Successfully mounted layered stratum at {}",
+            canonical_mountpoint.display()
+        );
+        Ok(())
+    }
+
+    /// Apply [`MountOptions`] to a composefs configuration
+    ///
+    /// With `tmpfs`, this mounts a fresh tmpfs under the store's temp directory and
+    /// points the upper/workdir at it; its path is returned so it can be torn down
+    /// on unmount. The tmpfs is limited to `max_size` bytes, if given.
+    fn apply_mount_options(
+        &self,
+        config: crate::mount::composefs::ComposeFsConfig,
+        options: &MountOptions,
+        max_size: Option<u64>,
+    ) -> Result<(crate::mount::composefs::ComposeFsConfig, Option<PathBuf>), String> {
+        if options.tmpfs && options.read_only {
+            return Err("A tmpfs-backed mount cannot be read-only".to_string());
+        }
+        if options.volatile && (config.upperdir.is_none() && !options.tmpfs || options.read_only) {
+            return Err("The volatile option requires a writable upperdir".to_string());
+        }
+
+        let mut config = config
+            .with_volatile(options.volatile)
+            .with_read_only(options.read_only)
+            .with_idmap(options.idmap);
+
+        let tmpfs_dir = if options.tmpfs {
+            let tmpfs_dir = tempfile::Builder::new()
+                .prefix("stratum_tmpfs_")
+                .tempdir_in(self.temp_path())
+                .map_err(|e| format!("Failed to create tmpfs directory: {}", e))?
+                .keep();
+            crate::mount::mount_tmpfs_at(&tmpfs_dir, max_size).map_err(|e| {
+                let _ = std::fs::remove_dir(&tmpfs_dir);
+                format!("Failed to mount tmpfs at {}: {}", tmpfs_dir.display(), e)
+            })?;
+
+            let upperdir = tmpfs_dir.join("upperdir");
+            let workdir = tmpfs_dir.join("workdir");
+            if let Err(e) =
+                std::fs::create_dir(&upperdir).and_then(|_| std::fs::create_dir(&workdir))
+            {
+                Self::teardown_tmpfs(&tmpfs_dir);
+                return Err(format!("Failed to set up tmpfs upperdir: {}", e));
+            }
+            tracing::debug!("Using tmpfs upperdir at {}", upperdir.display());
+            config.upperdir = Some(upperdir);
+            config.workdir = Some(workdir);
+            Some(tmpfs_dir)
+        } else {
+            None
+        };
+
+        Ok((config, tmpfs_dir))
+    }
+
+    /// Mount a composefs persistently, tearing down its tmpfs (if any) on failure
+    fn mount_composefs_with_tmpfs(
+        &self,
+        config: &crate::mount::composefs::ComposeFsConfig,
+        mountpoint: &Path,
+        tmpfs_dir: Option<&Path>,
+    ) -> Result<(), String> {
+        crate::mount::composefs::mount_composefs_persistent_at(config, mountpoint).map_err(|e| {
+            if let Some(dir) = tmpfs_dir {
+                Self::teardown_tmpfs(dir);
+            }
+            format!("Failed to mount composefs: {}", e)
+        })
+    }
+
+    /// Unmount a worktree to mount it again with [`Self::remount_worktree`]
+    ///
+    /// Unlike [`Self::unmount_ref`], the tmpfs upperdir of a `--tmpfs` mount is kept.
+    /// Returns how the worktree was mounted.
+    fn unmount_for_remount(
+        &self,
+        mount_point: &Path,
+    ) -> Result<crate::state::MountedStratum, String> {
+        let mounted = self
+            .state_manager
+            .find_mount_by_path(mount_point)?
+            .ok_or_else(|| format!("No mount recorded at {}", mount_point.display()))?;
+        crate::mount::composefs::unmount_composefs_at(mount_point)
+            .map_err(|e| format!("Failed to unmount composefs: {}", e))?;
+        self.state_manager.remove_mount(mount_point)?;
+        Ok(mounted)
+    }
+
+    /// Mount a worktree again the way `mounted` records it was mounted
+    ///
+    /// Stacked layers, options and the tmpfs upperdir of `--tmpfs` mounts are all
+    /// kept. Regular mounts use the worktree's current base, which may have changed
+    /// since.
+    fn remount_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        mut mounted: crate::state::MountedStratum,
+    ) -> Result<(), String> {
+        let layer_ids = if mounted.layers.is_empty() {
+            vec![
+                self.load_worktree(label, worktree_name)?
+                    .base_commit()
+                    .to_string(),
+            ]
+        } else {
+            mounted.layers.clone()
+        };
+        // The kernel wants lowerdirs topmost first
+        let mut images = layer_ids
+            .iter()
+            .rev()
+            .map(|cid| self.open_commit_image(cid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let verity = images.iter().all(|(_, verity)| *verity);
+        let (top_image, _) = images.remove(0);
+        let lower_images = images.into_iter().map(|(image, _)| image.into()).collect();
+
+        let mut config = self
+            .worktree_mount_config(label, worktree_name, top_image)
+            .with_lower_images(lower_images)
+            .with_verity(verity)
+            .with_volatile(mounted.options.volatile)
+            .with_read_only(mounted.read_only)
+            .with_idmap(mounted.options.idmap);
+        if let Some(tmpfs_dir) = &mounted.tmpfs_dir {
+            config.upperdir = Some(tmpfs_dir.join("upperdir"));
+            config.workdir = Some(tmpfs_dir.join("workdir"));
+        }
+
+        // The tmpfs is left alone on failure, since it holds the worktree's changes
+        crate::mount::composefs::mount_composefs_persistent_at(&config, &mounted.mount_point)
+            .map_err(|e| match &mounted.tmpfs_dir {
+                Some(tmpfs_dir) => format!(
+                    "Failed to remount composefs, its changes are left in {}: {}",
+                    tmpfs_dir.display(),
+                    e
+                ),
+                None => format!("Failed to remount composefs: {}", e),
+            })?;
+
+        mounted.stratum_ref = crate::state::StratumMountRef::Worktree {
+            label: label.to_string(),
+            worktree: worktree_name.to_string(),
+        };
+        mounted.base_commit = layer_ids[0].clone();
+        self.state_manager
+            .add_mount(mounted.mount_point.clone(), mounted)
+    }
+
+    /// Unmount and remove a tmpfs created by [`Self::apply_mount_options`]
+    fn teardown_tmpfs(tmpfs_dir: &Path) {
+        if let Err(e) = crate::mount::composefs::unmount_composefs_at(tmpfs_dir) {
+            tracing::warn!("Failed to unmount tmpfs at {}: {}", tmpfs_dir.display(), e);
+            return;
+        }
+        if let Err(e) = std::fs::remove_dir(tmpfs_dir) {
+            tracing::warn!("Failed to remove {}: {}", tmpfs_dir.display(), e);
+        }
+    }
+
+    /// Open the composefs image of a commit, checking it against the store's verity policy
+    ///
+    /// Returns whether the commit is fs-verity protected, in which case the image's
+    /// digest has been checked and mounts should pass `verity=require` to overlay.
+    fn open_commit_image(&self, commit_id: &str) -> Result<(std::fs::File, bool), String> {
+        let commit_file = self.commit_image_path(commit_id);
+        if !Path::new(&commit_file).exists() {
+            return Err(format!("Commit file not found: {}", commit_file));
+        }
+        let image_file = std::fs::File::open(&commit_file)
+            .map_err(|e| format!("Failed to open composefs image {}: {}", commit_file, e))?;
+
+        if self.config.verity == config::VerityPolicy::Off {
+            return Ok((image_file, false));
+        }
+
+        let Some(expected) = self.load_commit(commit_id)?.commit.verity_digest else {
+            if self.config.verity == config::VerityPolicy::Required {
+                return Err(format!(
+                    "Commit {} is not fs-verity protected, but the store requires it",
+                    commit_id
+                ));
+            }
+            return Ok((image_file, false));
+        };
+
+        // Measure the fd we're about to mount, not the path, so the image can't be
+        // swapped in between.
+        let actual = crate::composefs::fsverity::measure_verity(&image_file).map_err(|e| {
+            format!(
+                "Failed to measure fs-verity digest of {}: {}",
+                commit_file, e
+            )
+        })?;
+        if hex::encode(actual) != expected {
+            return Err(format!(
+                "fs-verity digest mismatch for commit {}: expected {}, got {}",
+                commit_id,
+                expected,
+                hex::encode(actual)
+            ));
+        }
+        Ok((image_file, true))
+    }
+
+    /// Build the writable composefs configuration for mounting a worktree
+    /// on top of the given commit image
+    fn worktree_mount_config(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        image_file: std::fs::File,
+    ) -> crate::mount::composefs::ComposeFsConfig {
+        let source_name = format!("stratum:{}+{}", label, worktree_name);
+
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        let workdir = self.worktree_workdir(label, worktree_name);
+        let config = crate::mount::composefs::ComposeFsConfig::writable(
+            image_file.into(),
+            source_name.clone(),
+            std::path::PathBuf::from(upperdir),
+            Some(std::path::PathBuf::from(workdir)),
+        );
+
+        config
+            .with_basedir(std::path::PathBuf::from(self.objects_path()))
+            .with_source_name(source_name)
+    }
+
     /// Temporarily mounts a stratum commit at a mountpoint using an ephemeral mount.
     /// Returns a [`crate::mount::FsHandle`] for the mounted filesystem, which are
     /// automatically unmounted when dropped from memory.
@@ -394,15 +787,8 @@ impl Store {
             .resolve_commit_id(self)
             .map_err(|e| format!("Failed to resolve commit ID: {}", e))?;
 
-        // Get the composefs file for this commit
-        let commit_file = format!("{}/commit.cfs", self.commit_path(&cid));
-        if !Path::new(&commit_file).exists() {
-            return Err(format!("Commit file not found: {}", commit_file));
-        }
-
-        // Open the composefs image file
-        let image_file = std::fs::File::open(&commit_file)
-            .map_err(|e| format!("Failed to open composefs image {}: {}", commit_file, e))?;
+        // Open the composefs image file for this commit
+        let (image_file, verity) = self.open_commit_image(&cid)?;
 
         // Create composefs configuration for ephemeral mount
         let config = crate::mount::composefs::ComposeFsConfig::read_only(
@@ -413,7 +799,8 @@ impl Store {
         tracing::trace!("Creating mount config for stratum ref: {}", sref);
         let config = config
             .with_basedir(std::path::PathBuf::from(self.objects_path()))
-            .with_source_name(sref.to_string());
+            .with_source_name(sref.to_string())
+            .with_verity(verity);
 
         // Mount using native implementation
         tracing::debug!("Mounting read-only composefs at {}", mountpoint);
@@ -484,7 +871,7 @@ impl Store {
         // Safety check: verify the mount is registered in state manager
         let state = self.state_manager.load_state()?;
 
-        if !state.mounts.contains_key(&canonical_mountpoint) {
+        let Some(mounted) = state.mounts.get(&canonical_mountpoint) else {
             tracing::warn!(
                 "Mount at {} not found in state manager, but appears to be mounted",
                 canonical_mountpoint.display()
@@ -493,12 +880,17 @@ impl Store {
                 "Mount at {} is not managed by stratum",
                 canonical_mountpoint.display()
             ));
-        }
+        };
 
         // Use native Rust composefs unmounting implementation
         crate::mount::composefs::unmount_composefs_at(&canonical_mountpoint)
             .map_err(|e| format!("Failed to unmount composefs: {}", e))?;
 
+        // Throwaway upperdirs are discarded along with the mount
+        if let Some(tmpfs_dir) = &mounted.tmpfs_dir {
+            Self::teardown_tmpfs(tmpfs_dir);
+        }
+
         // Remove mount from state manager
         self.state_manager.remove_mount(&canonical_mountpoint)?;
 
@@ -558,6 +950,157 @@ impl Store {
         Ok(())
     }
 
+    /// List the IDs of every commit in the store, including incomplete ones
+    pub fn list_commits(&self) -> Result<Vec<String>, String> {
+        let commits_path = self.commits_path();
+        if !Path::new(&commits_path).exists() {
+            return Ok(Vec::new());
+        }
+        std::fs::read_dir(&commits_path)
+            .map_err(|e| format!("Failed to list commits: {}", e))?
+            .map(|entry| {
+                entry
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+
+    /// Whether a commit was fully written, i.e. has its metadata
+    ///
+    /// Metadata is written last, so commits without it were interrupted.
+    pub fn is_commit_complete(&self, commit_id: &str) -> bool {
+        Path::new(&self.commit_path(commit_id))
+            .join(Self::COMMIT_META_FILE)
+            .exists()
+    }
+
+    /// Remove a commit, whether or not it was fully written
+    pub fn discard_commit(&self, commit_id: &str) -> Result<(), String> {
+        if self.is_commit_complete(commit_id) {
+            return self.delete_commit(commit_id);
+        }
+        tracing::debug!("Removing incomplete commit {}", commit_id);
+        std::fs::remove_dir_all(self.commit_path(commit_id))
+            .map_err(|e| format!("Failed to remove incomplete commit {}: {}", commit_id, e))
+    }
+
+    /// Update the metadata of an existing commit
+    ///
+    /// The commit ID only covers the commit's contents, so this doesn't change it.
+    fn update_commit(
+        &self,
+        commit_id: &str,
+        update: impl FnOnce(&mut crate::commit::Commit),
+    ) -> Result<(), String> {
+        let mut commit = self.load_commit(commit_id)?;
+        update(&mut commit);
+        self.store_commit(commit_id, &commit)
+    }
+
+    /// Replace the patch relations recorded in a commit's metadata
+    pub fn set_patch_relations(
+        &self,
+        commit_id: &str,
+        relations: crate::commit::PatchRelations,
+    ) -> Result<(), String> {
+        self.update_commit(commit_id, |commit| commit.patch = relations)
+    }
+
+    /// Find the patch that was applied on top of a commit's parent to create it
+    ///
+    /// Commits created before patches were recorded don't know theirs, so it's
+    /// recovered by diffing the commit against its parent. The recovered patch is
+    /// stored as a new commit and recorded, so this only happens once.
+    pub fn recover_applied_patch(&self, commit_id: &str) -> Result<String, String> {
+        let commit = self.load_commit(commit_id)?;
+        if let Some(patch) = commit.commit.applied_patch {
+            return Ok(patch);
+        }
+        let parent = commit
+            .commit
+            .parent_commit
+            .ok_or_else(|| format!("Commit {} has no parent", commit_id))?;
+
+        tracing::info!(
+            "Recovering the patch between {} and its parent {}",
+            commit_id,
+            parent
+        );
+        let new_mount = self.new_tempdir();
+        let old_mount = self.new_tempdir();
+        let delta = self.new_tempdir();
+        {
+            let _new = self.mount_ref_ephemeral(
+                &StratumRef::Commit(commit_id.to_string()),
+                &new_mount.path().to_string_lossy(),
+            )?;
+            let _old = self.mount_ref_ephemeral(
+                &StratumRef::Commit(parent.clone()),
+                &old_mount.path().to_string_lossy(),
+            )?;
+            crate::util::write_tree_delta(old_mount.path(), new_mount.path(), delta.path())
+                .map_err(|e| format!("Failed to diff {} against {}: {}", commit_id, parent, e))?;
+        }
+
+        // The patch doesn't belong to any stratum
+        let label = format!("transient_{}", ulid::Ulid::new());
+        let patch =
+            self.commit_directory_bare(&label, &delta.path().to_string_lossy(), None, false)?;
+        std::fs::remove_dir_all(self.ref_path(&label))
+            .map_err(|e| format!("Failed to remove transient ref '{}': {}", label, e))?;
+
+        self.update_commit(commit_id, |commit| {
+            commit.commit.applied_patch = Some(patch.clone())
+        })?;
+        Ok(patch)
+    }
+
+    /// Check whether a commit is tagged, used as a worktree base or stash, or mounted,
+    /// or is in the history of one that is
+    pub fn is_commit_referenced(&self, commit_id: &str) -> Result<bool, String> {
+        Ok(self.referenced_commits()?.contains(commit_id))
+    }
+
+    /// Every commit that is tagged, used as a worktree base or stash, or mounted,
+    /// along with everything they were built from
+    ///
+    /// History is followed through parents, merged parents and applied patches, so
+    /// deleting anything outside this set never breaks `log`, `merge` or
+    /// `patchset extract` of a commit that's still in use.
+    pub fn referenced_commits(&self) -> Result<HashSet<String>, String> {
+        let mut queue = Vec::new();
+        for mounted in self.state_manager.get_all_mounts()?.into_values() {
+            queue.push(mounted.base_commit);
+            queue.extend(mounted.layers);
+        }
+        for label in self.list_all_refs()? {
+            for tag in self.list_tags(&label)? {
+                if let Ok(commit) = self.resolve_tag(&label, &tag) {
+                    queue.push(commit);
+                }
+            }
+        }
+        for (_, wt) in self.list_all_worktrees()? {
+            queue.push(wt.worktree.base_commit);
+            queue.extend(wt.worktree.stash.into_iter().map(|s| s.commit));
+        }
+
+        let mut referenced = HashSet::new();
+        while let Some(id) = queue.pop() {
+            if id.is_empty() || !referenced.insert(id.clone()) {
+                continue;
+            }
+            // Part of the history may already be gone, the rest is still worth keeping
+            let Ok(commit) = self.load_commit(&id) else {
+                continue;
+            };
+            queue.extend(commit.commit.parents().map(String::from));
+            queue.extend(commit.commit.applied_patch);
+        }
+        Ok(referenced)
+    }
+
     /// Register a single object in the object database
     #[tracing::instrument(skip(self, commit_id, object_id), level = "trace")]
     pub fn register_object(&self, commit_id: &str, object_id: &str) -> Result<(), String> {
@@ -672,12 +1215,15 @@ impl Store {
         tracing::trace!("Creating commit metadata");
 
         // Create the commit object
-        let commit = crate::commit::Commit {
+        let mut commit = crate::commit::Commit {
             commit: crate::commit::CommitInfo {
                 merkle_root: hex::encode(merkle_root),
                 metadata_hash: commit_id.clone(),
                 timestamp: chrono::Utc::now(),
                 parent_commit: parent_commit.map(|s| s.to_string()),
+                verity_digest: None,
+                applied_patch: None,
+                merged_parents: Vec::new(),
             },
             files: crate::commit::FileStats {
                 count: file_chunks.len() as u64,
@@ -691,11 +1237,13 @@ impl Store {
                     (file_chunks.len() as f64).log2().ceil() as u32
                 },
             },
+            patch: crate::commit::PatchRelations::default(),
         };
 
         tracing::trace!("Creating commit snapshot");
         // Create composefs file in commit directory
         let file = self.create_composefs_file(&commit_id, dir_path)?;
+        commit.commit.verity_digest = self.enable_commit_verity(&file)?;
 
         if !transient {
             tracing::trace!("Registering objects");
@@ -759,9 +1307,9 @@ impl Store {
     }
 
     pub fn untag(&self, tag: &str, label: &str) -> Result<(), String> {
-        let tag_symlink = format!("{}/{}", Self::TAGS_DIR, tag);
+        let tag_symlink = format!("{}/{}", self.tags_path(label), tag);
 
-        if !Path::new(&tag_symlink).exists() {
+        if std::fs::symlink_metadata(&tag_symlink).is_err() {
             return Err(format!("Tag {}:{} does not exist", label, tag));
         }
 
@@ -1027,9 +1575,148 @@ impl Store {
         Ok(())
     }
 
-    /// Update state manager with mount information after unmounting
-    pub fn remove_mount_from_state(&self, mountpoint: &str) -> Result<(), String> {
-        self.state_manager.remove_mount(Path::new(mountpoint))?;
+    /// Clone a worktree, including its upperdir, into a new worktree
+    ///
+    /// The upperdir is copied with whiteouts, opaque directories and other xattrs
+    /// intact, reflinking files where the filesystem supports it. The new worktree
+    /// gets the same base, description, ignore patterns and size limit, but starts
+    /// with an empty stash. The limit's project quota is left to
+    /// [`Self::apply_worktree_quota`].
+    pub fn fork_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_label: &str,
+        new_worktree_name: &str,
+    ) -> Result<(), String> {
+        let source = self.load_worktree(label, worktree_name)?;
+        if self.worktree_exists(new_label, new_worktree_name) {
+            return Err(format!(
+                "Worktree {}:{} already exists",
+                new_label, new_worktree_name
+            ));
+        }
+        if self.is_worktree_mounted(label, worktree_name)? {
+            tracing::warn!(
+                "Worktree {}:{} is mounted, changes made while forking may be missed",
+                label,
+                worktree_name
+            );
+        }
+
+        self.create_worktree(
+            new_label,
+            new_worktree_name,
+            source.base_commit(),
+            source.worktree.description.clone(),
+        )?;
+        let result = (|| {
+            let upperdir = self.worktree_upperdir(label, worktree_name);
+            let new_upperdir = self.worktree_upperdir(new_label, new_worktree_name);
+            crate::util::apply_upper_layer(Path::new(&upperdir), Path::new(&new_upperdir))
+                .map_err(|e| format!("Failed to copy upperdir {}: {}", upperdir, e))?;
+            if let Err(e) = fsync_all_walk(Path::new(&new_upperdir)) {
+                tracing::warn!("Failed to fsync worktree upperdir {}: {}", new_upperdir, e);
+            }
+
+            let mut forked = self.load_worktree(new_label, new_worktree_name)?;
+            forked.worktree.ignore = source.worktree.ignore.clone();
+            forked.worktree.max_size = source.worktree.max_size;
+            forked.worktree.read_only_over_limit = source.worktree.read_only_over_limit;
+            self.save_worktree_metadata(new_label, &forked)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            // Don't leave a half-copied worktree behind
+            let _ = std::fs::remove_dir_all(self.worktree_path(new_label, new_worktree_name));
+            return Err(e);
+        }
+
+        tracing::info!(
+            "Forked worktree {}:{} into {}:{}",
+            label,
+            worktree_name,
+            new_label,
+            new_worktree_name
+        );
+        Ok(())
+    }
+
+    /// Rename a worktree, possibly moving it to another label
+    ///
+    /// Fails if the worktree is mounted, unless `remount` is set, in which case it's
+    /// unmounted, renamed and mounted again at the same place the same way, see
+    /// [`Self::remount_worktree`].
+    pub fn rename_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_label: &str,
+        new_worktree_name: &str,
+        remount: bool,
+    ) -> Result<(), String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        if self.worktree_exists(new_label, new_worktree_name) {
+            return Err(format!(
+                "Worktree {}:{} already exists",
+                new_label, new_worktree_name
+            ));
+        }
+
+        let mut mounted = None;
+        if let Some(path) = self.get_worktree_mount_path(label, worktree_name)? {
+            if !remount {
+                return Err(format!(
+                    "Worktree {}:{} is currently mounted at {}. Unmount it first, or use --remount.",
+                    label,
+                    worktree_name,
+                    path.display()
+                ));
+            }
+            tracing::info!(
+                "Worktree {}:{} is currently mounted at {}, will unmount and remount after renaming",
+                label,
+                worktree_name,
+                path.display()
+            );
+            mounted = Some(self.unmount_for_remount(&path)?);
+        }
+
+        let old_path = self.worktree_path(label, worktree_name);
+        let new_path = self.worktree_path(new_label, new_worktree_name);
+        std::fs::rename(&old_path, &new_path).map_err(|e| {
+            format!(
+                "Failed to move worktree {} to {}: {}",
+                old_path, new_path, e
+            )
+        })?;
+        worktree.worktree.name = new_worktree_name.to_string();
+        worktree.touch();
+        self.save_worktree_metadata(new_label, &worktree)?;
+
+        tracing::info!(
+            "Renamed worktree {}:{} to {}:{}",
+            label,
+            worktree_name,
+            new_label,
+            new_worktree_name
+        );
+
+        if let Some(mounted) = mounted {
+            tracing::info!(
+                "Remounting worktree {}:{} at {}",
+                new_label,
+                new_worktree_name,
+                mounted.mount_point.display()
+            );
+            self.remount_worktree(new_label, new_worktree_name, mounted)?;
+        }
+        Ok(())
+    }
+
+    /// Update state manager with mount information after unmounting
+    pub fn remove_mount_from_state(&self, mountpoint: &str) -> Result<(), String> {
+        self.state_manager.remove_mount(Path::new(mountpoint))?;
         Ok(())
     }
 
@@ -1057,11 +1744,242 @@ impl Store {
     }
 
     /// Check if a worktree has uncommitted changes
+    ///
+    /// Changes to ignored paths don't count.
     pub fn worktree_has_changes(&self, label: &str, worktree_name: &str) -> Result<bool, String> {
         let worktree = self.load_worktree(label, worktree_name)?;
         let upperdir_string = self.worktree_upperdir(label, worktree_name);
         let upperdir_path = Path::new(&upperdir_string);
-        Ok(worktree.has_uncommitted_changes(upperdir_path))
+        if !worktree.has_uncommitted_changes(upperdir_path) {
+            return Ok(false);
+        }
+
+        self.worktree_ignore_rules(label, worktree_name)?
+            .has_unignored(upperdir_path)
+            .map_err(|e| format!("Failed to read upperdir {}: {}", upperdir_string, e))
+    }
+
+    /// Load the ignore patterns configured for a label in `refs/<label>/meta.toml`
+    pub fn label_ignore_patterns(&self, label: &str) -> Result<Vec<String>, String> {
+        #[derive(serde::Deserialize, Default)]
+        struct LabelMeta {
+            #[serde(default)]
+            label: LabelInfo,
+        }
+        #[derive(serde::Deserialize, Default)]
+        struct LabelInfo {
+            #[serde(default)]
+            ignore: Vec<String>,
+        }
+
+        let meta_path = format!("{}/{}", self.ref_path(label), Self::WORKTREE_META_FILE);
+        if !Path::new(&meta_path).exists() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(&meta_path)
+            .map_err(|e| format!("Failed to read label metadata {}: {}", meta_path, e))?;
+        let meta: LabelMeta = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse label metadata {}: {}", meta_path, e))?;
+        Ok(meta.label.ignore)
+    }
+
+    /// Gather the ignore rules of a worktree
+    ///
+    /// The label's patterns come first, then the worktree's, then those in the
+    /// `.stratumignore` file at the root of its upperdir, so later ones can re-include
+    /// what earlier ones ignore. The ignore file itself is always ignored.
+    pub fn worktree_ignore_rules(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<IgnoreRules, String> {
+        let worktree = self.load_worktree(label, worktree_name)?;
+        let mut rules = IgnoreRules::default();
+        rules.extend(
+            self.label_ignore_patterns(label)?
+                .iter()
+                .map(String::as_str),
+        );
+        rules.extend(worktree.worktree.ignore.iter().map(String::as_str));
+
+        let ignore_file =
+            Path::new(&self.worktree_upperdir(label, worktree_name)).join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            let content = std::fs::read_to_string(&ignore_file)
+                .map_err(|e| format!("Failed to read {}: {}", ignore_file.display(), e))?;
+            rules.extend(content.lines());
+        }
+        rules.extend([format!("/{}", IGNORE_FILE).as_str()]);
+        Ok(rules)
+    }
+
+    /// Copy a worktree's upperdir to `dest`, leaving out ignored paths
+    fn stage_worktree_changes(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        dest: &Path,
+    ) -> Result<(), String> {
+        let rules = self.worktree_ignore_rules(label, worktree_name)?;
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        crate::util::copy_upper_layer_filtered(Path::new(&upperdir), dest, &|path, is_dir| {
+            !rules.is_ignored(path, is_dir)
+        })
+        .map_err(|e| {
+            format!(
+                "Failed to stage changes of {}:{}: {}",
+                label, worktree_name, e
+            )
+        })
+    }
+
+    /// Shelve the changes in a worktree's upperdir and clear it
+    ///
+    /// The upperdir is stored as a commit on top of the worktree's base, whiteouts and
+    /// opaque directories included, and pushed onto the worktree's stash. Returns the
+    /// commit ID.
+    pub fn stash_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        message: Option<String>,
+    ) -> Result<String, String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        // Changing the upperdir under a live overlayfs mount is undefined behaviour
+        if self.is_worktree_mounted(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} is currently mounted. Unmount it first.",
+                label, worktree_name
+            ));
+        }
+        if !self.worktree_has_changes(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} has no changes to stash",
+                label, worktree_name
+            ));
+        }
+
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        let commit_id =
+            self.commit_directory_bare(label, &upperdir, Some(worktree.base_commit()), false)?;
+
+        // Record the stash before clearing anything, so the changes can't get lost
+        worktree.worktree.stash.insert(
+            0,
+            crate::commit::StashEntry {
+                commit: commit_id.clone(),
+                created: chrono::Utc::now(),
+                message,
+            },
+        );
+        worktree.touch();
+        self.save_worktree_metadata(label, &worktree)?;
+
+        std::fs::remove_dir_all(&upperdir)
+            .and_then(|_| std::fs::create_dir_all(&upperdir))
+            .map_err(|e| format!("Failed to clear upperdir {}: {}", upperdir, e))?;
+
+        tracing::info!(
+            "Stashed changes of worktree {}:{} as {}",
+            label,
+            worktree_name,
+            commit_id
+        );
+        Ok(commit_id)
+    }
+
+    /// Reapply a stash entry on top of a worktree's upperdir and drop it
+    ///
+    /// `index` counts from the most recent entry. Fails without changing anything if
+    /// the stash would overwrite changes made in the upperdir since.
+    pub fn pop_worktree_stash(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        index: usize,
+    ) -> Result<(), String> {
+        let worktree = self.load_worktree(label, worktree_name)?;
+        if self.is_worktree_mounted(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} is currently mounted. Unmount it first.",
+                label, worktree_name
+            ));
+        }
+        let entry = Self::stash_entry(&worktree, index)?;
+
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        let stash_mount = self.new_tempdir();
+        {
+            let _handle = self.mount_ref_ephemeral(
+                &StratumRef::Commit(entry.commit.clone()),
+                &stash_mount.path().to_string_lossy(),
+            )?;
+
+            let conflicts =
+                crate::util::upper_layer_conflicts(stash_mount.path(), Path::new(&upperdir))
+                    .map_err(|e| format!("Failed to compare stash with upperdir: {}", e))?;
+            if !conflicts.is_empty() {
+                return Err(format!(
+                    "Stash {} conflicts with changes in {}:{}:\n  {}",
+                    index,
+                    label,
+                    worktree_name,
+                    conflicts
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n  ")
+                ));
+            }
+
+            crate::util::apply_upper_layer(stash_mount.path(), Path::new(&upperdir))
+                .map_err(|e| format!("Failed to apply stash to {}: {}", upperdir, e))?;
+        }
+
+        tracing::info!(
+            "Restored stash {} of worktree {}:{}",
+            entry.commit,
+            label,
+            worktree_name
+        );
+        self.drop_worktree_stash(label, worktree_name, index)
+    }
+
+    /// Remove a stash entry, deleting its commit unless something else references it
+    pub fn drop_worktree_stash(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        index: usize,
+    ) -> Result<(), String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        let entry = Self::stash_entry(&worktree, index)?.clone();
+        worktree.worktree.stash.remove(index);
+        self.save_worktree_metadata(label, &worktree)?;
+
+        if !self.is_commit_referenced(&entry.commit)? {
+            self.delete_commit(&entry.commit)?;
+        }
+        Ok(())
+    }
+
+    fn stash_entry(
+        worktree: &Worktree,
+        index: usize,
+    ) -> Result<&crate::commit::StashEntry, String> {
+        worktree
+            .worktree
+            .stash
+            .get(index)
+            .ok_or_else(|| match worktree.worktree.stash.len() {
+                0 => format!("Worktree {} has no stashed changes", worktree.name()),
+                len => format!(
+                    "Stash {} does not exist, worktree {} has {} entries",
+                    index,
+                    worktree.name(),
+                    len
+                ),
+            })
     }
 
     /// Get the worktree name for a mount point using the state manager
@@ -1090,16 +2008,13 @@ impl Store {
         Ok(None)
     }
 
-    /// Rebase a worktree to a new base commit
-    pub fn rebase_worktree(
+    /// Resolve the commit a worktree should be rebased onto
+    fn resolve_rebase_target(
         &self,
         label: &str,
-        worktree_name: &str,
+        current_worktree: &Worktree,
         new_base_commit: &StratumRef,
-    ) -> Result<(), String> {
-        // Load the existing worktree metadata
-        let mut current_worktree = self.load_worktree(label, worktree_name)?;
-
+    ) -> Result<String, String> {
         let new_base = match new_base_commit {
             StratumRef::Tag(id) => self.resolve_tag(label, id)?,
             StratumRef::Commit(id) => id.clone(),
@@ -1110,36 +2025,51 @@ impl Store {
 
                 let loaded_worktree = self.load_worktree(label, worktree)?;
 
-                if current_worktree == loaded_worktree {
+                if current_worktree == &loaded_worktree {
                     return Err("cannot rebase a worktree onto itself".to_string());
                 }
                 loaded_worktree.base_commit().to_string()
             }
         };
+        Ok(new_base)
+    }
 
-        // Check if worktree is being mounted, if so remember the mount point and unmount it
-        let mut mount_path = None;
-        if self.is_worktree_mounted(label, worktree_name)? {
-            // Get the mount path before unmounting
-            mount_path = self.get_worktree_mount_path(label, worktree_name)?;
-
-            if let Some(path) = &mount_path {
-                tracing::info!(
-                    "Worktree {}:{} is currently mounted at {}, will unmount and remount after rebase",
-                    label,
-                    worktree_name,
-                    path.display()
-                );
+    /// Rebase a worktree to a new base commit
+    ///
+    /// Paths the upperdir overrides that changed between the bases are resolved
+    /// according to `options`, see [`rebase`]. Nothing changes if any are left
+    /// unresolved.
+    pub fn rebase_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_base_commit: &StratumRef,
+        options: &rebase::RebaseOptions,
+    ) -> Result<(), String> {
+        // Load the existing worktree metadata
+        let mut current_worktree = self.load_worktree(label, worktree_name)?;
 
-                // Unmount the worktree
-                self.unmount_ref(&path.to_string_lossy())?;
-            }
-        }
+        let new_base = self.resolve_rebase_target(label, &current_worktree, new_base_commit)?;
 
         // Verify the new base commit exists
         if !self.commit_exists(&new_base) {
             return Err(format!("New base commit {} does not exist", new_base));
         }
+        let take_base = self.resolve_rebase_conflicts(label, worktree_name, &new_base, options)?;
+
+        // Check if worktree is being mounted, if so remember how and unmount it
+        let mut mounted = None;
+        if let Some(path) = self.get_worktree_mount_path(label, worktree_name)? {
+            tracing::info!(
+                "Worktree {}:{} is currently mounted at {}, will unmount and remount after rebase",
+                label,
+                worktree_name,
+                path.display()
+            );
+            mounted = Some(self.unmount_for_remount(&path)?);
+        }
+
+        self.take_base_versions(label, worktree_name, &take_base)?;
 
         // Update the worktree's base commit
         current_worktree.set_base_commit(new_base.to_string());
@@ -1153,27 +2083,195 @@ impl Store {
         );
 
         // Remount the worktree if it was mounted before
-        if let Some(path) = mount_path {
+        if let Some(mounted) = mounted {
             tracing::info!(
                 "Remounting worktree {}:{} at {}",
                 label,
                 worktree_name,
-                path.display()
+                mounted.mount_point.display()
             );
+            self.remount_worktree(label, worktree_name, mounted)?;
+        }
 
-            // Create a StratumRef for the worktree
-            let sref = StratumRef::Worktree {
-                label: label.to_string(),
-                worktree: worktree_name.to_string(),
-            };
+        Ok(())
+    }
+
+    /// Rebase a mounted worktree onto a new base commit without unmounting it
+    ///
+    /// The new composefs mount is built detached and swapped onto the mountpoint,
+    /// see [`crate::mount::composefs::swap_composefs_at`]. Before swapping, this checks
+    /// that no process has files open (or its cwd) in a directory whose contents differ
+    /// between the old and new base, since those would keep seeing the old layer.
+    ///
+    /// If the worktree is not mounted, this is equivalent to [`Self::rebase_worktree`].
+    /// Otherwise the upperdir can't be changed, so conflicts can only be resolved by
+    /// keeping the worktree's version. The new mount keeps the options (and tmpfs
+    /// upperdir) the old one was made with. Mounts of stacked layers don't use the
+    /// worktree's base, so they can't be live rebased.
+    pub fn rebase_worktree_live(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_base_commit: &StratumRef,
+        options: &rebase::RebaseOptions,
+    ) -> Result<(), String> {
+        let mut current_worktree = self.load_worktree(label, worktree_name)?;
+        let new_base = self.resolve_rebase_target(label, &current_worktree, new_base_commit)?;
+
+        let Some(mount_path) = self.get_worktree_mount_path(label, worktree_name)? else {
+            tracing::info!(
+                "Worktree {}:{} is not mounted, doing a regular rebase",
+                label,
+                worktree_name
+            );
+            return self.rebase_worktree(label, worktree_name, new_base_commit, options);
+        };
 
-            // Remount the worktree at the same path
-            self.mount_ref(&sref, &path.to_string_lossy(), Some(worktree_name))?;
+        if !self.commit_exists(&new_base) {
+            return Err(format!("New base commit {} does not exist", new_base));
+        }
+        let take_base = self.resolve_rebase_conflicts(label, worktree_name, &new_base, options)?;
+        if !take_base.is_empty() {
+            return Err(format!(
+                "Cannot live rebase {}:{}: taking the base's version of {} changes the upperdir, which needs a regular rebase",
+                label,
+                worktree_name,
+                take_base
+                    .iter()
+                    .map(|c| c.path.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        let old_base = current_worktree.base_commit().to_string();
+        let busy = self.live_rebase_conflicts(&mount_path, &old_base, &new_base)?;
+        if !busy.is_empty() {
+            let paths = busy
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(format!(
+                "Cannot live rebase {}:{}: files are open in directories changed by the new base: {}",
+                label, worktree_name, paths
+            ));
+        }
+
+        let mounted = self
+            .state_manager
+            .find_mount_by_path(&mount_path)?
+            .ok_or_else(|| format!("No mount recorded at {}", mount_path.display()))?;
+        if !mounted.layers.is_empty() {
+            return Err(format!(
+                "Cannot live rebase {}:{}: it's mounted with stacked layers, which don't use its base",
+                label, worktree_name
+            ));
         }
 
+        // Rebuild the mount the way it was made, on top of the new base
+        let (image_file, verity) = self.open_commit_image(&new_base)?;
+        let mut config = self
+            .worktree_mount_config(label, worktree_name, image_file)
+            .with_verity(verity)
+            .with_volatile(mounted.options.volatile)
+            .with_read_only(mounted.read_only)
+            .with_idmap(mounted.options.idmap);
+        if let Some(tmpfs_dir) = &mounted.tmpfs_dir {
+            config.upperdir = Some(tmpfs_dir.join("upperdir"));
+            config.workdir = Some(tmpfs_dir.join("workdir"));
+        }
+
+        // Metadata first, so it's put back if the swap fails rather than disagreeing
+        // with the mount
+        current_worktree.set_base_commit(new_base.clone());
+        self.save_worktree_metadata(label, &current_worktree)?;
+        if let Err(e) = crate::mount::composefs::swap_composefs_at(&config, &mount_path) {
+            current_worktree.set_base_commit(old_base);
+            if let Err(e) = self.save_worktree_metadata(label, &current_worktree) {
+                tracing::error!(
+                    "Failed to restore the base of worktree {}:{}: {}",
+                    label,
+                    worktree_name,
+                    e
+                );
+            }
+            return Err(format!(
+                "Failed to swap mount at {}: {}",
+                mount_path.display(),
+                e
+            ));
+        }
+
+        self.state_manager.transaction(|state| {
+            if let Some(mounted) = state.mounts.get_mut(&mount_path) {
+                mounted.base_commit = new_base.clone();
+            }
+            Ok(())
+        })?;
+
+        tracing::info!(
+            "Live rebased worktree {}:{} at {} to new base commit {}",
+            label,
+            worktree_name,
+            mount_path.display(),
+            new_base
+        );
         Ok(())
     }
 
+    /// Find open paths under a mounted worktree that would be affected by swapping
+    /// the lower layer from `old_base` to `new_base`
+    ///
+    /// Only the directories that actually have open files in them are compared,
+    /// by mounting both bases ephemerally and comparing their entries.
+    fn live_rebase_conflicts(
+        &self,
+        mount_path: &Path,
+        old_base: &str,
+        new_base: &str,
+    ) -> Result<Vec<PathBuf>, String> {
+        let open_paths = crate::mount::open_paths_under(mount_path)
+            .map_err(|e| format!("Failed to scan open files: {}", e))?;
+        if open_paths.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let old_mount = self.new_tempdir();
+        let new_mount = self.new_tempdir();
+        let _old_handle = self.mount_ref_ephemeral(
+            &StratumRef::Commit(old_base.to_string()),
+            &old_mount.path().to_string_lossy(),
+        )?;
+        let _new_handle = self.mount_ref_ephemeral(
+            &StratumRef::Commit(new_base.to_string()),
+            &new_mount.path().to_string_lossy(),
+        )?;
+
+        let mut conflicts = Vec::new();
+        for open_path in open_paths {
+            let rel = open_path.strip_prefix(mount_path).unwrap_or(&open_path);
+            // An open directory (usually a cwd) is affected by its own entries,
+            // an open file by its siblings.
+            let dir = if open_path.is_dir() {
+                rel.to_path_buf()
+            } else {
+                rel.parent().map(Path::to_path_buf).unwrap_or_default()
+            };
+
+            if crate::util::dir_entries_differ(
+                &old_mount.path().join(&dir),
+                &new_mount.path().join(&dir),
+            ) {
+                tracing::debug!(?open_path, "Open path is in a directory changed by rebase");
+                conflicts.push(open_path);
+            }
+        }
+
+        conflicts.sort();
+        Ok(conflicts)
+    }
+
     // == End Worktree Management ==
 
     /*
@@ -1386,6 +2484,7 @@ impl Store {
     /// * `label` - The label (namespace) for this commit
     /// * `dir_path` - Path to the patch directory (upperdir)
     /// * `base_commit` - The base commit to patch on top of
+    /// * `applied_patch` - The commit `dir_path` holds, if it's a stored patch
     /// * `transient` - Whether this is a transient commit
     ///
     /// # Returns
@@ -1395,6 +2494,7 @@ impl Store {
         label: &str,
         dir_path: &str,
         base_commit: &str,
+        applied_patch: Option<&str>,
         transient: bool,
     ) -> Result<String, String> {
         // Verify the base commit exists
@@ -1596,6 +2696,12 @@ impl Store {
             result?
         };
 
+        if let Some(patch) = applied_patch {
+            self.update_commit(&commit_id, |commit| {
+                commit.commit.applied_patch = Some(patch.to_string())
+            })?;
+        }
+
         tracing::info!("Imported bare directory on top of commit {}", base_commit);
         tracing::info!("New commit: {}", commit_id);
 
@@ -1800,12 +2906,15 @@ impl Store {
         let total_size = crate::util::calculate_total_size(dir_path)?;
 
         // Create the commit object with pre-computed data
-        let commit = crate::commit::Commit {
+        let mut commit = crate::commit::Commit {
             commit: crate::commit::CommitInfo {
                 merkle_root: hex::encode(merkle_root),
                 metadata_hash: commit_id.clone(),
                 timestamp: chrono::Utc::now(),
                 parent_commit: parent_commit.map(|s| s.to_string()),
+                verity_digest: None,
+                applied_patch: None,
+                merged_parents: Vec::new(),
             },
             files: crate::commit::FileStats {
                 count: file_chunks.len() as u64,
@@ -1819,10 +2928,12 @@ impl Store {
                     (file_chunks.len() as f64).log2().ceil() as u32
                 },
             },
+            patch: crate::commit::PatchRelations::default(),
         };
 
         // Create composefs file in commit directory
         let file = self.create_composefs_file(&commit_id, dir_path)?;
+        commit.commit.verity_digest = self.enable_commit_verity(&file)?;
 
         if !transient {
             self.register_objects(&commit_id, &file)?;
@@ -1881,6 +2992,14 @@ impl Store {
         .map_err(|e| format!("Failed to fsync directory {}: {}", dir_path, e))?;
         let commit_file = format!("{}/{}", self.commit_path(commit_id), Self::COMMIT_FILE);
 
+        // Images with fs-verity enabled can't be opened for writing again, so replace
+        // instead of overwriting when re-importing the same commit
+        if Path::new(&commit_file).exists() {
+            std::fs::remove_file(&commit_file).map_err(|e| {
+                format!("Failed to remove old composefs file {}: {}", commit_file, e)
+            })?;
+        }
+
         let output = std::process::Command::new("mkcomposefs")
             .arg(format!("--digest-store={}", self.objects_path()))
             .arg(dir_path)
@@ -1908,6 +3027,50 @@ impl Store {
         Ok(commit_file)
     }
 
+    /// Enable fs-verity on a commit's image and all of its objects, following the
+    /// store's [`config::VerityPolicy`]
+    ///
+    /// Returns the image's fs-verity digest if the whole commit is now protected.
+    fn enable_commit_verity(&self, commit_file: &str) -> Result<Option<String>, String> {
+        use crate::composefs::fsverity;
+        use config::VerityPolicy;
+
+        let policy = self.config.verity;
+        if policy == VerityPolicy::Off {
+            return Ok(None);
+        }
+
+        let objects = self.composefs_info_objects(commit_file)?;
+        let paths = objects
+            .iter()
+            .map(|object| format!("{}/{}", self.objects_path(), object))
+            .chain(std::iter::once(commit_file.to_string()));
+        for path in paths {
+            if let Err(e) = fsverity::enable_verity(Path::new(&path)) {
+                if policy == VerityPolicy::Required {
+                    return Err(format!("Failed to enable fs-verity on {}: {}", path, e));
+                }
+                tracing::debug!(
+                    "Could not enable fs-verity on {} ({}), continuing without it",
+                    path,
+                    e
+                );
+                return Ok(None);
+            }
+        }
+
+        let image = std::fs::File::open(commit_file)
+            .map_err(|e| format!("Failed to open {}: {}", commit_file, e))?;
+        let digest = fsverity::measure_verity(&image).map_err(|e| {
+            format!(
+                "Failed to measure fs-verity digest of {}: {}",
+                commit_file, e
+            )
+        })?;
+        tracing::debug!("Enabled fs-verity for {}", commit_file);
+        Ok(Some(hex::encode(digest)))
+    }
+
     /// Calls `composefs-info objects` to get lists of objects in a commit
     fn composefs_info_objects(&self, file: &str) -> Result<Vec<String>, String> {
         let output = std::process::Command::new("composefs-info")
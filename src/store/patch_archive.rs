@@ -0,0 +1,116 @@
+//! Portable patch archives
+//!
+//! A worktree's changes can be shared without its base by exporting the upperdir
+//! as a tarball, e.g. `profile.tar.zst`. Archives can't carry overlayfs whiteouts
+//! (character devices) or opaque directories (trusted xattrs) portably, so these are
+//! converted to the `.wh.<name>` and `.wh..wh..opq` files bare patches use, and back
+//! when the archive is imported with `import --patch`.
+//!
+//! Archives are written and read with GNU tar, compressed according to the archive's
+//! extension. Extended attributes (capabilities, SELinux labels and the like) are
+//! kept, except overlay's own: extracted archives become overlay upper layers, where
+//! a crafted `trusted.overlay.redirect` could expose arbitrary paths of the base.
+//! Archives may come from other machines, so ownership isn't restored on import:
+//! files belong to the importing user, and lose their setuid and setgid bits.
+
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::TempDir;
+
+use super::Store;
+use crate::util::{whiteouts_from_portable, whiteouts_to_portable};
+
+impl Store {
+    /// Export a worktree's changes as a patch archive at `output`
+    ///
+    /// Ignored paths are left out, as they would be when committing the worktree.
+    pub fn export_worktree_patch(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        output: &Path,
+    ) -> Result<(), String> {
+        if !self.worktree_has_changes(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} has no changes to export",
+                label, worktree_name
+            ));
+        }
+        if self.is_worktree_mounted(label, worktree_name)? {
+            tracing::warn!(
+                "Worktree {}:{} is mounted, changes made during the export may be missed",
+                label,
+                worktree_name
+            );
+        }
+
+        let staging = self.new_tempdir();
+        let staged = staging.path().join("patch");
+        self.stage_worktree_changes(label, worktree_name, &staged)?;
+        whiteouts_to_portable(&staged)
+            .map_err(|e| format!("Failed to convert whiteouts for export: {}", e))?;
+
+        run_tar(
+            Command::new("tar")
+                .arg("--create")
+                .arg("--auto-compress")
+                .arg("--numeric-owner")
+                .arg("--xattrs")
+                .arg("--xattrs-include=*")
+                // Overlay's own bookkeeping means nothing outside this upperdir
+                .arg("--xattrs-exclude=trusted.overlay.*")
+                .arg("--file")
+                .arg(output)
+                .arg("--directory")
+                .arg(&staged)
+                .arg("."),
+        )?;
+        tracing::info!(
+            "Exported worktree {}:{} to {}",
+            label,
+            worktree_name,
+            output.display()
+        );
+        Ok(())
+    }
+
+    /// Extract a patch archive into a temporary directory, as an upperdir
+    pub fn extract_patch_archive(&self, archive: &Path) -> Result<TempDir, String> {
+        let extracted = self.new_tempdir();
+        run_tar(
+            Command::new("tar")
+                .arg("--extract")
+                .arg("--no-same-owner")
+                .arg("--xattrs")
+                .arg("--xattrs-include=*")
+                .arg("--xattrs-exclude=trusted.overlay.*")
+                .arg("--file")
+                .arg(archive)
+                .arg("--directory")
+                .arg(extracted.path()),
+        )?;
+        whiteouts_from_portable(extracted.path()).map_err(|e| {
+            format!(
+                "Failed to convert whiteouts of {}: {}",
+                archive.display(),
+                e
+            )
+        })?;
+        Ok(extracted)
+    }
+}
+
+fn run_tar(command: &mut Command) -> Result<(), String> {
+    tracing::debug!("Running {:?}", command);
+    let output = command
+        .output()
+        .map_err(|e| format!("Failed to run tar: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
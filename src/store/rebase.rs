@@ -0,0 +1,292 @@
+//! Conflict detection for worktree rebases
+//!
+//! Rebasing a worktree swaps the lower layer under its upperdir. Anything the
+//! upperdir overrides that also changed between the old and new base ends up
+//! shadowing the new version: files keep their stale copy, and whiteouts or opaque
+//! directories may hide files the new base added. These are the conflicts.
+//!
+//! Ignored paths (see [`crate::ignore`]) are local state like caches and logs that
+//! never gets committed, so they're not reported as conflicts.
+//!
+//! Each conflict is resolved by keeping the upperdir's version, or by taking the
+//! base's version (removing the entry from the upperdir). Resolutions can be given
+//! per path in a TOML file:
+//!
+//! ```toml
+//! [resolutions]
+//! "Data/config.ini" = "keep-upper"
+//! "saves" = "take-base"
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::Store;
+use crate::composefs::erofs::{ErofsImage, OverlayEntryKind};
+use crate::ignore::IgnoreRules;
+
+/// How to resolve a rebase conflict
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Resolution {
+    /// Keep the upperdir's version, shadowing the new base
+    KeepUpper,
+    /// Drop the upperdir's version, so the new base's shows through
+    TakeBase,
+}
+
+/// A path the upperdir overrides that also changed between the old and new base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebaseConflict {
+    /// Path relative to the upperdir
+    pub path: String,
+    /// What the upperdir holds at the path: a file, a whiteout or an opaque directory
+    pub kind: OverlayEntryKind,
+}
+
+impl fmt::Display for RebaseConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            OverlayEntryKind::Whiteout => {
+                write!(f, "{} (deleted, but changed in the new base)", self.path)
+            }
+            OverlayEntryKind::OpaqueDirectory => {
+                write!(f, "{} (opaque, hides changes in the new base)", self.path)
+            }
+            _ => write!(f, "{} (modified, but changed in the new base)", self.path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebaseOptions {
+    /// Resolution for conflicts without a per-path one, or `None` to abort on them
+    pub policy: Option<Resolution>,
+    /// Resolutions by path, relative to the upperdir
+    pub resolutions: BTreeMap<String, Resolution>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResolutionFile {
+    #[serde(default)]
+    resolutions: BTreeMap<String, Resolution>,
+}
+
+impl RebaseOptions {
+    /// Load per-path resolutions from a TOML file
+    pub fn load_resolutions(path: &Path) -> Result<BTreeMap<String, Resolution>, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read resolution file {}: {}", path.display(), e))?;
+        let file: ResolutionFile = toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse resolution file {}: {}", path.display(), e))?;
+        Ok(file.resolutions)
+    }
+}
+
+impl Store {
+    /// Find the paths a worktree's upperdir overrides that differ between its base
+    /// and `new_base`
+    ///
+    /// The bases are compared through their composefs images, without mounting them.
+    pub fn rebase_conflicts(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_base: &str,
+    ) -> Result<Vec<RebaseConflict>, String> {
+        let worktree = self.load_worktree(label, worktree_name)?;
+        let old_base = worktree.base_commit();
+        if old_base == new_base {
+            return Ok(Vec::new());
+        }
+
+        let read_image = |commit_id: &str| {
+            let image_path = self.commit_image_path(commit_id);
+            std::fs::read(&image_path)
+                .map_err(|e| format!("Failed to read composefs image {}: {}", image_path, e))
+        };
+        let old_image = read_image(old_base)?;
+        let new_image = read_image(new_base)?;
+        let changed = ErofsImage::from_bytes(&old_image)
+            .changed_paths(&ErofsImage::from_bytes(&new_image))
+            .into_iter()
+            .collect::<BTreeSet<_>>();
+
+        let rules = self.worktree_ignore_rules(label, worktree_name)?;
+        let mut conflicts = Vec::new();
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        collect_conflicts(Path::new(&upperdir), "", &changed, &rules, &mut conflicts)
+            .map_err(|e| format!("Failed to read upperdir {}: {}", upperdir, e))?;
+        Ok(conflicts)
+    }
+
+    /// Check a rebase's conflicts against `options`, returning those to resolve by
+    /// taking the base's version
+    ///
+    /// Fails, listing them, if any conflict has no resolution.
+    pub(super) fn resolve_rebase_conflicts(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        new_base: &str,
+        options: &RebaseOptions,
+    ) -> Result<Vec<RebaseConflict>, String> {
+        let conflicts = self.rebase_conflicts(label, worktree_name, new_base)?;
+        let mut take_base = Vec::new();
+        let mut unresolved = Vec::new();
+        for conflict in conflicts {
+            match options
+                .resolutions
+                .get(&conflict.path)
+                .copied()
+                .or(options.policy)
+            {
+                Some(Resolution::KeepUpper) => {
+                    tracing::info!("Keeping the worktree's version of {}", conflict.path)
+                }
+                Some(Resolution::TakeBase) => take_base.push(conflict),
+                None => unresolved.push(conflict),
+            }
+        }
+
+        if !unresolved.is_empty() {
+            return Err(format!(
+                "Rebasing {}:{} onto {} conflicts with the worktree's changes:\n  {}\nResolve them with --keep-upper, --take-base or a resolution file",
+                label,
+                worktree_name,
+                new_base,
+                unresolved
+                    .iter()
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n  ")
+            ));
+        }
+        Ok(take_base)
+    }
+
+    /// Drop the upperdir's version of conflicting paths, so the base's shows through
+    ///
+    /// The worktree must not be mounted. Opaque directories are made transparent
+    /// rather than removed, keeping the files in them.
+    pub(super) fn take_base_versions(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        conflicts: &[RebaseConflict],
+    ) -> Result<(), String> {
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        for conflict in conflicts {
+            let path = Path::new(&upperdir).join(&conflict.path);
+            tracing::info!("Taking the base's version of {}", conflict.path);
+            let result = match conflict.kind {
+                OverlayEntryKind::OpaqueDirectory => crate::util::clear_opaque_dir(&path),
+                _ => std::fs::remove_file(&path),
+            };
+            result.map_err(|e| format!("Failed to resolve {}: {}", conflict.path, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Walk an upperdir, collecting the entries overriding any of the `changed` paths
+///
+/// Ignored entries are skipped, along with everything beneath ignored directories.
+fn collect_conflicts(
+    dir: &Path,
+    prefix: &str,
+    changed: &BTreeSet<String>,
+    rules: &IgnoreRules,
+    conflicts: &mut Vec<RebaseConflict>,
+) -> std::io::Result<()> {
+    let changed_at_or_under = |path: &str| {
+        changed.contains(path)
+            || changed
+                .range(format!("{}/", path)..)
+                .next()
+                .is_some_and(|p| p.starts_with(&format!("{}/", path)))
+    };
+
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        if rules.is_ignored(Path::new(&path), metadata.is_dir()) {
+            continue;
+        }
+
+        if metadata.is_dir() {
+            if crate::util::is_opaque_dir(&entry.path()) {
+                if changed_at_or_under(&path) {
+                    conflicts.push(RebaseConflict {
+                        path,
+                        kind: OverlayEntryKind::OpaqueDirectory,
+                    });
+                }
+            } else {
+                collect_conflicts(
+                    &entry.path(),
+                    &format!("{}/", path),
+                    changed,
+                    rules,
+                    conflicts,
+                )?;
+            }
+        } else if changed_at_or_under(&path) {
+            let kind = if crate::util::is_whiteout(&metadata) {
+                OverlayEntryKind::Whiteout
+            } else {
+                OverlayEntryKind::File
+            };
+            conflicts.push(RebaseConflict { path, kind });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_conflicts() {
+        let upper = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(upper.path().join("Data/textures")).unwrap();
+        std::fs::write(upper.path().join("Data/config.ini"), b"mine").unwrap();
+        std::fs::write(upper.path().join("Data/textures/sky.dds"), b"mine").unwrap();
+        std::fs::write(upper.path().join("saves"), b"replaced a directory").unwrap();
+        std::fs::create_dir_all(upper.path().join("Data/logs")).unwrap();
+        std::fs::write(upper.path().join("Data/logs/game.log"), b"ignored").unwrap();
+
+        let changed = [
+            "Data/config.ini",
+            "Data/new.esp",
+            "saves/slot1",
+            "Data/logs/game.log",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let rules = IgnoreRules::parse("logs/");
+        let mut conflicts = Vec::new();
+        collect_conflicts(upper.path(), "", &changed, &rules, &mut conflicts).unwrap();
+
+        assert_eq!(
+            conflicts,
+            vec![
+                RebaseConflict {
+                    path: "Data/config.ini".to_string(),
+                    kind: OverlayEntryKind::File,
+                },
+                RebaseConflict {
+                    path: "saves".to_string(),
+                    kind: OverlayEntryKind::File,
+                },
+            ]
+        );
+    }
+}
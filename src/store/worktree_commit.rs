@@ -0,0 +1,268 @@
+//! Committing worktrees
+//!
+//! A worktree is committed by applying its upperdir on top of its base, leaving out
+//! ignored paths. Commits can also be limited to selected paths of the upperdir,
+//! taking everything beneath them (whiteouts included), so the save files can be
+//! committed while config tweaks stay pending. With [`CommitOptions::rebase`], the
+//! committed entries are then moved out of the upperdir by rebasing the worktree
+//! onto the new commit.
+
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::Store;
+use crate::ignore::IgnoreRules;
+use crate::util::{clear_opaque_dir, copy_upper_layer_filtered, is_opaque_dir};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitOptions {
+    /// Paths of the upperdir to commit, relative to its root, or everything if empty
+    pub paths: Vec<String>,
+    /// Remove the committed entries from the upperdir and rebase the worktree onto
+    /// the new commit, so only the remaining changes stay pending
+    pub rebase: bool,
+}
+
+impl Store {
+    /// Commit a worktree's changes on top of its base, returning the new commit's ID
+    ///
+    /// Ignored paths are left out of the commit, but stay in the upperdir. Unless
+    /// `options.rebase` is set, the worktree itself is left as it is, still based on
+    /// the old commit.
+    pub fn commit_worktree(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        options: &CommitOptions,
+    ) -> Result<String, String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        if options.rebase && self.is_worktree_mounted(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} is currently mounted. Unmount it first.",
+                label, worktree_name
+            ));
+        }
+        if !self.worktree_has_changes(label, worktree_name)? {
+            return Err(format!(
+                "Worktree {}:{} has no changes to commit",
+                label, worktree_name
+            ));
+        }
+
+        let upperdir = PathBuf::from(self.worktree_upperdir(label, worktree_name));
+        let rules = self.worktree_ignore_rules(label, worktree_name)?;
+        let selected = select_paths(&upperdir, &options.paths)?;
+
+        // The upperdir is used as an overlay layer while committing, so work on a copy
+        let staging = self.new_tempdir();
+        let staged = staging.path().join("upper");
+        copy_upper_layer_filtered(&upperdir, &staged, &|path, is_dir| {
+            is_selected(&selected, path) && !rules.is_ignored(path, is_dir)
+        })
+        .map_err(|e| {
+            format!(
+                "Failed to stage changes of {}:{}: {}",
+                label, worktree_name, e
+            )
+        })?;
+        if std::fs::read_dir(&staged)
+            .map_err(|e| e.to_string())?
+            .next()
+            .is_none()
+        {
+            return Err(format!(
+                "Nothing to commit in {}:{}, the selected paths are all ignored",
+                label, worktree_name
+            ));
+        }
+
+        let commit_id = self.union_patch_commit(
+            label,
+            &staged.to_string_lossy(),
+            worktree.base_commit(),
+            None,
+            false,
+        )?;
+
+        if options.rebase {
+            // Metadata first, so an interrupted cleanup only leaves redundant copies in
+            // the upperdir, rather than changes the base doesn't have
+            worktree.set_base_commit(commit_id.clone());
+            worktree.mark_committed();
+            self.save_worktree_metadata(label, &worktree)?;
+
+            let roots = if selected.is_empty() {
+                vec![PathBuf::new()]
+            } else {
+                selected
+            };
+            for path in &roots {
+                remove_committed(&upperdir, path, &rules)
+                    .and_then(|_| remove_empty_parents(&upperdir, path))
+                    .map_err(|e| {
+                        format!(
+                            "Failed to remove committed entry {} from the upperdir: {}",
+                            path.display(),
+                            e
+                        )
+                    })?;
+            }
+            tracing::info!(
+                "Rebased worktree {}:{} onto {}",
+                label,
+                worktree_name,
+                commit_id
+            );
+        } else {
+            self.mark_worktree_committed(label, worktree_name)?;
+        }
+
+        tracing::info!(
+            "Committed worktree {}:{} as {}",
+            label,
+            worktree_name,
+            commit_id
+        );
+        Ok(commit_id)
+    }
+}
+
+/// Normalize the paths selected for a commit and check they can be committed on their
+/// own
+///
+/// Each must exist in the upperdir, and can't be inside an opaque directory, since
+/// the rest of that directory would have to be committed along with it.
+fn select_paths(upperdir: &Path, paths: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut selected = Vec::new();
+    for path in paths {
+        let mut normalized = PathBuf::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => normalized.push(name),
+                Component::RootDir | Component::CurDir => {}
+                _ => {
+                    return Err(format!(
+                        "Invalid path {}, must be inside the worktree",
+                        path
+                    ));
+                }
+            }
+        }
+        if normalized.as_os_str().is_empty() {
+            // The whole upperdir
+            return Ok(Vec::new());
+        }
+        if std::fs::symlink_metadata(upperdir.join(&normalized)).is_err() {
+            return Err(format!("{} has no changes in the worktree", path));
+        }
+        for ancestor in normalized.ancestors().skip(1) {
+            if !ancestor.as_os_str().is_empty() && is_opaque_dir(&upperdir.join(ancestor)) {
+                return Err(format!(
+                    "{} is inside the opaque directory {}, select that instead",
+                    path,
+                    ancestor.display()
+                ));
+            }
+        }
+        selected.push(normalized);
+    }
+    Ok(selected)
+}
+
+/// Whether `path` is one of the `selected` paths, beneath one, or a directory leading
+/// to one
+fn is_selected(selected: &[PathBuf], path: &Path) -> bool {
+    selected.is_empty()
+        || selected
+            .iter()
+            .any(|s| path.starts_with(s) || s.starts_with(path))
+}
+
+/// Remove the entries at and beneath `path` that went into a commit
+///
+/// Ignored entries weren't committed, so they're kept, along with the directories
+/// holding them. Those directories are made transparent, since the committed
+/// versions of everything else in them are now in the base.
+fn remove_committed(upperdir: &Path, path: &Path, rules: &IgnoreRules) -> io::Result<()> {
+    let full_path = upperdir.join(path);
+    let metadata = std::fs::symlink_metadata(&full_path)?;
+    if !metadata.is_dir() {
+        if !rules.is_ignored(path, false) {
+            std::fs::remove_file(&full_path)?;
+        }
+        return Ok(());
+    }
+    if rules.is_ignored(path, true) {
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(&full_path)? {
+        remove_committed(upperdir, &path.join(entry?.file_name()), rules)?;
+    }
+    if path.as_os_str().is_empty() {
+        return Ok(());
+    }
+    if std::fs::read_dir(&full_path)?.next().is_none() {
+        std::fs::remove_dir(&full_path)
+    } else if is_opaque_dir(&full_path) {
+        clear_opaque_dir(&full_path)
+    } else {
+        Ok(())
+    }
+}
+
+/// Remove the directories leading to `path` that were left empty
+///
+/// They were committed along with it, so the base has them now.
+fn remove_empty_parents(upperdir: &Path, path: &Path) -> io::Result<()> {
+    for ancestor in path.ancestors().skip(1) {
+        let dir = upperdir.join(ancestor);
+        if ancestor.as_os_str().is_empty() || std::fs::read_dir(&dir)?.next().is_some() {
+            break;
+        }
+        std::fs::remove_dir(&dir)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_commit_selection() {
+        let upper = tempfile::TempDir::new().unwrap();
+        std::fs::create_dir_all(upper.path().join("saves/old")).unwrap();
+        std::fs::create_dir_all(upper.path().join("config")).unwrap();
+        std::fs::write(upper.path().join("saves/slot1.sav"), b"save").unwrap();
+        std::fs::write(upper.path().join("saves/debug.log"), b"log").unwrap();
+        std::fs::write(upper.path().join("config/foo.ini"), b"foo").unwrap();
+        std::fs::write(upper.path().join("config/bar.ini"), b"bar").unwrap();
+
+        let selected = select_paths(
+            upper.path(),
+            &["saves/".to_string(), "/config/foo.ini".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            selected,
+            vec![PathBuf::from("saves"), PathBuf::from("config/foo.ini")]
+        );
+        assert!(is_selected(&selected, Path::new("saves/old")));
+        assert!(is_selected(&selected, Path::new("config")));
+        assert!(!is_selected(&selected, Path::new("config/bar.ini")));
+        assert!(select_paths(upper.path(), &["missing".to_string()]).is_err());
+        assert!(select_paths(upper.path(), &["../saves".to_string()]).is_err());
+
+        let rules = IgnoreRules::parse("*.log");
+        for path in &selected {
+            remove_committed(upper.path(), path, &rules).unwrap();
+            remove_empty_parents(upper.path(), path).unwrap();
+        }
+        assert!(!upper.path().join("saves/slot1.sav").exists());
+        assert!(!upper.path().join("saves/old").exists());
+        assert!(upper.path().join("saves/debug.log").exists());
+        assert!(!upper.path().join("config/foo.ini").exists());
+        assert!(upper.path().join("config/bar.ini").exists());
+    }
+}
@@ -0,0 +1,259 @@
+//! Size limits of worktrees
+//!
+//! A runaway log can fill the disk through an upperdir, so worktrees can be given a
+//! `max_size`. Worktrees mounted with `--tmpfs` get a tmpfs of that size, and
+//! disk-backed upperdirs a project quota where the filesystem supports them (see
+//! [`crate::mount::quota`]). Either way, `stratum watchdog` keeps an eye on mounted
+//! worktrees, warning as an upperdir nears its limit and, if the worktree is set to
+//! `read_only_over_limit`, remounting it read-only once it goes over.
+
+use std::path::{Path, PathBuf};
+
+use super::Store;
+use crate::mount::quota::{allocate_project_id, set_project_quota};
+use crate::state::StratumMountRef;
+use crate::util::calculate_total_size;
+
+/// Where a worktree's usage stands against its limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitStatus {
+    /// The worktree has no limit
+    Unlimited,
+    Within,
+    /// At or past the warning threshold, but not over the limit
+    Near,
+    Over,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorktreeUsage {
+    /// Bytes in the upperdir
+    pub used: u64,
+    pub max_size: Option<u64>,
+    /// Whether the worktree should be made read-only once it's over its limit
+    pub read_only_over_limit: bool,
+}
+
+impl WorktreeUsage {
+    /// Where the usage stands, warning at `warn_percent` percent of the limit
+    pub fn status(&self, warn_percent: u8) -> LimitStatus {
+        let Some(max_size) = self.max_size else {
+            return LimitStatus::Unlimited;
+        };
+        if self.used > max_size {
+            LimitStatus::Over
+        } else if self.used as u128 * 100 >= max_size as u128 * warn_percent as u128 {
+            LimitStatus::Near
+        } else {
+            LimitStatus::Within
+        }
+    }
+}
+
+impl Store {
+    /// Set or lift (with `None`) the size limit of a worktree
+    ///
+    /// This only records the limit, see [`Self::apply_worktree_quota`] for enforcing
+    /// it on disk-backed upperdirs. Worktrees mounted with `--tmpfs` only get the new
+    /// limit when next mounted.
+    pub fn set_worktree_max_size(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        max_size: Option<u64>,
+        read_only_over_limit: bool,
+    ) -> Result<(), String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        worktree.worktree.max_size = max_size;
+        worktree.worktree.read_only_over_limit = read_only_over_limit && max_size.is_some();
+        self.save_worktree_metadata(label, &worktree)?;
+
+        if self
+            .worktree_tmpfs_upperdir(label, worktree_name)?
+            .is_some()
+        {
+            tracing::info!(
+                "Worktree {}:{} is mounted on a tmpfs, the new limit applies once it's mounted again",
+                label,
+                worktree_name
+            );
+        }
+        Ok(())
+    }
+
+    /// Set the project quota of a worktree's upperdir to `max_size`
+    ///
+    /// Fails if the filesystem doesn't support project quotas, in which case the
+    /// limit is only enforced by the watchdog. Failing to lift a quota isn't an
+    /// error, since the worktree no longer has a limit to enforce.
+    pub fn apply_worktree_quota(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        max_size: Option<u64>,
+    ) -> Result<(), String> {
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        match self.set_worktree_quota(label, worktree_name, max_size) {
+            Ok(()) => tracing::debug!("Set project quota of {} to {:?}", upperdir, max_size),
+            Err(e) if max_size.is_some() => {
+                return Err(format!(
+                    "Failed to set a project quota on {}: {}",
+                    upperdir, e
+                ));
+            }
+            Err(e) => tracing::debug!("Failed to lift project quota of {}: {}", upperdir, e),
+        }
+        Ok(())
+    }
+
+    /// Set the project quota of a worktree's upperdir, allocating a project ID for it
+    /// the first time
+    fn set_worktree_quota(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        max_size: Option<u64>,
+    ) -> Result<(), String> {
+        let mut worktree = self.load_worktree(label, worktree_name)?;
+        let id = match (worktree.worktree.project_id, max_size) {
+            (Some(id), _) => id,
+            // Never limited, so there's no quota to lift
+            (None, None) => return Ok(()),
+            (None, Some(_)) => {
+                let in_use = self
+                    .list_all_worktrees()?
+                    .into_iter()
+                    .filter_map(|(_, wt)| wt.worktree.project_id)
+                    .collect();
+                let id = allocate_project_id(&in_use)
+                    .map_err(|e| format!("Failed to allocate a project ID: {}", e))?;
+                worktree.worktree.project_id = Some(id);
+                self.save_worktree_metadata(label, &worktree)?;
+                id
+            }
+        };
+        let upperdir = self.worktree_upperdir(label, worktree_name);
+        set_project_quota(Path::new(&upperdir), id, max_size).map_err(|e| e.to_string())
+    }
+
+    /// Measure a worktree's upperdir against its limit
+    ///
+    /// If the worktree is mounted with `--tmpfs`, that's the upperdir measured.
+    pub fn worktree_usage(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<WorktreeUsage, String> {
+        let worktree = self.load_worktree(label, worktree_name)?;
+        let upperdir = match self.worktree_tmpfs_upperdir(label, worktree_name)? {
+            Some(upperdir) => upperdir,
+            None => PathBuf::from(self.worktree_upperdir(label, worktree_name)),
+        };
+        let used = calculate_total_size(&upperdir.to_string_lossy())
+            .map_err(|e| format!("Failed to measure upperdir {}: {}", upperdir.display(), e))?;
+        Ok(WorktreeUsage {
+            used,
+            max_size: worktree.worktree.max_size,
+            read_only_over_limit: worktree.worktree.read_only_over_limit,
+        })
+    }
+
+    /// Make every writable mount of a worktree read-only in place
+    ///
+    /// Returns the mountpoints that were made read-only. This fails while the
+    /// worktree has files open for writing, since the kernel won't take write
+    /// access away from them.
+    pub fn make_worktree_read_only(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut remounted = Vec::new();
+        for (mount_point, mut mounted) in self.state_manager.get_all_mounts()? {
+            if mounted.read_only || !is_worktree_mount(&mounted.stratum_ref, label, worktree_name) {
+                continue;
+            }
+            crate::mount::remount_read_only(&mount_point).map_err(|e| {
+                format!(
+                    "Failed to remount {} read-only: {}",
+                    mount_point.display(),
+                    e
+                )
+            })?;
+            mounted.read_only = true;
+            mounted.options.read_only = true;
+            self.state_manager.add_mount(mount_point.clone(), mounted)?;
+            tracing::info!(
+                "Remounted worktree {}:{} read-only at {}",
+                label,
+                worktree_name,
+                mount_point.display()
+            );
+            remounted.push(mount_point);
+        }
+        Ok(remounted)
+    }
+
+    /// List the worktrees the watchdog keeps an eye on: those mounted writable,
+    /// including the ones mounted with `--tmpfs`
+    pub fn watched_worktrees(&self) -> Result<Vec<(String, String)>, String> {
+        let mut worktrees = self
+            .state_manager
+            .get_all_mounts()?
+            .into_values()
+            .filter(|m| !m.read_only)
+            .filter_map(|m| match m.stratum_ref {
+                StratumMountRef::Worktree { label, worktree } => Some((label, worktree)),
+                StratumMountRef::Snapshot(_) => None,
+            })
+            .collect::<Vec<_>>();
+        worktrees.sort();
+        worktrees.dedup();
+        Ok(worktrees)
+    }
+
+    /// The upperdir of a worktree mounted with `--tmpfs`, if it is
+    fn worktree_tmpfs_upperdir(
+        &self,
+        label: &str,
+        worktree_name: &str,
+    ) -> Result<Option<PathBuf>, String> {
+        Ok(self
+            .state_manager
+            .get_all_mounts()?
+            .into_values()
+            .filter(|m| is_worktree_mount(&m.stratum_ref, label, worktree_name))
+            .find_map(|m| m.tmpfs_dir)
+            .map(|dir| dir.join("upperdir")))
+    }
+}
+
+fn is_worktree_mount(stratum_ref: &StratumMountRef, label: &str, worktree_name: &str) -> bool {
+    matches!(
+        stratum_ref,
+        StratumMountRef::Worktree { label: l, worktree: w } if l == label && w == worktree_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_status() {
+        let usage = |used, max_size| WorktreeUsage {
+            used,
+            max_size,
+            read_only_over_limit: false,
+        };
+        assert_eq!(usage(100, None).status(90), LimitStatus::Unlimited);
+        assert_eq!(usage(50, Some(100)).status(90), LimitStatus::Within);
+        assert_eq!(usage(90, Some(100)).status(90), LimitStatus::Near);
+        assert_eq!(usage(100, Some(100)).status(90), LimitStatus::Near);
+        assert_eq!(usage(101, Some(100)).status(90), LimitStatus::Over);
+        assert_eq!(
+            usage(u64::MAX, Some(u64::MAX - 1)).status(100),
+            LimitStatus::Over
+        );
+    }
+}
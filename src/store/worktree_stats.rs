@@ -0,0 +1,237 @@
+//! Disk usage and change statistics of worktrees
+//!
+//! Upperdirs grow quietly: an app touching a file copies all of it up, so a worktree
+//! can end up holding gigabytes of textures that are byte for byte what its base
+//! already has. [`Store::worktree_stats`] breaks an upperdir down into what was
+//! added, modified (copied up over a file in the base) and deleted (whiteouts), and
+//! finds the files whose contents are already in `objects/`, which committing would
+//! deduplicate.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use super::Store;
+use crate::composefs::erofs::{ErofsImage, OverlayEntryKind};
+use crate::composefs::fsverity::compute_verity_digest;
+use crate::ignore::IgnoreRules;
+use crate::util::{is_opaque_dir, is_whiteout};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorktreeStats {
+    /// Apparent size of everything in the upperdir
+    pub total_bytes: u64,
+    /// Space the upperdir actually takes up on disk
+    pub disk_bytes: u64,
+    /// Files and symlinks that aren't in the base
+    pub added: u64,
+    /// Files and symlinks copied up over ones in the base
+    pub modified: u64,
+    /// Whiteouts, hiding entries of the base
+    pub deleted: u64,
+    /// Directories hiding the base's contents beneath them
+    pub opaque_dirs: u64,
+    /// Size of the files that the ignore rules leave out of commits
+    pub ignored_bytes: u64,
+    /// Size of the files whose contents are already in the object store
+    pub duplicate_bytes: u64,
+    /// The largest files, largest first
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+impl Store {
+    /// Gather disk usage and change statistics of a worktree's upperdir
+    ///
+    /// `largest` is how many of the largest files to list. Finding duplicates of
+    /// stored objects means hashing every file with the size of some object, so this
+    /// reads much of the upperdir.
+    pub fn worktree_stats(
+        &self,
+        label: &str,
+        worktree_name: &str,
+        largest: usize,
+    ) -> Result<WorktreeStats, String> {
+        let worktree = self.load_worktree(label, worktree_name)?;
+        let image_path = self.commit_image_path(worktree.base_commit());
+        let image = std::fs::read(&image_path)
+            .map_err(|e| format!("Failed to read composefs image {}: {}", image_path, e))?;
+        let base_paths = ErofsImage::from_bytes(&image)
+            .overlay_entries()
+            .into_iter()
+            .filter(|(_, kind)| *kind != OverlayEntryKind::Whiteout)
+            .map(|(path, _)| path)
+            .collect::<HashSet<_>>();
+
+        let objects = Path::new(&self.objects_path()).to_path_buf();
+        let object_sizes = object_sizes(&objects)
+            .map_err(|e| format!("Failed to read object store {}: {}", objects.display(), e))?;
+
+        let mut walker = StatsWalker {
+            upperdir: PathBuf::from(self.worktree_upperdir(label, worktree_name)),
+            base_paths,
+            objects,
+            object_sizes,
+            rules: self.worktree_ignore_rules(label, worktree_name)?,
+            largest_count: largest,
+            largest: BinaryHeap::new(),
+            stats: WorktreeStats::default(),
+        };
+        walker.walk(Path::new("")).map_err(|e| {
+            format!(
+                "Failed to read upperdir {}: {}",
+                walker.upperdir.display(),
+                e
+            )
+        })?;
+
+        let mut stats = walker.stats;
+        stats.largest = walker
+            .largest
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((size, path))| (path, size))
+            .collect();
+        Ok(stats)
+    }
+}
+
+struct StatsWalker {
+    upperdir: PathBuf,
+    /// Paths in the base, relative to its root
+    base_paths: HashSet<String>,
+    objects: PathBuf,
+    object_sizes: HashSet<u64>,
+    rules: IgnoreRules,
+    largest_count: usize,
+    /// Min-heap of the largest files seen so far
+    largest: BinaryHeap<Reverse<(u64, PathBuf)>>,
+    stats: WorktreeStats,
+}
+
+impl StatsWalker {
+    fn walk(&mut self, relative: &Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(self.upperdir.join(relative))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            let full_path = entry.path();
+            let metadata = std::fs::symlink_metadata(&full_path)?;
+            self.stats.total_bytes += metadata.len();
+            self.stats.disk_bytes += metadata.blocks() * 512;
+
+            if metadata.is_dir() {
+                if is_opaque_dir(&full_path) {
+                    self.stats.opaque_dirs += 1;
+                }
+                self.walk(&path)?;
+                continue;
+            }
+            if is_whiteout(&metadata) {
+                self.stats.deleted += 1;
+                continue;
+            }
+            if self.base_paths.contains(path.to_string_lossy().as_ref()) {
+                self.stats.modified += 1;
+            } else {
+                self.stats.added += 1;
+            }
+            if !metadata.is_file() {
+                continue;
+            }
+
+            if self.rules.is_ignored(&path, false) {
+                self.stats.ignored_bytes += metadata.len();
+            }
+            if self.object_sizes.contains(&metadata.len()) && self.is_stored(&full_path)? {
+                self.stats.duplicate_bytes += metadata.len();
+            }
+            if self.largest_count > 0 {
+                self.largest.push(Reverse((metadata.len(), path)));
+                if self.largest.len() > self.largest_count {
+                    self.largest.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the contents of a file are already in the object store
+    fn is_stored(&self, path: &Path) -> std::io::Result<bool> {
+        let digest = hex::encode(compute_verity_digest(std::io::BufReader::new(File::open(
+            path,
+        )?))?);
+        Ok(self.objects.join(&digest[..2]).join(&digest[2..]).exists())
+    }
+}
+
+/// Collect the sizes of the objects in the store, to only hash files that could be
+/// one of them
+fn object_sizes(objects: &Path) -> std::io::Result<HashSet<u64>> {
+    let mut sizes = HashSet::new();
+    if !objects.exists() {
+        return Ok(sizes);
+    }
+    for dir in std::fs::read_dir(objects)? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for object in std::fs::read_dir(dir.path())? {
+            sizes.insert(object?.metadata()?.len());
+        }
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worktree_stats_walk() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let upperdir = dir.path().join("upper");
+        let objects = dir.path().join("objects");
+        std::fs::create_dir_all(upperdir.join("textures")).unwrap();
+        std::fs::write(upperdir.join("textures/sky.dds"), [7u8; 10000]).unwrap();
+        std::fs::write(upperdir.join("save.dat"), b"save").unwrap();
+        std::fs::write(upperdir.join("game.log"), b"log line\n").unwrap();
+        crate::util::create_whiteout(&upperdir.join("intro.bik")).unwrap();
+
+        // The texture is already in the object store
+        let digest = hex::encode(compute_verity_digest(&[7u8; 10000][..]).unwrap());
+        std::fs::create_dir_all(objects.join(&digest[..2])).unwrap();
+        std::fs::write(objects.join(&digest[..2]).join(&digest[2..]), [7u8; 10000]).unwrap();
+
+        let mut walker = StatsWalker {
+            upperdir,
+            base_paths: ["textures", "textures/sky.dds", "intro.bik"]
+                .map(String::from)
+                .into(),
+            object_sizes: object_sizes(&objects).unwrap(),
+            objects,
+            rules: IgnoreRules::parse("*.log"),
+            largest_count: 2,
+            largest: BinaryHeap::new(),
+            stats: WorktreeStats::default(),
+        };
+        walker.walk(Path::new("")).unwrap();
+        let stats = walker.stats;
+        assert_eq!((stats.added, stats.modified, stats.deleted), (2, 1, 1));
+        assert_eq!(stats.ignored_bytes, 9);
+        assert_eq!(stats.duplicate_bytes, 10000);
+        assert_eq!(
+            walker
+                .largest
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Reverse(entry)| entry)
+                .collect::<Vec<_>>(),
+            vec![
+                (10000, PathBuf::from("textures/sky.dds")),
+                (9, PathBuf::from("game.log"))
+            ]
+        );
+    }
+}
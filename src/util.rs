@@ -3,7 +3,7 @@ use rs_merkle::{Hasher, MerkleTree};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 // Parses and OCI-style tag into a tuple of name and optional tag.
 pub fn parse_label(label: &str) -> Result<(String, Option<String>), String> {
@@ -446,10 +446,113 @@ pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<
     Ok(())
 }
 
+/// Copy a directory recursively like [`copy_dir_all`], but only the entries for which
+/// `filter` returns true
+///
+/// `filter` is given each entry's path relative to `src`. Directories are always
+/// descended into; they're only kept if anything beneath them was copied, or if
+/// `filter` accepts the directory itself.
+pub fn copy_dir_filtered(
+    src: impl AsRef<Path>,
+    dst: impl AsRef<Path>,
+    filter: &dyn Fn(&Path) -> bool,
+) -> io::Result<()> {
+    fn copy_filtered(
+        root: &Path,
+        src: &Path,
+        dst: &Path,
+        filter: &dyn Fn(&Path) -> bool,
+    ) -> io::Result<bool> {
+        let existed = dst.exists();
+        fs::create_dir_all(dst)?;
+        copy_metadata(src, dst)?;
+
+        let mut copied = false;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let ty = entry.file_type()?;
+            let path = entry.path();
+            let target = dst.join(entry.file_name());
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+
+            if ty.is_dir() {
+                copied |= copy_filtered(root, &path, &target, filter)?;
+            } else if !filter(relative) {
+                tracing::trace!("Filtered out {}", relative.display());
+            } else if ty.is_file() {
+                copy_file_with_metadata(&path, &target)?;
+                copied = true;
+            } else if ty.is_symlink() {
+                copy_symlink(&path, &target)?;
+                copied = true;
+            } else {
+                tracing::warn!("Skipping special file: {}", path.display());
+            }
+        }
+
+        let relative = src.strip_prefix(root).unwrap_or(src);
+        if !copied && !existed && relative != Path::new("") && !filter(relative) {
+            fs::remove_dir(dst)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    let src_path = src.as_ref();
+    copy_filtered(src_path, src_path, dst.as_ref(), filter)?;
+    Ok(())
+}
+
+/// Match a path against a glob pattern
+///
+/// `*` and `?` match within a single path component, and `**` matches any number of
+/// components. Leading and trailing slashes in the pattern are ignored.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => {
+                match_component(&pattern[1..], name)
+                    || (!name.is_empty() && match_component(pattern, &name[1..]))
+            }
+            (Some(b'?'), Some(_)) => match_component(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => match_component(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+
+    fn match_components(pattern: &[&str], path: &[&str]) -> bool {
+        match pattern.first() {
+            None => path.is_empty(),
+            Some(&"**") => (0..=path.len()).any(|i| match_components(&pattern[1..], &path[i..])),
+            Some(component) => {
+                !path.is_empty()
+                    && match_component(component.as_bytes(), path[0].as_bytes())
+                    && match_components(&pattern[1..], &path[1..])
+            }
+        }
+    }
+
+    let pattern = pattern
+        .trim_matches('/')
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    let path = path
+        .trim_matches('/')
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .collect::<Vec<_>>();
+    match_components(&pattern, &path)
+}
+
 /// Copy a file and preserve all its metadata
 fn copy_file_with_metadata(src: &Path, dst: &Path) -> io::Result<()> {
-    // Copy file content
-    fs::copy(src, dst)?;
+    // Copy file content, sharing extents where the filesystem supports it
+    if let Err(e) = reflink_file(src, dst) {
+        tracing::trace!("Can't reflink {}, copying it: {}", src.display(), e);
+        fs::copy(src, dst)?;
+    }
 
     // Copy all metadata
     copy_metadata(src, dst)?;
@@ -457,6 +560,25 @@ fn copy_file_with_metadata(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+/// Clone a file's contents into a new file with `FICLONE`, so both share the same
+/// extents on disk
+///
+/// Only works within a filesystem that supports it, like Btrfs or XFS. Nothing is
+/// left at `dst` if it fails.
+pub fn reflink_file(src: &Path, dst: &Path) -> io::Result<()> {
+    let src_file = fs::File::open(src)?;
+    let dst_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    if let Err(e) = rustix::fs::ioctl_ficlone(&dst_file, &src_file) {
+        drop(dst_file);
+        let _ = fs::remove_file(dst);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
 /// Copy a symlink and preserve its metadata
 fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
     let link_target = fs::read_link(src)?;
@@ -688,6 +810,455 @@ pub fn calculate_size_recursive(dir: &Path, total_size: &mut u64) -> Result<(),
 
     Ok(())
 }
+/// Check whether the direct entries of two directories differ
+///
+/// Entries are compared by name, file type, mode, size and modification time,
+/// without following symlinks. A directory that is missing on only one side
+/// counts as different.
+pub fn dir_entries_differ(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    fn entries(dir: &Path) -> Option<Vec<(std::ffi::OsString, u32, u64, i64)>> {
+        let mut entries = fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = fs::symlink_metadata(entry.path()).ok()?;
+                Some((
+                    entry.file_name(),
+                    metadata.mode(),
+                    metadata.len(),
+                    metadata.mtime(),
+                ))
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        Some(entries)
+    }
+
+    entries(a) != entries(b)
+}
+
+/// Create an overlayfs whiteout (a 0/0 character device) at `path`
+pub fn create_whiteout(path: &Path) -> io::Result<()> {
+    let path_cstr = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+    if unsafe { libc::mknod(path_cstr.as_ptr(), libc::S_IFCHR, libc::makedev(0, 0)) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Write the changes from `old` to `new` into `out`, as an overlayfs upperdir would
+/// hold them
+///
+/// Added and changed entries are copied, and removed entries become whiteouts.
+/// Files are considered unchanged if their type, mode, ownership, size and
+/// modification time (down to the nanosecond) all match. If only the sub-second part
+/// of the modification time differs, which happens when one side went through a
+/// format that drops it, their contents are compared instead. Returns whether
+/// anything changed.
+pub fn write_tree_delta(old: &Path, new: &Path, out: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    fn same(a: &fs::Metadata, b: &fs::Metadata, a_path: &Path, b_path: &Path) -> io::Result<bool> {
+        if a.file_type() != b.file_type()
+            || a.mode() != b.mode()
+            || a.uid() != b.uid()
+            || a.gid() != b.gid()
+        {
+            return Ok(false);
+        }
+        if a.is_dir() {
+            return Ok(true);
+        }
+        if a.size() != b.size() || a.mtime() != b.mtime() {
+            return Ok(false);
+        }
+        if a.mtime_nsec() == b.mtime_nsec() {
+            return Ok(true);
+        }
+        if a.is_symlink() {
+            Ok(fs::read_link(a_path)? == fs::read_link(b_path)?)
+        } else if a.is_file() {
+            same_contents(a_path, b_path)
+        } else {
+            Ok(false)
+        }
+    }
+
+    let existed = out.exists();
+    fs::create_dir_all(out)?;
+    copy_metadata(new, out)?;
+    let mut changed = !same(
+        &fs::symlink_metadata(old)?,
+        &fs::symlink_metadata(new)?,
+        old,
+        new,
+    )?;
+
+    for entry in fs::read_dir(new)? {
+        let entry = entry?;
+        let new_path = entry.path();
+        let old_path = old.join(entry.file_name());
+        let out_path = out.join(entry.file_name());
+        let new_meta = fs::symlink_metadata(&new_path)?;
+        let old_meta = fs::symlink_metadata(&old_path).ok();
+
+        if new_meta.is_dir() {
+            if old_meta.as_ref().is_some_and(|m| m.is_dir()) {
+                changed |= write_tree_delta(&old_path, &new_path, &out_path)?;
+            } else {
+                // A new directory, or one replacing a file
+                copy_dir_all(&new_path, &out_path)?;
+                changed = true;
+            }
+            continue;
+        }
+
+        if let Some(old_meta) = &old_meta
+            && same(old_meta, &new_meta, &old_path, &new_path)?
+        {
+            continue;
+        }
+        if new_meta.is_file() {
+            copy_file_with_metadata(&new_path, &out_path)?;
+        } else if new_meta.is_symlink() {
+            copy_symlink(&new_path, &out_path)?;
+        } else {
+            tracing::warn!("Skipping special file: {}", new_path.display());
+            continue;
+        }
+        changed = true;
+    }
+
+    for entry in fs::read_dir(old)? {
+        let entry = entry?;
+        if fs::symlink_metadata(new.join(entry.file_name())).is_err() {
+            tracing::trace!("Whiting out {}", entry.path().display());
+            create_whiteout(&out.join(entry.file_name()))?;
+            changed = true;
+        }
+    }
+
+    if !changed && !existed {
+        fs::remove_dir(out)?;
+    }
+    Ok(changed)
+}
+
+/// Whether two files of the same size have the same contents
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    use std::io::Read;
+
+    let mut a = io::BufReader::new(fs::File::open(a)?);
+    let mut b = io::BufReader::new(fs::File::open(b)?);
+    let mut a_buf = [0u8; 8192];
+    let mut b_buf = [0u8; 8192];
+    loop {
+        let n = a.read(&mut a_buf)?;
+        if n == 0 {
+            // Sizes match, so `b` is at its end too
+            return Ok(true);
+        }
+        b.read_exact(&mut b_buf[..n])?;
+        if a_buf[..n] != b_buf[..n] {
+            return Ok(false);
+        }
+    }
+}
+
+/// Whether `metadata` belongs to an overlayfs whiteout (a 0/0 character device)
+pub fn is_whiteout(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+/// The xattr marking an overlayfs directory as opaque
+const OVERLAY_OPAQUE_XATTR: &std::ffi::CStr = c"trusted.overlay.opaque";
+
+/// Whether the directory at `path` is an opaque overlayfs directory
+pub fn is_opaque_dir(path: &Path) -> bool {
+    let Ok(path_cstr) = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()) else {
+        return false;
+    };
+    let mut value = [0u8; 1];
+    let len = unsafe {
+        libc::lgetxattr(
+            path_cstr.as_ptr(),
+            OVERLAY_OPAQUE_XATTR.as_ptr(),
+            value.as_mut_ptr() as *mut libc::c_void,
+            value.len(),
+        )
+    };
+    len == 1 && value[0] == b'y'
+}
+
+/// Make an opaque overlayfs directory transparent again, so lower layers show through
+pub fn clear_opaque_dir(path: &Path) -> io::Result<()> {
+    let path_cstr = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+    if unsafe { libc::lremovexattr(path_cstr.as_ptr(), OVERLAY_OPAQUE_XATTR.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Mark a directory as opaque, hiding whatever lower layers have at its path
+pub fn set_opaque_dir(path: &Path) -> io::Result<()> {
+    let path_cstr = std::ffi::CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"))?;
+    let value = b"y";
+    if unsafe {
+        libc::lsetxattr(
+            path_cstr.as_ptr(),
+            OVERLAY_OPAQUE_XATTR.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Prefix of whiteout files in the portable upper layer format
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// File marking its directory as opaque in the portable upper layer format
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Convert an overlayfs upper layer in place to the portable format used by
+/// archives, which can't hold whiteout devices or trusted xattrs
+///
+/// Whiteouts become empty `.wh.<name>` files, and opaque directories get an empty
+/// `.wh..wh..opq` file instead of their xattr.
+pub fn whiteouts_to_portable(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()? {
+        let path = entry.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.is_dir() {
+            if is_opaque_dir(&path) {
+                clear_opaque_dir(&path)?;
+                fs::File::create(path.join(OPAQUE_MARKER))?;
+            }
+            whiteouts_to_portable(&path)?;
+        } else if is_whiteout(&metadata) {
+            fs::remove_file(&path)?;
+            let mut name = std::ffi::OsString::from(WHITEOUT_PREFIX);
+            name.push(entry.file_name());
+            fs::File::create(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Convert an upper layer in the portable format back in place to the one overlayfs
+/// uses, undoing [`whiteouts_to_portable`]
+pub fn whiteouts_from_portable(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()? {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name_str = name.to_string_lossy();
+        if name_str == OPAQUE_MARKER {
+            fs::remove_file(&path)?;
+            set_opaque_dir(dir)?;
+        } else if let Some(target) = name_str.strip_prefix(WHITEOUT_PREFIX) {
+            fs::remove_file(&path)?;
+            let target = dir.join(target);
+            match fs::symlink_metadata(&target) {
+                Ok(m) if m.is_dir() => fs::remove_dir_all(&target)?,
+                Ok(_) => fs::remove_file(&target)?,
+                Err(_) => {}
+            }
+            create_whiteout(&target)?;
+        } else if fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir()) {
+            // Checked again, since a whiteout may have replaced it by now
+            whiteouts_from_portable(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Find the entries of the upper layer `src` that would replace something in the
+/// upper layer `dst` if applied on top of it
+///
+/// Directories on both sides are merged, so only their contents are compared, unless
+/// the one in `src` is opaque. The returned paths are relative to `src`.
+pub fn upper_layer_conflicts(src: &Path, dst: &Path) -> io::Result<Vec<PathBuf>> {
+    fn walk(root: &Path, src: &Path, dst: &Path, conflicts: &mut Vec<PathBuf>) -> io::Result<()> {
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            let Ok(existing) = fs::symlink_metadata(dst.join(entry.file_name())) else {
+                continue;
+            };
+            let path = entry.path();
+            if entry.file_type()?.is_dir() && existing.is_dir() && !is_opaque_dir(&path) {
+                walk(root, &path, &dst.join(entry.file_name()), conflicts)?;
+            } else {
+                conflicts.push(path.strip_prefix(root).unwrap_or(&path).to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    let mut conflicts = Vec::new();
+    if dst.exists() {
+        walk(src, src, dst, &mut conflicts)?;
+    }
+    conflicts.sort();
+    Ok(conflicts)
+}
+
+/// Apply the upper layer `src` on top of the upper layer `dst`
+///
+/// Unlike [`copy_dir_all`], whiteouts are kept, along with the xattrs marking opaque
+/// directories. Entries of `src` replace those in `dst`, and directories on both
+/// sides are merged unless the one in `src` is opaque.
+pub fn apply_upper_layer(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::create_dir_all(dst)?;
+    copy_metadata(src, dst)?;
+
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&src_path)?;
+
+        if metadata.is_dir() && !is_opaque_dir(&src_path) {
+            if fs::symlink_metadata(&dst_path).is_ok_and(|m| !m.is_dir()) {
+                fs::remove_file(&dst_path)?;
+            }
+            apply_upper_layer(&src_path, &dst_path)?;
+        } else {
+            copy_upper_entry(&src_path, &dst_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy an upper layer like [`apply_upper_layer`], skipping the entries `filter`
+/// rejects
+///
+/// `filter` is given each entry's path relative to `src`, and whether it's a
+/// directory. Rejected directories are skipped along with everything beneath them.
+/// Other directories are only kept if anything beneath them was copied, or if
+/// they're empty or opaque, since those are changes on their own.
+pub fn copy_upper_layer_filtered(
+    src: &Path,
+    dst: &Path,
+    filter: &dyn Fn(&Path, bool) -> bool,
+) -> io::Result<()> {
+    fn copy_filtered(
+        root: &Path,
+        src: &Path,
+        dst: &Path,
+        filter: &dyn Fn(&Path, bool) -> bool,
+    ) -> io::Result<bool> {
+        fs::create_dir_all(dst)?;
+        copy_metadata(src, dst)?;
+
+        let mut empty = true;
+        let mut copied = false;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            empty = false;
+            let ty = entry.file_type()?;
+            let path = entry.path();
+            let target = dst.join(entry.file_name());
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+
+            if !filter(relative, ty.is_dir()) {
+                tracing::trace!("Filtered out {}", relative.display());
+            } else if ty.is_dir() {
+                if copy_filtered(root, &path, &target, filter)? || is_opaque_dir(&path) {
+                    copied = true;
+                } else {
+                    fs::remove_dir(&target)?;
+                }
+            } else {
+                copy_upper_entry(&path, &target)?;
+                copied = true;
+            }
+        }
+        Ok(copied || empty)
+    }
+
+    copy_filtered(src, src, dst, filter)?;
+    Ok(())
+}
+
+/// Copy a single entry of an upper layer, replacing whatever is at `dst`
+///
+/// Directories are copied recursively, keeping whiteouts and opaque directories.
+pub fn copy_upper_entry(src: &Path, dst: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(dst) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(dst)?,
+        Ok(_) => fs::remove_file(dst)?,
+        Err(_) => {}
+    }
+
+    let metadata = fs::symlink_metadata(src)?;
+    if metadata.is_dir() {
+        apply_upper_layer(src, dst)?;
+    } else if metadata.is_file() {
+        copy_file_with_metadata(src, dst)?;
+    } else if metadata.is_symlink() {
+        copy_symlink(src, dst)?;
+    } else if is_whiteout(&metadata) {
+        create_whiteout(dst)?;
+    } else {
+        tracing::warn!("Skipping special file: {}", src.display());
+    }
+    Ok(())
+}
+
+/// Format a size in bytes for humans, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Parse a size like `512`, `64K`, `1.5G` or `10GiB`, in binary units
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    let trimmed = size.trim();
+    let number_end = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(number_end);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| format!("Invalid size {}", size))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit
+        .strip_suffix("IB")
+        .or_else(|| unit.strip_suffix('B'))
+        .unwrap_or(&unit);
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        let exponent = UNITS
+            .iter()
+            .position(|u| *u == unit)
+            .ok_or_else(|| format!("Invalid size unit in {}", size))?;
+        1u64 << (10 * (exponent + 1))
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
 pub fn fsync_all_walk(dir: &Path) -> io::Result<()> {
     tracing::trace!("Running fsync() on {}", dir.display());
 
@@ -784,6 +1355,157 @@ mod tests {
         assert_ne!(leaf_hash, internal_hash);
     }
 
+    #[test]
+    fn test_dir_entries_differ() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let a = temp_dir.path().join("a");
+        let b = temp_dir.path().join("b");
+        fs::create_dir_all(&a).unwrap();
+        fs::create_dir_all(&b).unwrap();
+        assert!(!dir_entries_differ(&a, &b));
+
+        fs::write(a.join("file.txt"), "content").unwrap();
+        assert!(dir_entries_differ(&a, &b));
+
+        // Missing directories only compare equal to each other
+        assert!(dir_entries_differ(&a, &temp_dir.path().join("missing")));
+        assert!(!dir_entries_differ(
+            &temp_dir.path().join("missing"),
+            &temp_dir.path().join("missing2")
+        ));
+    }
+
+    #[test]
+    fn test_write_tree_delta() {
+        let old = tempfile::TempDir::new().unwrap();
+        let new = tempfile::TempDir::new().unwrap();
+        let out = tempfile::TempDir::new().unwrap();
+
+        for dir in [old.path(), new.path()] {
+            fs::create_dir_all(dir.join("same")).unwrap();
+            fs::write(dir.join("same/file"), b"unchanged").unwrap();
+        }
+        // Match the mtimes so the unchanged file really is identical
+        let mtime = fs::metadata(old.path().join("same/file"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(new.path().join("same/file"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        fs::write(old.path().join("changed"), b"old").unwrap();
+        fs::write(new.path().join("changed"), b"new contents").unwrap();
+        fs::create_dir(new.path().join("added")).unwrap();
+        fs::write(new.path().join("added/file"), b"added").unwrap();
+
+        assert!(write_tree_delta(old.path(), new.path(), out.path()).unwrap());
+        assert_eq!(
+            fs::read(out.path().join("changed")).unwrap(),
+            b"new contents"
+        );
+        assert!(out.path().join("added/file").exists());
+        assert!(!out.path().join("same").exists());
+    }
+
+    #[test]
+    fn test_write_tree_delta_same_second() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let old = tempfile::TempDir::new().unwrap();
+        let new = tempfile::TempDir::new().unwrap();
+        let out = tempfile::TempDir::new().unwrap();
+
+        // Same size and second, only the nanoseconds tell them apart
+        let set = |path: &Path, contents: &[u8], nanos: u32| {
+            fs::write(path, contents).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(UNIX_EPOCH + Duration::new(1_700_000_000, nanos))
+                .unwrap();
+        };
+        set(&old.path().join("edited"), b"aaaa", 100);
+        set(&new.path().join("edited"), b"bbbb", 200);
+        set(&old.path().join("touched"), b"same", 0);
+        set(&new.path().join("touched"), b"same", 300);
+
+        assert!(write_tree_delta(old.path(), new.path(), out.path()).unwrap());
+        assert_eq!(fs::read(out.path().join("edited")).unwrap(), b"bbbb");
+        assert!(!out.path().join("touched").exists());
+    }
+
+    #[test]
+    fn test_apply_upper_layer() {
+        let stash = tempfile::TempDir::new().unwrap();
+        let upper = tempfile::TempDir::new().unwrap();
+
+        fs::create_dir_all(stash.path().join("saves")).unwrap();
+        fs::write(stash.path().join("saves/slot1"), b"stashed").unwrap();
+        fs::write(stash.path().join("config.ini"), b"stashed").unwrap();
+        fs::create_dir_all(upper.path().join("saves")).unwrap();
+        fs::write(upper.path().join("saves/slot2"), b"current").unwrap();
+
+        assert!(
+            upper_layer_conflicts(stash.path(), upper.path())
+                .unwrap()
+                .is_empty()
+        );
+        fs::write(upper.path().join("config.ini"), b"current").unwrap();
+        assert_eq!(
+            upper_layer_conflicts(stash.path(), upper.path()).unwrap(),
+            vec![PathBuf::from("config.ini")]
+        );
+
+        apply_upper_layer(stash.path(), upper.path()).unwrap();
+        assert_eq!(
+            fs::read(upper.path().join("config.ini")).unwrap(),
+            b"stashed"
+        );
+        assert_eq!(
+            fs::read(upper.path().join("saves/slot1")).unwrap(),
+            b"stashed"
+        );
+        assert_eq!(
+            fs::read(upper.path().join("saves/slot2")).unwrap(),
+            b"current"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("1.5G"), Ok(1536 * 1024 * 1024));
+        assert_eq!(parse_size("10 GiB"), Ok(10 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("2mb"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("lots").is_err());
+        assert!(parse_size("10X").is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.esp", "mod.esp"));
+        assert!(!glob_match("*.esp", "Data/mod.esp"));
+        assert!(glob_match("Data/*.esp", "Data/mod.esp"));
+        assert!(glob_match("**/*.esp", "Data/plugins/mod.esp"));
+        assert!(glob_match("**/*.esp", "mod.esp"));
+        assert!(glob_match("Data/**", "Data/textures/a.dds"));
+        assert!(glob_match("/Docs/", "Docs"));
+        assert!(glob_match("te?t", "test"));
+        assert!(!glob_match("te?t", "tet"));
+    }
+
     #[test]
     fn test_empty_merkle_tree() {
         let empty_data: Vec<&[u8]> = vec![];